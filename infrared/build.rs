@@ -29,6 +29,36 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
+    // The USB descriptors, see `.cargo/config.toml`. The defaults are what
+    // the host tools look for.
+    let var = |name: &str, default: &str| {
+        println!("cargo:rerun-if-env-changed={name}");
+        env::var(name).unwrap_or_else(|_| default.to_owned())
+    };
+    let id = |name: &str, default: &str| {
+        let value = var(name, default);
+        u16::from_str_radix(value.strip_prefix("0x").unwrap_or(&value), 16)
+            .unwrap_or_else(|_| panic!("{name} must be a 16-bit hex number, got {value:?}"))
+    };
+    let vid = id("INFRARED_USB_VID", "0xc0de");
+    let pid = id("INFRARED_USB_PID", "0xcafe");
+    let manufacturer = var("INFRARED_USB_MANUFACTURER", "Jabu");
+    let product = var("INFRARED_USB_PRODUCT", "Infrared");
+    let serial_number = var("INFRARED_USB_SERIAL_NUMBER", "1");
+    File::create(out.join("usb_ids.rs"))
+        .unwrap()
+        .write_all(
+            format!(
+                "pub const VID: u16 = {vid:#06x};\n\
+                 pub const PID: u16 = {pid:#06x};\n\
+                 pub const MANUFACTURER: &str = {manufacturer:?};\n\
+                 pub const PRODUCT: &str = {product:?};\n\
+                 pub const SERIAL_NUMBER: &str = {serial_number:?};\n"
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
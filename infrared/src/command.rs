@@ -0,0 +1,360 @@
+//! Commands received from the host over USB.
+//!
+//! Two framings are accepted on the same stream:
+//!  - text: `[<emitter>/][r|r6.|s<bits>.]<hexword>[:<repeats>]\n`, where the
+//!    hex word is prefixed with `r` for RC5, with `r6.` for RC6 mode 0, with
+//!    `s` and the frame length (12, 15 or 20) for SIRC, and is NEC otherwise.
+//!    An RC6 word is the toggle bit followed by the control and information
+//!    bytes. Lines may end with `\r\n`, and
+//!    empty lines are ignored. `[<emitter>/]carrier=<hz>` instead changes the
+//!    NEC carrier frequency of the emitter, `info` asks for a description
+//!    of the firmware, and `play <slot>` transmits a stored pulse sequence on
+//!    emitter 0.
+//!  - binary: the [`OP_NEC`] byte followed by an NEC frame as a little-endian
+//!    u32, sent on emitter 0 without repeats, the lone [`OP_PING`] byte,
+//!    which the host uses to check the link is alive, the [`OP_PULSES`]
+//!    byte followed by a count and that many little-endian u16 durations in
+//!    microseconds, alternating marks and spaces starting with a mark, sent
+//!    on emitter 0 at its NEC carrier, or the lone [`OP_NEC_REPEAT`] byte,
+//!    which sends a single NEC repeat code on emitter 0, so that the host can
+//!    hold a button down for as long as it likes, or the [`OP_STORE_PULSES`]
+//!    byte followed by a slot and a pulse sequence like the one of
+//!    [`OP_PULSES`], which is kept in that slot of the flash rather than
+//!    transmitted.
+//!  - framed: the [`OP_FRAMED`] byte followed by a length and that many bytes
+//!    of a single command of either framing above, where the newline of a
+//!    text command may be left out. A malformed command is rejected without
+//!    losing track of where the next one starts.
+//!
+//! Text commands are all printable ASCII, so the opcodes can't be mistaken for
+//! the start of one. The host may split commands across USB packets or put
+//! several in one, so they are reassembled from the byte stream.
+
+use core::str;
+
+use defmt::error;
+
+use crate::{Protocol, emitter::NEC_CARRIER_RANGE};
+
+/// Opcode of a binary NEC command.
+pub const OP_NEC: u8 = 0x01;
+const BINARY_NEC_LEN: usize = 5;
+/// Opcode of a ping, acknowledged without doing anything.
+pub const OP_PING: u8 = 0x02;
+/// Opcode of a pulse sequence.
+pub const OP_PULSES: u8 = 0x03;
+/// Opcode of an NEC repeat code.
+pub const OP_NEC_REPEAT: u8 = 0x04;
+/// Opcode of a pulse sequence to store.
+pub const OP_STORE_PULSES: u8 = 0x05;
+/// Opcode of a length-prefixed command.
+pub const OP_FRAMED: u8 = 0x06;
+
+/// Longest text command accepted, longer lines are rejected as a whole.
+const MAX_LINE: usize = 64;
+/// Most pulses in a sequence, bounded by what the receive buffer holds.
+pub const MAX_PULSES: usize = 128;
+const MAX_PULSES_LEN: usize = 3 + 2 * MAX_PULSES;
+const MAX_FRAMED_LEN: usize = 2 + u8::MAX as usize;
+/// Longest command of any framing.
+const MAX_COMMAND: usize = max(max(MAX_PULSES_LEN, MAX_LINE), MAX_FRAMED_LEN);
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
+
+type Parsed = Result<Command, &'static [u8]>;
+
+pub enum Command {
+    Transmit(Request),
+    SetCarrier {
+        emitter: usize,
+        hz: u32,
+    },
+    Ping,
+    Info,
+    /// A pulse sequence of this many pulses, see [`Reassembler::pulses`].
+    Pulses(usize),
+    NecRepeat,
+    /// Keep a pulse sequence in a slot, see [`Reassembler::pulses`].
+    StorePulses {
+        slot: usize,
+        count: usize,
+    },
+    /// Transmit the pulse sequence kept in a slot.
+    Play(usize),
+}
+
+/// A parsed frame, ready to be transmitted.
+pub struct Request {
+    pub emitter: usize,
+    pub protocol: Protocol,
+    pub value: u32,
+    pub repeats: u8,
+}
+
+/// Collects received bytes until they make up whole commands.
+pub struct Reassembler {
+    /// Once all complete commands are taken, at most an incomplete command
+    /// of `MAX_COMMAND` bytes is left, so a whole USB packet always fits
+    /// after it.
+    buf: [u8; MAX_COMMAND + 64],
+    len: usize,
+    /// Set while skipping the rest of an overlong line, until its newline or
+    /// the start of a binary or framed command.
+    overlong: bool,
+    /// Durations of the last pulse sequence taken.
+    pulses: [u16; MAX_PULSES],
+}
+
+impl Reassembler {
+    pub const fn new() -> Self {
+        Reassembler {
+            buf: [0; MAX_COMMAND + 64],
+            len: 0,
+            overlong: false,
+            pulses: [0; MAX_PULSES],
+        }
+    }
+
+    /// Appends received data. When it doesn't fit, everything buffered is
+    /// dropped and `false` returned.
+    pub fn push(&mut self, mut data: &[u8]) -> bool {
+        if self.overlong {
+            let Some(end) = data.iter().position(|&b| b == b'\n' || is_opcode(b)) else {
+                return true;
+            };
+            // The opcode is kept, it starts the next command
+            let skip = if data[end] == b'\n' { end + 1 } else { end };
+            data = &data[skip..];
+            self.overlong = false;
+        }
+        let Some(free) = self.buf.get_mut(self.len..self.len + data.len()) else {
+            self.len = 0;
+            return false;
+        };
+        free.copy_from_slice(data);
+        self.len += data.len();
+        true
+    }
+
+    /// Takes the next complete command off the buffer and parses it. Errors
+    /// are the response to send back.
+    pub fn next(&mut self, emitters: usize) -> Option<Parsed> {
+        let data = &self.buf[..self.len];
+        let (result, consumed) = match *data {
+            [] => return None,
+            [OP_FRAMED, ref rest @ ..] => {
+                let &[len, ref rest @ ..] = rest else {
+                    return None;
+                };
+                let command = rest.get(..len as usize)?;
+                (
+                    parse_framed(command, emitters, &mut self.pulses),
+                    2 + len as usize,
+                )
+            }
+            [op, ..] if is_binary(op) => parse_binary(data, &mut self.pulses)?,
+            // Binary and framed commands can't be part of a line, so one
+            // starting before its newline ends it, and the newline search
+            // stops there
+            _ => match data.iter().position(|&b| b == b'\n' || is_opcode(b)) {
+                Some(end) if data[end] == b'\n' => {
+                    let line = &data[..end];
+                    let line = line.strip_suffix(b"\r").unwrap_or(line);
+                    if line.is_empty() {
+                        self.consume(end + 1);
+                        return self.next(emitters);
+                    }
+                    (parse_text(line, emitters), end + 1)
+                }
+                Some(end) => {
+                    error!("Dropping unterminated line");
+                    (Err(&b"ERR unterminated\n"[..]), end)
+                }
+                None if data.len() > MAX_LINE => {
+                    error!("Dropping overlong line");
+                    self.len = 0;
+                    self.overlong = true;
+                    return Some(Err(b"ERR toolong\n"));
+                }
+                None => return None,
+            },
+        };
+        self.consume(consumed);
+        Some(result)
+    }
+
+    /// The durations of a [`Command::Pulses`] or [`Command::StorePulses`] of
+    /// `count` pulses, until the next command is taken.
+    pub fn pulses(&self, count: usize) -> &[u16] {
+        &self.pulses[..count]
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.buf.copy_within(n..self.len, 0);
+        self.len -= n;
+    }
+}
+
+fn is_binary(op: u8) -> bool {
+    matches!(
+        op,
+        OP_NEC | OP_PING | OP_PULSES | OP_NEC_REPEAT | OP_STORE_PULSES
+    )
+}
+
+/// Whether `b` starts a command other than a text one.
+fn is_opcode(b: u8) -> bool {
+    b == OP_FRAMED || is_binary(b)
+}
+
+/// Parses the binary command at the start of `data`, keeping the durations
+/// of a pulse sequence in `pulses`, and returns it along with how many bytes
+/// it took, `None` while it's incomplete.
+fn parse_binary(data: &[u8], pulses: &mut [u16; MAX_PULSES]) -> Option<(Parsed, usize)> {
+    Some(match *data {
+        [OP_NEC, ref rest @ ..] => {
+            let &[b0, b1, b2, b3, ..] = rest else {
+                return None;
+            };
+            let value = u32::from_le_bytes([b0, b1, b2, b3]);
+            let request = Request {
+                emitter: 0,
+                protocol: Protocol::Nec,
+                value,
+                repeats: 0,
+            };
+            (Ok(Command::Transmit(request)), BINARY_NEC_LEN)
+        }
+        [OP_PING, ..] => (Ok(Command::Ping), 1),
+        [OP_NEC_REPEAT, ..] => (Ok(Command::NecRepeat), 1),
+        [op @ (OP_PULSES | OP_STORE_PULSES), ref rest @ ..] => {
+            let (slot, rest) = match *rest {
+                [slot, ref rest @ ..] if op == OP_STORE_PULSES => (Some(slot as usize), rest),
+                [] if op == OP_STORE_PULSES => return None,
+                _ => (None, rest),
+            };
+            let &[count, ref rest @ ..] = rest else {
+                return None;
+            };
+            let count = count as usize;
+            if !(1..=MAX_PULSES).contains(&count) {
+                error!("Invalid pulse count: {}", count);
+                // Where the command ends is anyone's guess, so everything
+                // buffered goes
+                return Some((Err(b"ERR badpulses\n"), data.len()));
+            }
+            let (durations, _) = rest.get(..2 * count)?.as_chunks::<2>();
+            for (pulse, &duration) in pulses.iter_mut().zip(durations) {
+                *pulse = u16::from_le_bytes(duration);
+            }
+            let result = if pulses[..count].contains(&0) {
+                error!("Zero length pulse");
+                Err(&b"ERR badpulses\n"[..])
+            } else {
+                Ok(match slot {
+                    Some(slot) => Command::StorePulses { slot, count },
+                    None => Command::Pulses(count),
+                })
+            };
+            let header = if slot.is_some() { 3 } else { 2 };
+            (result, header + 2 * count)
+        }
+        _ => return None,
+    })
+}
+
+/// Parses the whole `command` of a framed command, which must be a single
+/// command of either of the other framings.
+fn parse_framed(command: &[u8], emitters: usize, pulses: &mut [u16; MAX_PULSES]) -> Parsed {
+    match *command {
+        [op, ..] if is_binary(op) => match parse_binary(command, pulses) {
+            Some((result, len)) if len == command.len() => result,
+            Some((Err(e), _)) => Err(e),
+            _ => {
+                error!("Framed command of the wrong length");
+                Err(b"ERR badframe\n")
+            }
+        },
+        [] | [OP_FRAMED, ..] => {
+            error!("Invalid framed command");
+            Err(b"ERR badframe\n")
+        }
+        _ => {
+            let line = command.strip_suffix(b"\n").unwrap_or(command);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            parse_text(line, emitters)
+        }
+    }
+}
+
+fn parse_text(data: &[u8], emitters: usize) -> Parsed {
+    let Ok(data) = str::from_utf8(data) else {
+        error!("Received invalid UTF-8: {:?}", data);
+        return Err(b"ERR badutf8\n");
+    };
+    if data == "info" {
+        return Ok(Command::Info);
+    }
+    if let Some(slot) = data.strip_prefix("play ") {
+        // Whether the slot exists is up to the storage
+        let Ok(slot) = slot.parse() else {
+            error!("Invalid slot: {:?}", slot);
+            return Err(b"ERR badslot\n");
+        };
+        return Ok(Command::Play(slot));
+    }
+    let (emitter, data) = data.split_once('/').unwrap_or(("0", data));
+    let Some(emitter) = emitter.parse::<usize>().ok().filter(|&i| i < emitters) else {
+        error!("Invalid emitter: {:?}", data);
+        return Err(b"ERR bademitter\n");
+    };
+    if let Some(hz) = data.strip_prefix("carrier=") {
+        let Some(hz) = hz.parse().ok().filter(|hz| NEC_CARRIER_RANGE.contains(hz)) else {
+            error!("Invalid carrier frequency: {:?}", hz);
+            return Err(b"ERR badcarrier\n");
+        };
+        return Ok(Command::SetCarrier { emitter, hz });
+    }
+    let (protocol, data) = if let Some(data) = data.strip_prefix("r6.") {
+        (Protocol::Rc6, data)
+    } else if let Some(data) = data.strip_prefix('r') {
+        (Protocol::Rc5, data)
+    } else if let Some(data) = data.strip_prefix('s') {
+        match data
+            .split_once('.')
+            .map(|(bits, data)| (bits.parse(), data))
+        {
+            Some((Ok(bits @ (12 | 15 | 20)), data)) => (Protocol::Sirc(bits), data),
+            _ => {
+                error!("Invalid SIRC frame length: {:?}", data);
+                return Err(b"ERR badbits\n");
+            }
+        }
+    } else {
+        (Protocol::Nec, data)
+    };
+    let (word, repeats) = data.split_once(':').unwrap_or((data, "0"));
+    let Ok(value) = u32::from_str_radix(word, 16) else {
+        error!("Can't parse hex u32: {:?}", data);
+        return Err(b"ERR badhex\n");
+    };
+    if value
+        .checked_shr(protocol.frame_bits())
+        .is_some_and(|v| v != 0)
+    {
+        error!("Frame too long for {}: {:x}", protocol, value);
+        return Err(b"ERR badhex\n");
+    }
+    let Ok(repeats) = repeats.parse::<u8>() else {
+        error!("Can't parse repeat count: {:?}", data);
+        return Err(b"ERR badrepeat\n");
+    };
+    Ok(Command::Transmit(Request {
+        emitter,
+        protocol,
+        value,
+        repeats,
+    }))
+}
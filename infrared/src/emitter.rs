@@ -0,0 +1,611 @@
+//! Transmitting side: the PIO programs generating NEC, RC5, RC6 and SIRC
+//! frames, and arbitrary pulse sequences, on a single output pin. Each
+//! emitter occupies a whole PIO block.
+
+use embassy_rp::{
+    Peripheral,
+    clocks::clk_sys_freq,
+    pio::{
+        self, Common, FifoJoin, Instance, InstanceMemory, Pio, PioPin, StateMachine,
+        program::{self, InstructionOperands, JmpCondition, SetDestination, pio_asm},
+    },
+};
+use fixed::traits::ToFixed as _;
+
+use crate::Protocol;
+
+/// Bits in an RC5 frame, and the half-bit symbols they are encoded as.
+pub const RC5_BITS: u32 = 14;
+const RC5_SYMBOLS: u32 = 2 * RC5_BITS;
+
+/// Bits of an RC6 mode 0 frame the host provides: the toggle (trailer) bit on
+/// top of the 8-bit control (address) and 8-bit information (command) fields.
+pub const RC6_BITS: u32 = 17;
+/// Symbols of an RC6 frame: the header, the double length trailer bit and the
+/// other 16 bits.
+const RC6_SYMBOLS: u32 = 16 + 4 + 2 * (RC6_BITS - 1);
+
+/// Instructions the burst program spends on each carrier cycle.
+const BURST_TICKS_PER_CYCLE: f64 = 4.;
+/// Instructions the symbol program spends on each carrier cycle.
+const SYMBOL_TICKS_PER_CYCLE: f64 = 4.;
+/// Instructions the pulse program spends on each carrier cycle.
+const PULSE_TICKS_PER_CYCLE: f64 = 4.;
+
+/// NEC carrier used until the host picks another one, 38222 Hz unless
+/// overridden with one of the `carrier-*` features.
+#[cfg(not(any(
+    feature = "carrier-36k",
+    feature = "carrier-40k",
+    feature = "carrier-56k"
+)))]
+pub const DEFAULT_NEC_CARRIER_HZ: u32 = 38222;
+#[cfg(feature = "carrier-36k")]
+pub const DEFAULT_NEC_CARRIER_HZ: u32 = 36000;
+#[cfg(feature = "carrier-40k")]
+pub const DEFAULT_NEC_CARRIER_HZ: u32 = 40000;
+#[cfg(feature = "carrier-56k")]
+pub const DEFAULT_NEC_CARRIER_HZ: u32 = 56000;
+
+#[cfg(any(
+    all(feature = "carrier-36k", feature = "carrier-40k"),
+    all(feature = "carrier-36k", feature = "carrier-56k"),
+    all(feature = "carrier-40k", feature = "carrier-56k"),
+))]
+compile_error!("Only one of the carrier-* features may be enabled");
+
+/// Length of an instruction of the NEC control and repeat programs, half of a
+/// 562.5us burst. Every NEC timing is a multiple of it, so nudging it
+/// stretches or shrinks whole frames, bursts included, for devices that are
+/// picky about the timing. The spaces can be adjusted on their own with the
+/// defines of the programs.
+const NEC_TICK_S: f64 = 562.5e-6 / 2.;
+
+/// Range of NEC carriers accepted from the host. Above it, a 562.5us burst
+/// takes more carrier cycles than the burst program can count, so a longer
+/// [`NEC_TICK_S`] needs a lower limit.
+pub const NEC_CARRIER_RANGE: core::ops::RangeInclusive<u32> = 30_000..=57_000;
+
+/// Carrier and symbol length the symbol program is configured with.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct SymbolTiming {
+    carrier_hz: u32,
+    /// Carrier cycles per symbol, at most 32.
+    cycles: u8,
+}
+
+/// A symbol is half of an 889us RC5 bit.
+const RC5_TIMING: SymbolTiming = SymbolTiming {
+    carrier_hz: 36000,
+    cycles: 32,
+};
+
+/// A symbol is the 444us RC6 time unit, half of a normal bit.
+const RC6_TIMING: SymbolTiming = SymbolTiming {
+    carrier_hz: 36000,
+    cycles: 16,
+};
+
+/// A symbol is the 600us SIRC time unit.
+const SIRC_TIMING: SymbolTiming = SymbolTiming {
+    carrier_hz: 40000,
+    cycles: 24,
+};
+
+/// The programs taking turns on sm2, as there's no room for both at once.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Sm2Program {
+    NecRepeat,
+    Pulses,
+}
+
+type Program = program::Program<{ program::RP2040_MAX_PROGRAM_SIZE }>;
+
+pub struct Emitter<'d, PIO: Instance> {
+    common: Common<'d, PIO>,
+    burst: StateMachine<'d, PIO, 0>,
+    nec: StateMachine<'d, PIO, 1>,
+    /// Runs the NEC repeat program, or the pulse program while a pulse
+    /// sequence is sent.
+    sm2: StateMachine<'d, PIO, 2>,
+    symbols: StateMachine<'d, PIO, 3>,
+    /// What `symbols` is currently configured for, `None` before the first use.
+    symbol_timing: Option<SymbolTiming>,
+    /// Carrier of the NEC bursts, also used for pulse sequences.
+    nec_carrier_hz: u32,
+    /// The programs sm2 switches between.
+    prg_repeat: Program,
+    prg_pulses: Program,
+    /// Which one sm2 runs, `None` before the first use, and the instruction
+    /// memory it takes up.
+    sm2_program: Option<Sm2Program>,
+    sm2_memory: Option<InstanceMemory<'d, PIO>>,
+    /// Where the programs of sm0, sm1 and sm3 start, to restart them.
+    origins: [u8; 3],
+    out_pin: pio::Pin<'d, PIO>,
+}
+
+impl<'d, PIO: Instance> Emitter<'d, PIO> {
+    pub fn new(pio: Pio<'d, PIO>, pin: impl Peripheral<P = impl PioPin + 'd> + 'd) -> Self {
+        let Pio {
+            mut common,
+            mut sm0,
+            mut sm1,
+            sm2,
+            mut sm3,
+            ..
+        } = pio;
+
+        // The PIO programs come from here https://github.com/raspberrypi/pico-examples/tree/master/pio/ir_nec/nec_transmit_library
+
+        let prg_burst = pio_asm!(
+            r#"
+    .define BURST_IRQ 7                 ; which IRQ should trigger a carrier burst
+
+    .wrap_target
+        mov X, Y                        ; Y holds the carrier cycles per burst, minus one
+        wait 1 irq BURST_IRQ            ; wait for the IRQ then clear it
+    cycle_loop:
+        set pins, 1                     ; set the pin high (1 cycle)
+        set pins, 0 [1]                 ; set the pin low (2 cycles)
+        jmp X--, cycle_loop             ; (1 more cycle)
+    .wrap
+        "#
+        );
+
+        // The NEC timing constants, in ticks of NEC_TICK_S on top of the
+        // instruction itself. Tweak these to calibrate for finicky devices,
+        // NUM_INITIAL_BURSTS is shared with the repeat program and has to be
+        // changed in both.
+        let prg_control = pio_asm!(
+            r#"
+    .define BURST_IRQ 7                     ; the IRQ used to trigger a carrier burst
+    .define public NUM_INITIAL_BURSTS 16    ; how many bursts to transmit for a 'sync burst'
+    .define public SYNC_SPACE_DELAY 15      ; delay of the space after the sync burst (4.5ms)
+    .define public ONE_BIT_DELAY 3          ; extra delay of a '1' bit's space (1.125ms)
+
+    .wrap_target
+        pull                                ; fetch a data word from the transmit FIFO into the
+                                            ; output shift register, blocking if the FIFO is empty
+
+        set X, (NUM_INITIAL_BURSTS - 1)     ; send a sync burst (9ms)
+    long_burst:
+        irq BURST_IRQ
+        jmp X-- long_burst
+
+        nop [SYNC_SPACE_DELAY]              ; send a 4.5ms space
+        irq BURST_IRQ [1]                   ; send a 562.5us burst to begin the first data bit
+
+    data_bit:
+        out X, 1                            ; shift the least-significant bit from the OSR
+        jmp !X burst                        ; send a short delay for a '0' bit
+        nop [ONE_BIT_DELAY]                 ; send an additional delay for a '1' bit
+    burst:
+        irq BURST_IRQ                       ; send a 562.5us burst to end the data bit
+
+    jmp !OSRE data_bit                      ; continue sending bits until the OSR is empty
+
+    .wrap                                   ; fetch another data word from the FIFO
+        "#
+        );
+
+        // State machine usage of the PIO block:
+        //  - sm0: NEC carrier bursts, triggered by BURST_IRQ
+        //  - sm1: NEC data frames
+        //  - sm2: NEC repeat frames, or pulse sequences
+        //  - sm3: RC5, RC6 and SIRC frames, generates its own 36 or 40 kHz carrier
+        // All of them drive the same output pin, which is only ever driven by
+        // one of them at a time since the main loop transmits one frame at a time.
+        // Together the programs take up 31 of the 32 instruction slots, the
+        // pulse program swaps in for the NEC repeat one.
+        let out_pin = common.make_pio_pin(pin);
+        let mut origins = [0; 3];
+
+        {
+            let mut cfg = pio::Config::default();
+            let loaded = common.load_program(&prg_burst.program);
+            origins[0] = loaded.origin;
+            cfg.use_program(&loaded, &[]);
+            cfg.set_set_pins(&[&out_pin]);
+            sm0.set_pin_dirs(pio::Direction::Out, &[&out_pin]);
+            sm0.set_config(&cfg);
+            // Enabled by `set_nec_carrier`
+        }
+
+        let tick_rate = 1. / NEC_TICK_S;
+
+        {
+            let mut cfg = pio::Config::default();
+            let loaded = common.load_program(&prg_control.program);
+            origins[1] = loaded.origin;
+            cfg.use_program(&loaded, &[]);
+            cfg.fifo_join = FifoJoin::TxOnly;
+            cfg.clock_divider = ((clk_sys_freq() as f64) / tick_rate).to_fixed();
+            sm1.set_config(&cfg);
+            sm1.set_enable(true);
+        }
+
+        // Same as the control program, but transmits the NEC repeat frame instead
+        // of a data word. It drives the same burst IRQ, so the carrier is shared.
+        let prg_repeat = pio_asm!(
+            r#"
+    .define BURST_IRQ 7                     ; the IRQ used to trigger a carrier burst
+    .define public NUM_INITIAL_BURSTS 16    ; how many bursts to transmit for a 'sync burst'
+    .define public REPEAT_SPACE_DELAY 7     ; delay of the space after the sync burst (2.25ms)
+
+    .wrap_target
+        pull                                ; wait for a (dummy) word in the transmit FIFO
+
+        set X, (NUM_INITIAL_BURSTS - 1)     ; send a sync burst (9ms)
+    long_burst:
+        irq BURST_IRQ
+        jmp X-- long_burst
+
+        nop [REPEAT_SPACE_DELAY]            ; send a 2.25ms space
+        irq BURST_IRQ [1]                   ; send a 562.5us burst to end the frame
+
+    .wrap                                   ; wait for the next repeat request
+        "#
+        );
+        defmt::assert_eq!(
+            prg_repeat.public_defines.NUM_INITIAL_BURSTS,
+            prg_control.public_defines.NUM_INITIAL_BURSTS,
+            "repeat frames must start with the same sync burst as data frames"
+        );
+
+        // RC5, RC6 and SIRC all have symbols (half-bits for the Manchester
+        // encoded RC5 and RC6, the time unit for SIRC) far longer than the
+        // NEC bursts, and other carriers, so this program handles the carrier
+        // too. Each bit shifted out is one symbol, either a mark or a space,
+        // whose length in carrier cycles is kept in Y. The carrier and Y are
+        // set up for the protocol by `use_symbol_timing`, and the encoding to
+        // symbols is done by `rc5_symbols`, `rc6_symbols` and `sirc_symbols`.
+        // There's no room left for a program of its own per protocol, which
+        // is also why the RC6 leader and trailer bit are made of symbols.
+        let prg_symbols = pio_asm!(
+            r#"
+    .wrap_target
+    symbol:
+        out X, 1                            ; next symbol, autopull stalls here when idle
+        jmp !X space
+        mov X, Y                            ; Y holds the carrier cycles per symbol, minus one
+    mark:
+        set pins, 1                         ; set the pin high (1 cycle)
+        set pins, 0 [1]                     ; set the pin low (2 cycles)
+        jmp X-- mark                        ; (1 more cycle)
+        jmp symbol
+    space:
+        mov X, Y
+    space_loop:
+        jmp X-- space_loop [3]              ; stay low for as long as a carrier cycle takes
+    .wrap
+        "#
+        );
+
+        {
+            let mut cfg = pio::Config::default();
+            let loaded = common.load_program(&prg_symbols.program);
+            origins[2] = loaded.origin;
+            cfg.use_program(&loaded, &[]);
+            cfg.set_set_pins(&[&out_pin]);
+            sm3.set_pin_dirs(pio::Direction::Out, &[&out_pin]);
+            cfg.fifo_join = FifoJoin::TxOnly;
+            cfg.shift_out = pio::ShiftConfig {
+                threshold: 32,
+                direction: pio::ShiftDirection::Left,
+                auto_fill: true,
+            };
+            sm3.set_config(&cfg);
+        }
+
+        // Sends whatever the host asks for, for protocols none of the above
+        // cover. Each word is a mark and the space after it, in carrier cycles
+        // minus one, in its upper and lower half. The carrier is the NEC one,
+        // set by `start_pulses`. Loaded in place of the repeat program by
+        // `use_sm2_program`, as well as that one.
+        let prg_pulses = pio_asm!(
+            r#"
+    .wrap_target
+        out X, 16                           ; mark length, autopull stalls here when idle
+    mark:
+        set pins, 1                         ; set the pin high (1 cycle)
+        set pins, 0 [1]                     ; set the pin low (2 cycles)
+        jmp X-- mark                        ; (1 more cycle)
+        out X, 16                           ; space length
+    space:
+        jmp X-- space [3]                   ; stay low for as long as a carrier cycle takes
+    .wrap
+        "#
+        );
+
+        let mut emitter = Emitter {
+            common,
+            burst: sm0,
+            nec: sm1,
+            sm2,
+            symbols: sm3,
+            symbol_timing: None,
+            nec_carrier_hz: DEFAULT_NEC_CARRIER_HZ,
+            prg_repeat: prg_repeat.program,
+            prg_pulses: prg_pulses.program,
+            sm2_program: None,
+            sm2_memory: None,
+            origins,
+            out_pin,
+        };
+        emitter.use_sm2_program(Sm2Program::NecRepeat);
+        emitter.use_symbol_timing(RC5_TIMING);
+        emitter.set_nec_carrier(DEFAULT_NEC_CARRIER_HZ);
+        emitter
+    }
+
+    /// Retunes the NEC carrier, keeping the bursts two [`NEC_TICK_S`] long by
+    /// adjusting how many carrier cycles they take. The burst length is off by
+    /// at most half a carrier cycle, and the carrier by well under 1 Hz, as the
+    /// clock divider has 8 fractional bits and is in the hundreds at 150 MHz.
+    /// Must not be called while a frame is being transmitted.
+    fn set_nec_carrier(&mut self, hz: u32) {
+        let cycles = (hz as f64 * 2. * NEC_TICK_S + 0.5) as u64;
+        self.nec_carrier_hz = hz;
+        let sm = &mut self.burst;
+        sm.set_enable(false);
+        sm.set_clock_divider(
+            ((clk_sys_freq() as f64) / (hz as f64 * BURST_TICKS_PER_CYCLE)).to_fixed(),
+        );
+        // X is loaded from Y before waiting for the IRQ, so set both for the
+        // next burst to have the new length
+        for destination in [SetDestination::X, SetDestination::Y] {
+            let set = InstructionOperands::SET {
+                destination,
+                data: cycles as u8 - 1,
+            };
+            // SAFETY: Only sets the counters, the program is stalled waiting
+            // for the IRQ when idle
+            unsafe { sm.exec_instr(set.encode()) };
+        }
+        sm.set_enable(true);
+    }
+
+    /// Reconfigures the symbol program if it's set up for another protocol.
+    /// Must not be called while a frame is being transmitted.
+    fn use_symbol_timing(&mut self, timing: SymbolTiming) {
+        if self.symbol_timing == Some(timing) {
+            return;
+        }
+        let sm = &mut self.symbols;
+        sm.set_enable(false);
+        sm.set_clock_divider(
+            ((clk_sys_freq() as f64) / (timing.carrier_hz as f64 * SYMBOL_TICKS_PER_CYCLE))
+                .to_fixed(),
+        );
+        let set_y = InstructionOperands::SET {
+            destination: SetDestination::Y,
+            data: timing.cycles - 1,
+        };
+        // SAFETY: Only sets Y, the program is stalled waiting for data when idle
+        unsafe { sm.exec_instr(set_y.encode()) };
+        sm.set_enable(true);
+        self.symbol_timing = Some(timing);
+    }
+
+    /// Swaps the program sm2 runs if it isn't `program` already. Must not be
+    /// called while sm2 is transmitting.
+    fn use_sm2_program(&mut self, program: Sm2Program) {
+        if self.sm2_program == Some(program) {
+            return;
+        }
+        let sm = &mut self.sm2;
+        sm.set_enable(false);
+        if let Some(memory) = self.sm2_memory.take() {
+            // SAFETY: Only sm2 runs the program, and it's disabled
+            unsafe { self.common.free_instr(memory) };
+        }
+        let mut cfg = pio::Config::default();
+        cfg.fifo_join = FifoJoin::TxOnly;
+        let loaded = match program {
+            Sm2Program::NecRepeat => {
+                cfg.clock_divider = (clk_sys_freq() as f64 * NEC_TICK_S).to_fixed();
+                self.common.load_program(&self.prg_repeat)
+            }
+            Sm2Program::Pulses => {
+                cfg.set_set_pins(&[&self.out_pin]);
+                cfg.shift_out = pio::ShiftConfig {
+                    threshold: 32,
+                    direction: pio::ShiftDirection::Left,
+                    auto_fill: true,
+                };
+                // The clock divider follows the NEC carrier, see `start_pulses`
+                self.common.load_program(&self.prg_pulses)
+            }
+        };
+        cfg.use_program(&loaded, &[]);
+        sm.set_config(&cfg);
+        sm.set_enable(true);
+        self.sm2_memory = Some(loaded.used_memory);
+        self.sm2_program = Some(program);
+    }
+
+    /// Brings every state machine back to the start of its program with empty
+    /// FIFOs and the output low, and sets them up again as they were.
+    fn restart(&mut self) {
+        fn reset<PIO: Instance, const SM: usize>(sm: &mut StateMachine<'_, PIO, SM>, origin: u8) {
+            sm.set_enable(false);
+            sm.clear_fifos();
+            sm.restart();
+            let jmp = InstructionOperands::JMP {
+                condition: JmpCondition::Always,
+                address: origin,
+            };
+            // SAFETY: The state machine is disabled, and jumps to the start
+            // of its own program
+            unsafe { sm.exec_instr(jmp.encode()) };
+        }
+
+        reset(&mut self.burst, self.origins[0]);
+        reset(&mut self.nec, self.origins[1]);
+        reset(&mut self.symbols, self.origins[2]);
+        self.sm2.set_enable(false);
+        self.sm2.clear_fifos();
+        self.sm2.restart();
+        // A stall in the middle of a mark leaves the pin high
+        let low = InstructionOperands::SET {
+            destination: SetDestination::PINS,
+            data: 0,
+        };
+        // SAFETY: Only drives the pin, the state machine is disabled
+        unsafe { self.burst.exec_instr(low.encode()) };
+
+        self.nec.set_enable(true);
+        // Reload the sm2 program, and redo what the others were set up with
+        self.sm2_program = None;
+        self.use_sm2_program(Sm2Program::NecRepeat);
+        let timing = self.symbol_timing.take().unwrap_or(RC5_TIMING);
+        self.use_symbol_timing(timing);
+        self.set_nec_carrier(self.nec_carrier_hz);
+    }
+
+    /// Queues the symbols for transmission, skipping a trailing all-space word.
+    fn send_symbols(&mut self, timing: SymbolTiming, symbols: u64) {
+        self.use_symbol_timing(timing);
+        self.symbols.tx().push((symbols >> 32) as u32);
+        if symbols as u32 != 0 {
+            self.symbols.tx().push(symbols as u32);
+        }
+    }
+}
+
+/// Protocol-agnostic interface to an [`Emitter`], so that the main loop can
+/// pick one at runtime regardless of which PIO block it lives on.
+pub trait Transmit {
+    /// Starts transmitting a frame. The value must be valid for the protocol.
+    fn send(&mut self, protocol: Protocol, value: u32);
+
+    /// Transmits whatever the protocol sends while a button is held after
+    /// the frame started by `send`.
+    fn repeat(&mut self, protocol: Protocol, value: u32);
+
+    /// Sets up for a pulse sequence, returning the carrier it's sent at, the
+    /// NEC one. The pulses are then queued with `try_push_pulse` as the FIFO
+    /// makes room, and transmitted as soon as they are.
+    fn start_pulses(&mut self) -> u32;
+
+    /// Queues a mark and the space following it, both in carrier cycles from
+    /// 1 to 65536. Returns `false` without queueing them when the FIFO is
+    /// full.
+    fn try_push_pulse(&mut self, mark: u32, space: u32) -> bool;
+
+    /// Changes the carrier of NEC frames, which must be in
+    /// [`NEC_CARRIER_RANGE`]. RC5, RC6 and SIRC always use their standard
+    /// carriers.
+    fn set_carrier(&mut self, hz: u32);
+
+    /// Restarts the state machines if one of them left a word in its FIFO.
+    /// Only meaningful once everything queued should have been transmitted,
+    /// when all of them are idle. Returns whether they were restarted.
+    fn recover_stall(&mut self) -> bool;
+}
+
+impl<PIO: Instance> Transmit for Emitter<'_, PIO> {
+    fn send(&mut self, protocol: Protocol, value: u32) {
+        match protocol {
+            Protocol::Nec => {
+                self.use_sm2_program(Sm2Program::NecRepeat);
+                self.nec.tx().push(value);
+            }
+            Protocol::Rc5 => self.send_symbols(RC5_TIMING, rc5_symbols(value)),
+            Protocol::Rc6 => self.send_symbols(RC6_TIMING, rc6_symbols(value)),
+            Protocol::Sirc(bits) => self.send_symbols(SIRC_TIMING, sirc_symbols(value, bits)),
+        }
+    }
+
+    fn repeat(&mut self, protocol: Protocol, value: u32) {
+        match protocol {
+            Protocol::Nec => {
+                // A pulse sequence may have taken the state machine since
+                self.use_sm2_program(Sm2Program::NecRepeat);
+                self.sm2.tx().push(0);
+            }
+            // RC5, RC6 and SIRC have no dedicated repeat frame, the whole
+            // frame is resent
+            Protocol::Rc5 | Protocol::Rc6 | Protocol::Sirc(_) => self.send(protocol, value),
+        }
+    }
+
+    fn start_pulses(&mut self) -> u32 {
+        self.use_sm2_program(Sm2Program::Pulses);
+        self.sm2.set_clock_divider(
+            ((clk_sys_freq() as f64) / (self.nec_carrier_hz as f64 * PULSE_TICKS_PER_CYCLE))
+                .to_fixed(),
+        );
+        self.nec_carrier_hz
+    }
+
+    fn try_push_pulse(&mut self, mark: u32, space: u32) -> bool {
+        self.sm2.tx().try_push((mark - 1) << 16 | (space - 1))
+    }
+
+    fn set_carrier(&mut self, hz: u32) {
+        self.set_nec_carrier(hz);
+    }
+
+    fn recover_stall(&mut self) -> bool {
+        if self.nec.tx().empty() && self.sm2.tx().empty() && self.symbols.tx().empty() {
+            return false;
+        }
+        self.restart();
+        true
+    }
+}
+
+/// Manchester encodes an RC5 frame (start bits, toggle bit, address and
+/// command, MSB first) into half-bit symbols for the symbol PIO program,
+/// aligned to the top of the word. A 1 bit is a space followed by a mark.
+fn rc5_symbols(frame: u32) -> u64 {
+    let mut symbols = 0;
+    for i in (0..RC5_BITS).rev() {
+        symbols <<= 2;
+        symbols |= if frame & (1 << i) != 0 { 0b01 } else { 0b10 };
+    }
+    symbols << (64 - RC5_SYMBOLS)
+}
+
+/// Encodes an RC6 mode 0 frame (toggle bit, control and information fields,
+/// MSB first) into 444us symbols for the symbol PIO program, aligned to the
+/// top of the word. After a 6 symbol leader mark and a 2 symbol space come the
+/// start bit, a 1, and the mode bits, all 0. Unlike RC5, a 1 bit is a mark
+/// followed by a space. The toggle bit that follows is the trailer bit, twice
+/// as long as the others. That's 52 symbols in total.
+fn rc6_symbols(frame: u32) -> u64 {
+    // The leader, the start bit and the mode bits
+    const HEADER: u64 = 0b1111_1100_1001_0101;
+    let toggle = if frame & (1 << (RC6_BITS - 1)) != 0 {
+        0b1100
+    } else {
+        0b0011
+    };
+    let mut symbols = HEADER << 4 | toggle;
+    for i in (0..RC6_BITS - 1).rev() {
+        symbols <<= 2;
+        symbols |= if frame & (1 << i) != 0 { 0b10 } else { 0b01 };
+    }
+    symbols << (64 - RC6_SYMBOLS)
+}
+
+/// Encodes a SIRC frame of `bits` bits (command and address, LSB first) into
+/// 600us symbols for the symbol PIO program, aligned to the top of the word.
+/// After a 4 symbol header mark, each bit is a space followed by a mark of one
+/// symbol for a 0 and two for a 1, which is at most 64 symbols for 20 bits.
+fn sirc_symbols(frame: u32, bits: u32) -> u64 {
+    let mut symbols: u64 = 0b1111;
+    let mut len = 4;
+    for i in 0..bits {
+        if frame & (1 << i) != 0 {
+            symbols = symbols << 3 | 0b011;
+            len += 3;
+        } else {
+            symbols = symbols << 2 | 0b01;
+            len += 2;
+        }
+    }
+    symbols << (64 - len)
+}
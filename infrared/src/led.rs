@@ -0,0 +1,85 @@
+//! Status indication on the onboard LED.
+//!
+//! The LED is lit solid while the device is powered but not configured by a
+//! host, gives a short pulse every two seconds while it is ready for commands,
+//! blinks once for every transmitted command, and flashes a few times when
+//! the host closes the port.
+
+use embassy_futures::select::{Either4, select4};
+use embassy_rp::gpio::Output;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+use embassy_usb::Handler;
+
+const PULSE_ON: Duration = Duration::from_millis(100);
+const PULSE_PERIOD: Duration = Duration::from_secs(2);
+const BLINK: Duration = Duration::from_millis(50);
+const CLOSED_FLASHES: usize = 3;
+
+static CONFIGURED: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+static TRANSMITTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static HOST_CLOSED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Reports the USB configuration state to the LED task.
+pub struct UsbStateHandler;
+
+impl Handler for UsbStateHandler {
+    fn configured(&mut self, configured: bool) {
+        CONFIGURED.signal(configured);
+    }
+}
+
+/// Blinks the LED to confirm a command was transmitted.
+pub fn transmitted() {
+    TRANSMITTED.signal(());
+}
+
+/// Flashes the LED to show the host dropped DTR.
+pub fn host_closed() {
+    HOST_CLOSED.signal(());
+}
+
+#[embassy_executor::task]
+pub async fn led_task(mut led: Output<'static>) -> ! {
+    let mut configured = false;
+    loop {
+        if !configured {
+            led.set_high();
+            configured = CONFIGURED.wait().await;
+            continue;
+        }
+        match select4(
+            CONFIGURED.wait(),
+            TRANSMITTED.wait(),
+            HOST_CLOSED.wait(),
+            pulse(&mut led),
+        )
+        .await
+        {
+            Either4::First(c) => configured = c,
+            Either4::Second(()) => blink(&mut led).await,
+            Either4::Third(()) => {
+                for _ in 0..CLOSED_FLASHES {
+                    blink(&mut led).await;
+                }
+            }
+        }
+    }
+}
+
+async fn blink(led: &mut Output<'static>) {
+    led.set_high();
+    Timer::after(BLINK).await;
+    // Keeps back-to-back blinks apart
+    led.set_low();
+    Timer::after(BLINK).await;
+}
+
+async fn pulse(led: &mut Output<'static>) -> ! {
+    loop {
+        led.set_low();
+        Timer::after(PULSE_PERIOD - PULSE_ON).await;
+        led.set_high();
+        Timer::after(PULSE_ON).await;
+    }
+}
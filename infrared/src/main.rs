@@ -1,20 +1,35 @@
 #![no_std]
 #![no_main]
 
-use core::str;
+mod command;
+mod emitter;
+mod led;
+mod receive;
+mod storage;
+/// USB descriptors, set at build time, see `build.rs`.
+mod usb_ids {
+    include!(concat!(env!("OUT_DIR"), "/usb_ids.rs"));
+}
 
+use command::{Command, MAX_PULSES, Reassembler, Request};
 use defmt::{error, info, unwrap};
 use embassy_executor::Spawner;
+use embassy_futures::select::{Either, select};
 use embassy_rp::{
     bind_interrupts,
     clocks::clk_sys_freq,
-    peripherals::{PIO0, USB},
+    gpio::{Level, Output, Pull},
+    peripherals::{PIO0, PIO1, PIO2, USB},
     pio::{self, FifoJoin, Pio, program::pio_asm},
     usb,
 };
-use embassy_usb::{UsbDevice, class::cdc_acm};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use embassy_usb::{UsbDevice, class::cdc_acm, driver::EndpointError};
+use emitter::{Emitter, RC5_BITS, RC6_BITS, Transmit};
 use fixed::traits::ToFixed as _;
 use static_cell::StaticCell;
+use storage::Storage;
 use {defmt_rtt as _, panic_probe as _};
 
 // Program metadata for `picotool info`.
@@ -23,28 +38,70 @@ use {defmt_rtt as _, panic_probe as _};
 #[used]
 pub static PICOTOOL_ENTRIES: [embassy_rp::binary_info::EntryAddr; 4] = [
     embassy_rp::binary_info::rp_program_name!(c"Pico IR"),
-    embassy_rp::binary_info::rp_program_description!(c"Transmits NEC IR protocol commands"),
+    embassy_rp::binary_info::rp_program_description!(
+        c"Transmits NEC, RC5, RC6 and SIRC IR protocol commands"
+    ),
     embassy_rp::binary_info::rp_cargo_version!(),
     embassy_rp::binary_info::rp_program_build_attribute!(),
 ];
 
+/// Time between the starts of consecutive frames while a button is held.
+const NEC_REPEAT_PERIOD: Duration = Duration::from_millis(108);
+const RC5_REPEAT_PERIOD: Duration = Duration::from_millis(114);
+const RC6_REPEAT_PERIOD: Duration = Duration::from_millis(107);
+const SIRC_REPEAT_PERIOD: Duration = Duration::from_millis(45);
+
+#[derive(Clone, Copy, defmt::Format)]
+enum Protocol {
+    Nec,
+    Rc5,
+    /// Mode 0, the only one supported.
+    Rc6,
+    /// Carries the frame length, which is 12, 15 or 20 bits.
+    Sirc(u32),
+}
+
+impl Protocol {
+    fn frame_bits(self) -> u32 {
+        match self {
+            Protocol::Nec => 32,
+            Protocol::Rc5 => RC5_BITS,
+            Protocol::Rc6 => RC6_BITS,
+            Protocol::Sirc(bits) => bits,
+        }
+    }
+
+    fn repeat_period(self) -> Duration {
+        match self {
+            Protocol::Nec => NEC_REPEAT_PERIOD,
+            Protocol::Rc5 => RC5_REPEAT_PERIOD,
+            Protocol::Rc6 => RC6_REPEAT_PERIOD,
+            Protocol::Sirc(_) => SIRC_REPEAT_PERIOD,
+        }
+    }
+}
+
+/// The USB CDC write half, shared between command responses and the receiver.
+type UsbSender =
+    Mutex<CriticalSectionRawMutex, cdc_acm::Sender<'static, usb::Driver<'static, USB>>>;
+
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => pio::InterruptHandler<PIO0>;
+    PIO1_IRQ_0 => pio::InterruptHandler<PIO1>;
+    PIO2_IRQ_0 => pio::InterruptHandler<PIO2>;
     USBCTRL_IRQ => usb::InterruptHandler<USB>;
 });
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
-    let pio = p.PIO0;
-    let mut pio = Pio::new(pio, Irqs);
 
     let usb_driver = usb::Driver::new(p.USB, Irqs);
     let usb_config = {
-        let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
-        config.manufacturer = Some("Jabu");
-        config.product = Some("Infrared");
-        config.serial_number = Some("1");
+        let mut config = embassy_usb::Config::new(usb_ids::VID, usb_ids::PID);
+        config.manufacturer = Some(usb_ids::MANUFACTURER);
+        config.product = Some(usb_ids::PRODUCT);
+        config.serial_number = Some(usb_ids::SERIAL_NUMBER);
         config.max_power = 100;
         config.max_packet_size_0 = 64;
         config
@@ -57,7 +114,6 @@ async fn main(spawner: Spawner) {
         static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
         static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
 
-        
         embassy_usb::Builder::new(
             usb_driver,
             usb_config,
@@ -68,102 +124,300 @@ async fn main(spawner: Spawner) {
         )
     };
 
-    let mut class = {
+    let (usb_tx, mut usb_rx, control) = {
         static STATE: StaticCell<cdc_acm::State> = StaticCell::new();
+        static USB_TX: StaticCell<UsbSender> = StaticCell::new();
         let state = STATE.init(cdc_acm::State::new());
-        cdc_acm::CdcAcmClass::new(&mut builder, state, 64)
+        let (tx, rx, control) =
+            cdc_acm::CdcAcmClass::new(&mut builder, state, MAX_PACKET_SIZE as u16)
+                .split_with_control();
+        (&*USB_TX.init(Mutex::new(tx)), rx, control)
     };
 
+    {
+        static USB_STATE: StaticCell<led::UsbStateHandler> = StaticCell::new();
+        builder.handler(USB_STATE.init(led::UsbStateHandler));
+    }
+
     let usb = builder.build();
     unwrap!(spawner.spawn(usb_task(usb)));
 
-    // The PIO programs come from here https://github.com/raspberrypi/pico-examples/tree/master/pio/ir_nec/nec_transmit_library
-
-    let prg_burst = pio_asm!(
-        r#"
-.define NUM_CYCLES 21               ; how many carrier cycles to generate
-.define BURST_IRQ 7                 ; which IRQ should trigger a carrier burst
-.define public TICKS_PER_LOOP 4     ; the number of instructions in the loop (for timing)
+    // The onboard LED
+    unwrap!(spawner.spawn(led::led_task(Output::new(p.PIN_25, Level::Low))));
 
-.wrap_target
-    set X, (NUM_CYCLES - 1)         ; initialise the loop counter
-    wait 1 irq BURST_IRQ            ; wait for the IRQ then clear it
-cycle_loop:
-    set pins, 1                     ; set the pin high (1 cycle)
-    set pins, 0 [1]                 ; set the pin low (2 cycles)
-    jmp X--, cycle_loop             ; (1 more cycle)
-.wrap
-    "#
-    );
+    // Resource usage of the PIO blocks:
+    //  - PIO0: emitter 0, all four state machines, 31 instructions
+    //  - PIO1: receiver, one state machine, 12 instructions
+    //  - PIO2: emitter 1, all four state machines, 31 instructions
+    let mut emitter0 = Emitter::new(Pio::new(p.PIO0, Irqs), p.PIN_5);
+    let mut emitter1 = Emitter::new(Pio::new(p.PIO2, Irqs), p.PIN_7);
+    let mut emitters: [&mut dyn Transmit; 2] = [&mut emitter0, &mut emitter1];
 
-    let prg_control = pio_asm!(
+    // The receiver lives on PIO1, since the emitters fill up the others.
+    // It measures the durations of marks and spaces on the output of the
+    // receiver module (which is active low) and `receive_task` decodes them.
+    let prg_receive = pio_asm!(
         r#"
-.define BURST_IRQ 7                     ; the IRQ used to trigger a carrier burst
-.define NUM_INITIAL_BURSTS 16           ; how many bursts to transmit for a 'sync burst'
+.define public TICKS_PER_COUNT 2        ; the number of instructions in the loops (for timing)
 
 .wrap_target
-    pull                                ; fetch a data word from the transmit FIFO into the
-                                        ; output shift register, blocking if the FIFO is empty
-
-    set X, (NUM_INITIAL_BURSTS - 1)     ; send a sync burst (9ms)
-long_burst:
-    irq BURST_IRQ
-    jmp X-- long_burst
-
-    nop [15]                            ; send a 4.5ms space
-    irq BURST_IRQ [1]                   ; send a 562.5us burst to begin the first data bit
-
-data_bit:
-    out X, 1                            ; shift the least-significant bit from the OSR
-    jmp !X burst                        ; send a short delay for a '0' bit
-    nop [3]                             ; send an additional delay for a '1' bit
-burst:
-    irq BURST_IRQ                       ; send a 562.5us burst to end the data bit
-
-jmp !OSRE data_bit                      ; continue sending bits until the OSR is empty
-
-.wrap                                   ; fetch another data word from the FIFO
+    wait 0 pin 0                        ; wait for a mark to start
+    mov X, ~NULL
+mark:
+    jmp pin mark_end                    ; the pin going high ends the mark
+    jmp X-- mark
+mark_end:
+    mov ISR, ~X                         ; X counted down from all ones
+    push
+    mov X, ~NULL
+space:
+    jmp pin space_cont                  ; the pin going low ends the space
+    jmp space_end
+space_cont:
+    jmp X-- space
+space_end:
+    mov ISR, ~X
+    push
+.wrap
     "#
     );
 
     {
+        let mut pio1 = Pio::new(p.PIO1, Irqs);
+        let mut in_pin = pio1.common.make_pio_pin(p.PIN_6);
+        // Keeps the input idle when no receiver is connected
+        in_pin.set_pull(Pull::Up);
         let mut cfg = pio::Config::default();
-        cfg.use_program(&pio.common.load_program(&prg_burst.program), &[]);
-        let out_pin = pio.common.make_pio_pin(p.PIN_5);
-        cfg.set_set_pins(&[&out_pin]);
-        pio.sm0.set_pin_dirs(pio::Direction::Out, &[&out_pin]);
+        cfg.use_program(&pio1.common.load_program(&prg_receive.program), &[]);
+        cfg.set_in_pins(&[&in_pin]);
+        cfg.set_jmp_pin(&in_pin);
+        cfg.fifo_join = FifoJoin::RxOnly;
+        // One count per microsecond
         cfg.clock_divider = ((clk_sys_freq() as f64)
-            / (38222. * (prg_burst.public_defines.TICKS_PER_LOOP as f64)))
+            / (1e6 * (prg_receive.public_defines.TICKS_PER_COUNT as f64)))
             .to_fixed();
-        pio.sm0.set_config(&cfg);
-        pio.sm0.set_enable(true);
+        pio1.sm0.set_pin_dirs(pio::Direction::In, &[&in_pin]);
+        pio1.sm0.set_config(&cfg);
+        pio1.sm0.set_enable(true);
+        unwrap!(spawner.spawn(receive::receive_task(pio1.sm0, usb_tx)));
     }
 
-    let tick_rate = 2. * (1. / 562.5e-6);
-
-    {
-        let mut cfg = pio::Config::default();
-        cfg.use_program(&pio.common.load_program(&prg_control.program), &[]);
-        cfg.fifo_join = FifoJoin::TxOnly;
-        cfg.clock_divider = ((clk_sys_freq() as f64) / tick_rate).to_fixed();
-        pio.sm1.set_config(&cfg);
-        pio.sm1.set_enable(true);
-    }
+    let mut storage = Storage::new(p.FLASH);
+    // Durations of the last stored sequence played
+    let mut stored = [0; MAX_PULSES];
 
     info!("Hi");
-    let mut buf = [0; 64];
+    let mut commands = Reassembler::new();
+    let mut buf = [0; MAX_PACKET_SIZE];
+    // Whether the host has the port open, as told by DTR
+    let mut host_open = usb_rx.dtr();
     loop {
-        let sz = class.read_packet(&mut buf).await.unwrap();
-        if sz == 0 {
+        // Reading a packet is cancel safe, it stays in the endpoint buffer
+        let read = match select(usb_rx.read_packet(&mut buf), control.control_changed()).await {
+            Either::First(read) => read,
+            Either::Second(()) => {
+                let dtr = usb_rx.dtr();
+                if host_open && !dtr {
+                    info!("Host closed the port");
+                    // Don't leave a partial command for the next host
+                    commands = Reassembler::new();
+                    led::host_closed();
+                }
+                host_open = dtr;
+                continue;
+            }
+        };
+        let sz = match read {
+            Ok(sz) => sz,
+            Err(EndpointError::BufferOverflow) => {
+                error!(
+                    "USB packet larger than {} bytes, dropping it",
+                    MAX_PACKET_SIZE
+                );
+                reply(usb_tx, b"ERR overflow\n").await;
+                continue;
+            }
+            Err(EndpointError::Disabled) => {
+                info!("USB disconnected, waiting for the host");
+                // Whatever was left of a command won't be completed now
+                commands = Reassembler::new();
+                usb_rx.wait_connection().await;
+                continue;
+            }
+        };
+        if !commands.push(&buf[..sz]) {
+            error!("Receive buffer overflow, dropping buffered data");
+            reply(usb_tx, b"ERR overflow\n").await;
             continue;
         }
-        let data = str::from_utf8(&buf[..sz]).unwrap();
-        let Ok(value) = u32::from_str_radix(data, 16) else {
-            error!("Can't parse hex u32: {:?}", data);
-            continue;
-        };
-        info!("sz: {}, value: {:x}", sz, value);
-        pio.sm1.tx().push(value);
+        while let Some(command) = commands.next(emitters.len()) {
+            let Request {
+                emitter,
+                protocol,
+                value,
+                repeats,
+            } = match command {
+                Ok(Command::Transmit(request)) => request,
+                Ok(Command::Ping) => {
+                    reply(usb_tx, b"OK\n").await;
+                    continue;
+                }
+                Ok(Command::Info) => {
+                    reply(usb_tx, INFO).await;
+                    continue;
+                }
+                Ok(Command::Pulses(count)) => {
+                    info!("pulses: {}", count);
+                    // Acknowledged up front, a sequence may take longer than
+                    // the host waits for a response
+                    reply(usb_tx, b"OK\n").await;
+                    send_pulses(&mut *emitters[0], commands.pulses(count)).await;
+                    check_stall(0, &mut *emitters[0]);
+                    continue;
+                }
+                Ok(Command::StorePulses { slot, count }) => {
+                    info!("store pulses: slot {}, {} pulses", slot, count);
+                    let response = match storage.store(slot, commands.pulses(count)) {
+                        Ok(()) => &b"OK\n"[..],
+                        Err(response) => response,
+                    };
+                    reply(usb_tx, response).await;
+                    continue;
+                }
+                Ok(Command::Play(slot)) => {
+                    let count = match storage.load(slot, &mut stored) {
+                        Ok(count) => count,
+                        Err(response) => {
+                            reply(usb_tx, response).await;
+                            continue;
+                        }
+                    };
+                    info!("play: slot {}, {} pulses", slot, count);
+                    // Acknowledged up front like any pulse sequence
+                    reply(usb_tx, b"OK\n").await;
+                    send_pulses(&mut *emitters[0], &stored[..count]).await;
+                    check_stall(0, &mut *emitters[0]);
+                    continue;
+                }
+                Ok(Command::NecRepeat) => {
+                    info!("NEC repeat");
+                    let start = Instant::now();
+                    emitters[0].repeat(Protocol::Nec, 0);
+                    led::transmitted();
+                    reply(usb_tx, b"OK\n").await;
+                    // Paced like the repeats of a frame, so the host can send
+                    // the next one as soon as this one is acknowledged
+                    Timer::at(start + Protocol::Nec.repeat_period()).await;
+                    check_stall(0, &mut *emitters[0]);
+                    continue;
+                }
+                Ok(Command::SetCarrier { emitter, hz }) => {
+                    info!("emitter: {}, carrier: {} Hz", emitter, hz);
+                    emitters[emitter].set_carrier(hz);
+                    reply(usb_tx, b"OK\n").await;
+                    continue;
+                }
+                Err(response) => {
+                    reply(usb_tx, response).await;
+                    continue;
+                }
+            };
+            info!(
+                "emitter: {}, protocol: {}, value: {:x}, repeats: {}",
+                emitter, protocol, value, repeats
+            );
+            let index = emitter;
+            let emitter = &mut emitters[emitter];
+            let start = Instant::now();
+            emitter.send(protocol, value);
+            led::transmitted();
+            reply(usb_tx, b"OK\n").await;
+            let period = protocol.repeat_period();
+            for i in 1..=repeats as u32 {
+                Timer::at(start + period * i).await;
+                emitter.repeat(protocol, value);
+            }
+            // Let the last frame finish before taking the next command, so the
+            // symbol program isn't reconfigured under a frame in flight.
+            Timer::at(start + period * (repeats as u32 + 1)).await;
+            check_stall(index, &mut **emitter);
+        }
+    }
+}
+
+/// Recovers an emitter whose state machines didn't take everything queued
+/// by the time the frames should be over, which would otherwise keep it from
+/// transmitting anything until the next power cycle.
+fn check_stall(index: usize, emitter: &mut dyn Transmit) {
+    if emitter.recover_stall() {
+        error!("Emitter {} stalled, restarted its state machines", index);
+    }
+}
+
+/// How often a pulse sequence checks for room in the FIFO. A word of it takes
+/// at least two carrier cycles, so the 8 word FIFO lasts long enough.
+const PULSE_POLL_INTERVAL: Duration = Duration::from_micros(200);
+/// How long a pulse sequence waits for room in the FIFO before giving up on
+/// the state machine. A word takes at most two 65.5ms pulses.
+const PULSE_STALL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Transmits mark and space durations in microseconds, starting with a mark,
+/// feeding them to the emitter as it makes room. Returns once the last one
+/// is over.
+async fn send_pulses(emitter: &mut dyn Transmit, pulses: &[u16]) {
+    let hz = emitter.start_pulses() as u64;
+    let cycles = |us: u16| ((us as u64 * hz + 500_000) / 1_000_000).clamp(1, 65536) as u32;
+    let start = Instant::now();
+    // Each pair takes an instruction more than its carrier cycles
+    let mut ticks = 0;
+    for pair in pulses.chunks(2) {
+        let mark = cycles(pair[0]);
+        // A sequence ending with a mark gets the shortest space possible
+        let space = pair.get(1).map_or(1, |&us| cycles(us));
+        let waiting = Instant::now();
+        while !emitter.try_push_pulse(mark, space) {
+            if waiting.elapsed() > PULSE_STALL_TIMEOUT {
+                // Left to the stall check after the sequence
+                error!("Pulse sequence stalled, dropping the rest of it");
+                return;
+            }
+            Timer::after(PULSE_POLL_INTERVAL).await;
+        }
+        ticks += 4 * (mark + space) as u64 + 2;
+    }
+    led::transmitted();
+    Timer::at(start + Duration::from_micros(ticks * 1_000_000 / (4 * hz) + 1)).await;
+}
+
+/// Largest USB packet of the CDC ACM data endpoints, and so the most a single
+/// read returns.
+const MAX_PACKET_SIZE: usize = 64;
+
+/// Response to the `info` command, telling the host which build this is and
+/// what it can ask for. Space separated `key=value` pairs, lists are comma
+/// separated. `version` is the one `rp_cargo_version!` embeds, and `emitters`
+/// must match the length of the `emitters` array in `main`. Longer than a
+/// packet, which [`reply`] takes care of.
+const INFO: &[u8] = concat!(
+    "INFO version=",
+    env!("CARGO_PKG_VERSION"),
+    " protocols=nec,rc5,rc6,sirc,pulses,nec-repeat,stored-pulses,framed emitters=2 receiver=1\n"
+)
+.as_bytes();
+
+/// Writes a response line back to the host, split into packets as long lines
+/// don't fit into one. A line filling its last packet is followed by an empty
+/// one, which tells the host that the transfer ended. Failures are only
+/// logged, the host treats a missing response the same as a lost one.
+async fn reply(usb_tx: &UsbSender, msg: &[u8]) {
+    let mut usb_tx = usb_tx.lock().await;
+    let end: &[u8] = &[];
+    let trailer = msg.len().is_multiple_of(MAX_PACKET_SIZE).then_some(end);
+    for packet in msg.chunks(MAX_PACKET_SIZE).chain(trailer) {
+        if usb_tx.write_packet(packet).await.is_err() {
+            error!("Failed to write response to USB");
+            return;
+        }
     }
 }
 
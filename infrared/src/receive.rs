@@ -0,0 +1,97 @@
+//! Decoding of NEC frames picked up by the IR receiver.
+//!
+//! The receive PIO program pushes alternating mark and space durations in
+//! microseconds, always starting with a mark. Decoded frames are reported to
+//! the host as `RX <hexword>\n`, where the hex word is in the same format the
+//! transmit side accepts.
+
+use defmt::{debug, info};
+use embassy_rp::{peripherals::PIO1, pio::StateMachine};
+
+use crate::{UsbSender, reply};
+
+/// Relative tolerance of received durations, as the receiver module skews
+/// mark/space lengths by a fair bit.
+fn within(us: u32, nominal: u32) -> bool {
+    let us = us.saturating_mul(4);
+    us > nominal * 3 && us < nominal * 5
+}
+
+#[derive(Default)]
+struct NecDecoder {
+    /// Set after a leader mark, until the following space is seen.
+    leader: bool,
+    /// Number of data bits received so far, `None` when not inside a frame.
+    bits: Option<u32>,
+    value: u32,
+}
+
+impl NecDecoder {
+    /// Returns the frame when the mark terminating its last bit is seen.
+    fn mark(&mut self, us: u32) -> Option<u32> {
+        if within(us, 9000) {
+            *self = NecDecoder {
+                leader: true,
+                ..Default::default()
+            };
+            return None;
+        }
+        if !within(us, 562) {
+            *self = NecDecoder::default();
+            return None;
+        }
+        if self.bits == Some(32) {
+            let value = self.value;
+            *self = NecDecoder::default();
+            return Some(value);
+        }
+        None
+    }
+
+    fn space(&mut self, us: u32) {
+        if self.leader {
+            self.leader = false;
+            if within(us, 4500) {
+                self.bits = Some(0);
+            } else {
+                // Most likely a repeat frame (2.25ms space), which we don't report
+                debug!("Ignoring frame with leader space {}us", us);
+            }
+            return;
+        }
+        let Some(bits) = self.bits else {
+            return;
+        };
+        let bit = if within(us, 562) {
+            0
+        } else if within(us, 1687) {
+            1
+        } else {
+            *self = NecDecoder::default();
+            return;
+        };
+        // NEC is transmitted LSB first
+        self.value |= bit << bits;
+        self.bits = Some(bits + 1);
+    }
+}
+
+#[embassy_executor::task]
+pub async fn receive_task(mut sm: StateMachine<'static, PIO1, 0>, usb_tx: &'static UsbSender) -> ! {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+
+    let mut decoder = NecDecoder::default();
+    loop {
+        let mark = sm.rx().wait_pull().await;
+        if let Some(value) = decoder.mark(mark) {
+            info!("Received NEC frame: {:x}", value);
+            let mut line = *b"RX 00000000\n";
+            for (i, c) in line[3..11].iter_mut().enumerate() {
+                *c = HEX[(value >> (28 - 4 * i)) as usize & 0xf];
+            }
+            reply(usb_tx, &line).await;
+        }
+        let space = sm.rx().wait_pull().await;
+        decoder.space(space);
+    }
+}
@@ -0,0 +1,101 @@
+//! Pulse sequences kept in flash across power cycles, so that the host can
+//! transmit frequently used ones by their slot instead of sending all of
+//! their durations each time.
+//!
+//! Each slot takes a sector at the end of the flash, which `memory.x` leaves
+//! out of the program's region. A slot holds a magic number and the count of
+//! pulses, both little-endian u16, followed by the durations.
+
+use defmt::error;
+use embassy_rp::{
+    flash::{Blocking, ERASE_SIZE, Flash},
+    peripherals::FLASH,
+};
+
+use crate::command::MAX_PULSES;
+
+/// Size of the flash of the board, `memory.x` must agree.
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+/// How many sequences can be stored, the `wire::STORED_PULSES_SLOTS` of the
+/// host must agree.
+pub const SLOTS: usize = 8;
+const STORAGE_OFFSET: u32 = (FLASH_SIZE - SLOTS * ERASE_SIZE) as u32;
+/// Marks a written slot, erased flash reads as all ones.
+const MAGIC: u16 = 0x5053;
+const HEADER_LEN: u32 = 4;
+
+pub struct Storage {
+    flash: Flash<'static, FLASH, Blocking, FLASH_SIZE>,
+}
+
+impl Storage {
+    pub fn new(flash: FLASH) -> Self {
+        Storage {
+            flash: Flash::new_blocking(flash),
+        }
+    }
+
+    fn offset(slot: usize) -> Result<u32, &'static [u8]> {
+        if slot >= SLOTS {
+            error!("Invalid slot: {}", slot);
+            return Err(b"ERR badslot\n");
+        }
+        Ok(STORAGE_OFFSET + (slot * ERASE_SIZE) as u32)
+    }
+
+    /// Replaces the sequence in `slot` with `pulses`. The header is written
+    /// last, so a slot interrupted while being written reads as empty.
+    pub fn store(&mut self, slot: usize, pulses: &[u16]) -> Result<(), &'static [u8]> {
+        let offset = Self::offset(slot)?;
+        let mut durations = [0; 2 * MAX_PULSES];
+        for (bytes, pulse) in durations.as_chunks_mut::<2>().0.iter_mut().zip(pulses) {
+            *bytes = pulse.to_le_bytes();
+        }
+        let [m0, m1] = MAGIC.to_le_bytes();
+        let [c0, c1] = (pulses.len() as u16).to_le_bytes();
+        self.flash
+            .blocking_erase(offset, offset + ERASE_SIZE as u32)
+            .and_then(|()| {
+                self.flash
+                    .blocking_write(offset + HEADER_LEN, &durations[..2 * pulses.len()])
+            })
+            .and_then(|()| self.flash.blocking_write(offset, &[m0, m1, c0, c1]))
+            .map_err(|e| {
+                error!("Failed to write slot {}: {}", slot, e);
+                &b"ERR flash\n"[..]
+            })
+    }
+
+    /// Reads the sequence in `slot` into `pulses`, returning how many there
+    /// are.
+    pub fn load(
+        &mut self,
+        slot: usize,
+        pulses: &mut [u16; MAX_PULSES],
+    ) -> Result<usize, &'static [u8]> {
+        let offset = Self::offset(slot)?;
+        let mut header = [0; HEADER_LEN as usize];
+        let mut durations = [0; 2 * MAX_PULSES];
+        let read = self
+            .flash
+            .blocking_read(offset, &mut header)
+            .and_then(|()| {
+                self.flash
+                    .blocking_read(offset + HEADER_LEN, &mut durations)
+            });
+        if let Err(e) = read {
+            error!("Failed to read slot {}: {}", slot, e);
+            return Err(b"ERR flash\n");
+        }
+        let [m0, m1, c0, c1] = header;
+        let count = u16::from_le_bytes([c0, c1]) as usize;
+        if u16::from_le_bytes([m0, m1]) != MAGIC || !(1..=MAX_PULSES).contains(&count) {
+            error!("Slot {} is empty", slot);
+            return Err(b"ERR empty\n");
+        }
+        for (pulse, bytes) in pulses.iter_mut().zip(durations.as_chunks::<2>().0) {
+            *pulse = u16::from_le_bytes(*bytes);
+        }
+        Ok(count)
+    }
+}
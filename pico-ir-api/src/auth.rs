@@ -0,0 +1,68 @@
+//! Optional bearer token authentication of the POST handlers.
+
+use poem::{
+    Endpoint, Middleware, Request, Result,
+    http::{Method, StatusCode, header},
+};
+
+use crate::json_error;
+
+/// Requires `Authorization: Bearer <token>` on POST requests when a token is
+/// configured, and lets everything through otherwise.
+pub struct BearerAuth {
+    token: Option<String>,
+}
+
+impl BearerAuth {
+    pub fn new(token: Option<String>) -> Self {
+        BearerAuth { token }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for BearerAuth {
+    type Output = BearerAuthEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        BearerAuthEndpoint {
+            ep,
+            token: self.token.clone(),
+        }
+    }
+}
+
+pub struct BearerAuthEndpoint<E> {
+    ep: E,
+    token: Option<String>,
+}
+
+impl<E: Endpoint> Endpoint for BearerAuthEndpoint<E> {
+    type Output = E::Output;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        if let Some(token) = &self.token
+            && req.method() == Method::POST
+            && !is_authorized(&req, token)
+        {
+            return Err(json_error(
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "a valid bearer token is required",
+            ));
+        }
+        self.ep.call(req).await
+    }
+}
+
+fn is_authorized(req: &Request, token: &str) -> bool {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+}
+
+/// Compares without exiting early, so the time taken doesn't reveal how much
+/// of the token was guessed correctly.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
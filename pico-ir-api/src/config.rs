@@ -0,0 +1,640 @@
+//! Settings of the server, read from an optional TOML file. Every setting can
+//! also be given in an environment variable, which takes precedence over the
+//! file. The file is read again on SIGHUP, see [`Config::fixed_changes`] for
+//! what that can't change.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use backon::ExponentialBuilder;
+use pico_ir_proto::{AudioInput, NecAddress};
+use serde::{Deserialize, Deserializer, de::Error as _};
+
+use crate::{BatchCommand, BatchEntry, MAX_REPEAT, RawFilter, mqtt::MqttTarget};
+
+const DEFAULT_BIND: &str = "127.0.0.1:9912";
+const DEFAULT_SERIAL_PATH: &str = "/dev/serial/by-id/usb-Jabu_Infrared_1-if00";
+const DEFAULT_BAUD_RATE: u32 = 115200;
+const DEFAULT_QUEUE_CAPACITY: usize = 1;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// TCP addresses to listen on when not socket activated, comma separated.
+    /// `PICO_IR_BIND`
+    pub bind: String,
+    /// Unix socket to listen on instead of TCP when not socket activated.
+    /// `PICO_IR_UNIX_SOCKET`
+    pub unix_socket: Option<PathBuf>,
+    /// Permissions the Unix socket is created with. `PICO_IR_UNIX_SOCKET_MODE`,
+    /// in octal
+    pub unix_socket_mode: u32,
+    /// `PICO_IR_LOG_FORMAT`
+    pub log_format: LogFormat,
+    /// Bearer token required on POST requests. `PICO_IR_TOKEN`
+    pub token: Option<String>,
+    /// How long the response to a POST request with an `Idempotency-Key` is
+    /// replayed to requests with the same key, 0 disables.
+    /// `PICO_IR_IDEMPOTENCY_WINDOW_MS`
+    pub idempotency_window_ms: u64,
+    /// Never open the serial ports and only log the frames. `PICO_IR_DRY_RUN`
+    pub dry_run: bool,
+    /// Serial port of the device when no `devices` are given, or
+    /// an `mqtt://` or `mqtts://` URL as described by [`MqttTarget`] to send
+    /// its commands through `pico-ir-mqtt` instead. `PICO_IR_SERIAL`
+    pub serial: String,
+    /// `PICO_IR_BAUD`
+    pub baud: u32,
+    /// How many times opening a serial port is retried before the device is
+    /// given up on, or `"forever"`. `PICO_IR_SERIAL_OPEN_RETRIES`
+    pub serial_open_retries: OpenRetries,
+    /// Wait before the first retry, doubled on each one after.
+    /// `PICO_IR_SERIAL_OPEN_BASE_DELAY_MS`
+    pub serial_open_base_delay_ms: u64,
+    /// Longest wait between retries. With the defaults, a port that doesn't
+    /// show up is given up on after about 11 minutes: 1, 2, 4, 8, 16 and 32s,
+    /// then 60s ten times. `PICO_IR_SERIAL_OPEN_MAX_DELAY_MS`
+    pub serial_open_max_delay_ms: u64,
+    /// NEC address used by commands that don't specify one, unless the
+    /// device has its own. `PICO_IR_NEC_ADDRESS`
+    pub nec_address: NecAddress,
+    /// Commands each device queues before rejecting more.
+    /// `PICO_IR_QUEUE_CAPACITY`
+    pub queue_capacity: usize,
+    /// How many transmitted commands each device keeps for `/history`, 0
+    /// disables. `PICO_IR_HISTORY_SIZE`
+    pub history_size: usize,
+    /// How long a command waits for room in a full queue before it's
+    /// rejected. `PICO_IR_ENQUEUE_TIMEOUT_MS`
+    pub enqueue_timeout_ms: u64,
+    /// Shortest time between the starts of consecutive frames. An NEC frame
+    /// takes about 67ms to transmit, so sending them any faster only piles
+    /// them up in the firmware. `PICO_IR_MIN_FRAME_SPACING_MS`
+    pub min_frame_spacing_ms: u64,
+    /// Pause after each frame written to the serial port before the next
+    /// command is taken, for slow serial bridges or parsers.
+    /// `PICO_IR_WRITE_DELAY_MS`
+    pub write_delay_ms: u64,
+    /// How many times a frame the firmware rejected is sent again.
+    /// `PICO_IR_REJECT_RETRIES`
+    pub reject_retries: u8,
+    /// Wait before each of those retries. `PICO_IR_REJECT_RETRY_DELAY_MS`
+    pub reject_retry_delay_ms: u64,
+    /// How long a serial link may sit idle before it's pinged, 0 disables the
+    /// pings. `PICO_IR_HEARTBEAT_MS`
+    pub heartbeat_ms: u64,
+    /// Default wait between the two toggles of the power-on hack.
+    /// `PICO_IR_POWER_ON_GAP_MS`
+    pub power_on_gap_ms: u64,
+    /// Wait after the second toggle of the power-on hack before the next
+    /// command is taken, 0 disables. `PICO_IR_POWER_ON_SETTLE_MS`
+    pub power_on_settle_ms: u64,
+    /// How long after a power-on hack finished further ones are dropped.
+    /// `PICO_IR_POWER_ON_DEBOUNCE_MS`
+    pub power_on_debounce_ms: u64,
+    /// How long after an input was selected selecting the same one again is
+    /// dropped, 0 disables. `PICO_IR_INPUT_COALESCE_MS`
+    pub input_coalesce_ms: u64,
+    /// The inputs `/input/toggle` switches between. `PICO_IR_TOGGLE_INPUTS`,
+    /// as two comma separated input names
+    pub toggle_inputs: [AudioInput; 2],
+    /// What the last known input becomes when the power is toggled.
+    /// `PICO_IR_INPUT_AFTER_POWER_TOGGLE`
+    pub input_after_power_toggle: InputAfterPower,
+    /// How many times the code of an input is sent to select it, by input
+    /// name, for inputs that don't always register the first one. Once for
+    /// those not given. `PICO_IR_INPUT_REPEATS`, as comma separated
+    /// `<input>=<count>`
+    pub input_repeats: HashMap<String, u8>,
+    /// The only bytes permitted as raw commands. `PICO_IR_RAW_ALLOW`, as
+    /// comma separated hex bytes
+    pub raw_allow: Option<Vec<u8>>,
+    /// Bytes never permitted as raw commands. `PICO_IR_RAW_DENY`, as comma
+    /// separated hex bytes
+    pub raw_deny: Option<Vec<u8>>,
+    /// `PICO_IR_DEVICES`, as a comma separated list of
+    /// `<name>=<serial>[@<address>]`
+    pub devices: Vec<DeviceConfig>,
+    /// Named sequences of commands, in the format of a `/command` batch, run
+    /// with `/macro/<name>`. Only in the config file.
+    pub macros: HashMap<String, Vec<BatchEntry>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: DEFAULT_BIND.into(),
+            unix_socket: None,
+            unix_socket_mode: 0o660,
+            log_format: LogFormat::Text,
+            token: None,
+            idempotency_window_ms: 60_000,
+            dry_run: false,
+            serial: DEFAULT_SERIAL_PATH.into(),
+            baud: DEFAULT_BAUD_RATE,
+            serial_open_retries: OpenRetries::Times(16),
+            serial_open_base_delay_ms: 1000,
+            serial_open_max_delay_ms: 60_000,
+            nec_address: NecAddress::DEFAULT,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            history_size: 50,
+            enqueue_timeout_ms: 5000,
+            min_frame_spacing_ms: 50,
+            write_delay_ms: 0,
+            reject_retries: 2,
+            reject_retry_delay_ms: 100,
+            heartbeat_ms: 5000,
+            power_on_gap_ms: 3000,
+            power_on_settle_ms: 3000,
+            power_on_debounce_ms: 5000,
+            input_coalesce_ms: 0,
+            toggle_inputs: [AudioInput::Optical, AudioInput::Bluetooth],
+            input_after_power_toggle: InputAfterPower::Unknown,
+            input_repeats: HashMap::new(),
+            raw_allow: None,
+            raw_deny: None,
+            devices: Vec::new(),
+            macros: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// How many times opening a serial port is retried, a number or `forever`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpenRetries {
+    Times(usize),
+    Forever,
+}
+
+impl FromStr for OpenRetries {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "forever" => Ok(OpenRetries::Forever),
+            n => n.parse().map(OpenRetries::Times),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for OpenRetries {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Times(usize),
+            Word(String),
+        }
+        match Value::deserialize(deserializer)? {
+            Value::Times(n) => Ok(OpenRetries::Times(n)),
+            Value::Word(w) if w == "forever" => Ok(OpenRetries::Forever),
+            Value::Word(w) => Err(D::Error::custom(format!(
+                "expected a number or \"forever\", got \"{w}\""
+            ))),
+        }
+    }
+}
+
+/// What a device is assumed to do with its input when it's turned off and on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum InputAfterPower {
+    /// It remembers the input, `keep`.
+    Keep,
+    /// The input is no longer known, `unknown`.
+    Unknown,
+    /// It starts on a fixed input, given by its name.
+    Input(AudioInput),
+}
+
+impl InputAfterPower {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "keep" => Some(InputAfterPower::Keep),
+            "unknown" => Some(InputAfterPower::Unknown),
+            name => AudioInput::from_name(name).map(InputAfterPower::Input),
+        }
+    }
+}
+
+impl TryFrom<String> for InputAfterPower {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::parse(&s).ok_or_else(|| {
+            format!(
+                "expected keep, unknown or {}, got \"{s}\"",
+                AudioInput::EXPECTED.trim_start_matches("one of ")
+            )
+        })
+    }
+}
+
+/// A device managed by this server, each with its own serial port and IR task.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub name: String,
+    /// A serial port or an MQTT URL, like the `serial` of the whole server.
+    pub serial: String,
+    /// Falls back to the `nec_address` of the whole server.
+    pub address: Option<NecAddress>,
+}
+
+impl Config {
+    /// Reads the config file at `path`, if any, and applies the environment
+    /// variables over it.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let mut config = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("Could not read {}", path.display()))?;
+                toml::from_str(&text).with_context(|| format!("Invalid {}", path.display()))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        fn var<T: FromStr>(name: &str) -> anyhow::Result<Option<T>>
+        where
+            T::Err: std::error::Error + Send + Sync + 'static,
+        {
+            std::env::var(name)
+                .ok()
+                .map(|v| v.parse().with_context(|| format!("Invalid {name}")))
+                .transpose()
+        }
+
+        fn hex_list(name: &str) -> anyhow::Result<Option<Vec<u8>>> {
+            let Ok(list) = std::env::var(name) else {
+                return Ok(None);
+            };
+            list.split(',')
+                .map(|b| {
+                    let b = b.trim();
+                    u8::from_str_radix(b.strip_prefix("0x").unwrap_or(b), 16)
+                        .with_context(|| format!("Invalid byte '{b}'"))
+                })
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| format!("Invalid {name}"))
+                .map(Some)
+        }
+
+        if let Some(v) = var("PICO_IR_BIND")? {
+            self.bind = v;
+        }
+        if let Some(v) = var("PICO_IR_UNIX_SOCKET")? {
+            self.unix_socket = Some(v);
+        }
+        if let Ok(v) = std::env::var("PICO_IR_UNIX_SOCKET_MODE") {
+            self.unix_socket_mode =
+                u32::from_str_radix(&v, 8).context("Invalid PICO_IR_UNIX_SOCKET_MODE")?;
+        }
+        if let Ok(v) = std::env::var("PICO_IR_LOG_FORMAT") {
+            self.log_format = if v == "json" {
+                LogFormat::Json
+            } else {
+                LogFormat::Text
+            };
+        }
+        if let Some(v) = var("PICO_IR_TOKEN")? {
+            self.token = Some(v);
+        }
+        if let Some(v) = var("PICO_IR_IDEMPOTENCY_WINDOW_MS")? {
+            self.idempotency_window_ms = v;
+        }
+        if let Ok(v) = std::env::var("PICO_IR_DRY_RUN") {
+            self.dry_run = v == "1";
+        }
+        if let Some(v) = var("PICO_IR_SERIAL")? {
+            self.serial = v;
+        }
+        if let Some(v) = var("PICO_IR_BAUD")? {
+            self.baud = v;
+        }
+        if let Some(v) = var("PICO_IR_SERIAL_OPEN_RETRIES")? {
+            self.serial_open_retries = v;
+        }
+        if let Some(v) = var("PICO_IR_SERIAL_OPEN_BASE_DELAY_MS")? {
+            self.serial_open_base_delay_ms = v;
+        }
+        if let Some(v) = var("PICO_IR_SERIAL_OPEN_MAX_DELAY_MS")? {
+            self.serial_open_max_delay_ms = v;
+        }
+        if let Ok(v) = std::env::var("PICO_IR_NEC_ADDRESS") {
+            self.nec_address = NecAddress::from_hex(&v).context("Invalid PICO_IR_NEC_ADDRESS")?;
+        }
+        if let Some(v) = var("PICO_IR_QUEUE_CAPACITY")? {
+            self.queue_capacity = v;
+        }
+        if let Some(v) = var("PICO_IR_HISTORY_SIZE")? {
+            self.history_size = v;
+        }
+        if let Some(v) = var("PICO_IR_ENQUEUE_TIMEOUT_MS")? {
+            self.enqueue_timeout_ms = v;
+        }
+        if let Some(v) = var("PICO_IR_MIN_FRAME_SPACING_MS")? {
+            self.min_frame_spacing_ms = v;
+        }
+        if let Some(v) = var("PICO_IR_WRITE_DELAY_MS")? {
+            self.write_delay_ms = v;
+        }
+        if let Some(v) = var("PICO_IR_REJECT_RETRIES")? {
+            self.reject_retries = v;
+        }
+        if let Some(v) = var("PICO_IR_REJECT_RETRY_DELAY_MS")? {
+            self.reject_retry_delay_ms = v;
+        }
+        if let Some(v) = var("PICO_IR_HEARTBEAT_MS")? {
+            self.heartbeat_ms = v;
+        }
+        if let Some(v) = var("PICO_IR_POWER_ON_GAP_MS")? {
+            self.power_on_gap_ms = v;
+        }
+        if let Some(v) = var("PICO_IR_POWER_ON_SETTLE_MS")? {
+            self.power_on_settle_ms = v;
+        }
+        if let Some(v) = var("PICO_IR_POWER_ON_DEBOUNCE_MS")? {
+            self.power_on_debounce_ms = v;
+        }
+        if let Some(v) = var("PICO_IR_INPUT_COALESCE_MS")? {
+            self.input_coalesce_ms = v;
+        }
+        if let Ok(v) = std::env::var("PICO_IR_TOGGLE_INPUTS") {
+            let inputs = v
+                .split(',')
+                .map(|name| {
+                    AudioInput::from_name(name.trim())
+                        .with_context(|| format!("Invalid input '{name}'"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+                .context("Invalid PICO_IR_TOGGLE_INPUTS")?;
+            self.toggle_inputs = inputs
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("PICO_IR_TOGGLE_INPUTS must name two inputs"))?;
+        }
+        if let Ok(v) = std::env::var("PICO_IR_INPUT_AFTER_POWER_TOGGLE") {
+            self.input_after_power_toggle =
+                InputAfterPower::parse(&v).context("Invalid PICO_IR_INPUT_AFTER_POWER_TOGGLE")?;
+        }
+        if let Ok(list) = std::env::var("PICO_IR_INPUT_REPEATS") {
+            self.input_repeats = list
+                .split(',')
+                .map(|entry| {
+                    let entry = entry.trim();
+                    let (name, count) = entry
+                        .split_once('=')
+                        .with_context(|| format!("Invalid entry '{entry}'"))?;
+                    let count = count
+                        .parse()
+                        .with_context(|| format!("Invalid count of input '{name}'"))?;
+                    Ok((name.into(), count))
+                })
+                .collect::<anyhow::Result<_>>()
+                .context("Invalid PICO_IR_INPUT_REPEATS")?;
+        }
+        if let Some(v) = hex_list("PICO_IR_RAW_ALLOW")? {
+            self.raw_allow = Some(v);
+        }
+        if let Some(v) = hex_list("PICO_IR_RAW_DENY")? {
+            self.raw_deny = Some(v);
+        }
+        if let Ok(list) = std::env::var("PICO_IR_DEVICES") {
+            self.devices = list
+                .split(',')
+                .map(|entry| {
+                    let entry = entry.trim();
+                    let (name, serial) = entry
+                        .split_once('=')
+                        .with_context(|| format!("Invalid PICO_IR_DEVICES entry '{entry}'"))?;
+                    let (serial, address) = match serial.rsplit_once('@') {
+                        Some((serial, a)) => (
+                            serial,
+                            Some(NecAddress::from_hex(a).with_context(|| {
+                                format!("Invalid NEC address of device '{name}'")
+                            })?),
+                        ),
+                        None => (serial, None),
+                    };
+                    Ok(DeviceConfig {
+                        name: name.into(),
+                        serial: serial.into(),
+                        address,
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.queue_capacity > 0, "queue_capacity must be positive");
+        anyhow::ensure!(
+            self.serial_open_base_delay_ms > 0,
+            "serial_open_base_delay_ms must be positive"
+        );
+        anyhow::ensure!(
+            self.serial_open_base_delay_ms <= self.serial_open_max_delay_ms,
+            "serial_open_base_delay_ms must be at most serial_open_max_delay_ms"
+        );
+        anyhow::ensure!(
+            self.bind_addresses().all(|a| !a.is_empty()),
+            "Invalid bind '{}'",
+            self.bind
+        );
+        anyhow::ensure!(
+            self.unix_socket_mode <= 0o777,
+            "unix_socket_mode must be at most 0o777"
+        );
+        anyhow::ensure!(
+            self.toggle_inputs[0] != self.toggle_inputs[1],
+            "toggle_inputs must be two different inputs"
+        );
+        for (name, &count) in &self.input_repeats {
+            anyhow::ensure!(
+                AudioInput::from_name(name).is_some(),
+                "Invalid input '{name}' in input_repeats, expected {}",
+                AudioInput::EXPECTED
+            );
+            anyhow::ensure!(
+                (1..=MAX_REPEAT).contains(&count),
+                "The count of input '{name}' in input_repeats must be between 1 and {MAX_REPEAT}"
+            );
+        }
+        anyhow::ensure!(
+            self.raw_allow.is_none() || self.raw_deny.is_none(),
+            "Only one of raw_allow and raw_deny may be set"
+        );
+        // Both end up in URLs
+        let valid_name = |name: &str| {
+            !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        };
+        for (i, device) in self.devices.iter().enumerate() {
+            let name = &device.name;
+            anyhow::ensure!(valid_name(name), "Invalid device name '{name}'");
+            anyhow::ensure!(
+                self.devices[..i].iter().all(|d| &d.name != name),
+                "Duplicate device name '{name}'"
+            );
+        }
+        for device in self.devices() {
+            if let Some(target) = MqttTarget::parse(&device.serial) {
+                target?;
+                // The bridge only sends to the speakers
+                anyhow::ensure!(
+                    device.address.unwrap_or(self.nec_address) == NecAddress::DEFAULT,
+                    "Device '{}' is reached over MQTT, so it can't have an NEC address",
+                    device.name
+                );
+            }
+        }
+        let raw_filter = RawFilter::from_config(self);
+        for (name, steps) in &self.macros {
+            anyhow::ensure!(valid_name(name), "Invalid macro name '{name}'");
+            for step in steps {
+                if let Some(invalid) = step.cmd.invalid() {
+                    anyhow::bail!("Macro '{name}' can't be run, {invalid}");
+                }
+                if let BatchCommand::Raw { cmd } = step.cmd {
+                    anyhow::ensure!(
+                        raw_filter.permits(cmd),
+                        "Macro '{name}' sends raw command {cmd:#04x}, which is not permitted"
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The configured devices, or a single one named `default` on `serial`
+    /// when there are none.
+    pub fn devices(&self) -> Vec<DeviceConfig> {
+        if self.devices.is_empty() {
+            return vec![DeviceConfig {
+                name: "default".into(),
+                serial: self.serial.clone(),
+                address: None,
+            }];
+        }
+        self.devices.clone()
+    }
+
+    /// The settings that differ in `new` but are only read at startup, so
+    /// changing them takes a restart.
+    pub fn fixed_changes(&self, new: &Config) -> Vec<&'static str> {
+        let endpoints = |config: &Config| -> Vec<(String, String)> {
+            config
+                .devices()
+                .into_iter()
+                .map(|d| (d.name, d.serial))
+                .collect()
+        };
+        [
+            ("bind", self.bind != new.bind),
+            ("unix_socket", self.unix_socket != new.unix_socket),
+            (
+                "unix_socket_mode",
+                self.unix_socket_mode != new.unix_socket_mode,
+            ),
+            ("log_format", self.log_format != new.log_format),
+            ("token", self.token != new.token),
+            (
+                "idempotency_window_ms",
+                self.idempotency_window_ms != new.idempotency_window_ms,
+            ),
+            ("dry_run", self.dry_run != new.dry_run),
+            ("baud", self.baud != new.baud),
+            (
+                "serial_open_retries",
+                self.serial_open_retries != new.serial_open_retries,
+            ),
+            (
+                "serial_open_base_delay_ms",
+                self.serial_open_base_delay_ms != new.serial_open_base_delay_ms,
+            ),
+            (
+                "serial_open_max_delay_ms",
+                self.serial_open_max_delay_ms != new.serial_open_max_delay_ms,
+            ),
+            ("queue_capacity", self.queue_capacity != new.queue_capacity),
+            ("history_size", self.history_size != new.history_size),
+            ("heartbeat_ms", self.heartbeat_ms != new.heartbeat_ms),
+            ("devices", endpoints(self) != endpoints(new)),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+    }
+
+    pub fn bind_addresses(&self) -> impl Iterator<Item = &str> {
+        self.bind.split(',').map(str::trim)
+    }
+
+    pub fn idempotency_window(&self) -> Duration {
+        Duration::from_millis(self.idempotency_window_ms)
+    }
+
+    /// The delays between attempts to open a serial port.
+    pub fn serial_open_backoff(&self) -> ExponentialBuilder {
+        let backoff = ExponentialBuilder::default()
+            .with_min_delay(Duration::from_millis(self.serial_open_base_delay_ms))
+            .with_max_delay(Duration::from_millis(self.serial_open_max_delay_ms));
+        match self.serial_open_retries {
+            OpenRetries::Times(n) => backoff.with_max_times(n),
+            OpenRetries::Forever => backoff.without_max_times(),
+        }
+    }
+
+    pub fn enqueue_timeout(&self) -> Duration {
+        Duration::from_millis(self.enqueue_timeout_ms)
+    }
+
+    pub fn min_frame_spacing(&self) -> Duration {
+        Duration::from_millis(self.min_frame_spacing_ms)
+    }
+
+    pub fn write_delay(&self) -> Duration {
+        Duration::from_millis(self.write_delay_ms)
+    }
+
+    pub fn reject_retry_delay(&self) -> Duration {
+        Duration::from_millis(self.reject_retry_delay_ms)
+    }
+
+    pub fn heartbeat(&self) -> Option<Duration> {
+        (self.heartbeat_ms > 0).then(|| Duration::from_millis(self.heartbeat_ms))
+    }
+
+    pub fn power_on_gap(&self) -> Duration {
+        Duration::from_millis(self.power_on_gap_ms)
+    }
+
+    pub fn power_on_settle(&self) -> Duration {
+        Duration::from_millis(self.power_on_settle_ms)
+    }
+
+    pub fn power_on_debounce(&self) -> Duration {
+        Duration::from_millis(self.power_on_debounce_ms)
+    }
+
+    pub fn input_coalesce(&self) -> Duration {
+        Duration::from_millis(self.input_coalesce_ms)
+    }
+}
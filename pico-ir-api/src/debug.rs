@@ -0,0 +1,74 @@
+//! Endpoints for reverse-engineering and checking the encoding, which never
+//! transmit anything.
+
+use pico_ir_proto::{InfraredCommand, NecAddress};
+use poem::{
+    handler,
+    http::StatusCode,
+    web::{Data, Json, Query},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{CommandSender, frame_hex, json_error, parse_command_byte};
+
+/// Period of the firmware's NEC control program, half of a 562.5us burst.
+/// Like the tick counts below, this assumes the firmware's default
+/// calibration, see `NEC_TICK_S` and the defines of `prg_control`.
+const NEC_TICK_US: f64 = 281.25;
+
+/// Mark and space durations of an NEC frame in control program ticks, as
+/// generated by `prg_control` in the firmware: a 16 burst leader mark and a
+/// 4.5ms space, then a burst followed by a short space for a 0 bit or a long
+/// one for a 1 bit, LSB first, and a final burst ending the last bit.
+fn nec_ticks(frame: u32) -> Vec<u32> {
+    let mut ticks = vec![32, 16];
+    for i in 0..32 {
+        ticks.push(2);
+        ticks.push(if frame & (1 << i) != 0 { 6 } else { 2 });
+    }
+    ticks.push(2);
+    ticks
+}
+
+#[derive(Debug, Deserialize)]
+struct FrameParams {
+    /// The command byte, in hex with a `0x` prefix or in decimal.
+    cmd: String,
+    address: Option<NecAddress>,
+}
+
+#[derive(Debug, Serialize)]
+struct FrameTimeline {
+    frame: String,
+    /// Alternating mark and space durations in microseconds, starting with a
+    /// mark. The marks are nominal, the firmware's carrier bursts end a few
+    /// microseconds early.
+    durations_us: Vec<f64>,
+}
+
+/// The timeline of the frame `cmd` would be sent as.
+#[handler]
+pub async fn get_frame(
+    tx: Data<&CommandSender>,
+    q: Query<FrameParams>,
+) -> poem::Result<Json<FrameTimeline>> {
+    let Some(byte) = parse_command_byte(&q.cmd) else {
+        return Err(json_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_command",
+            format!(
+                "cmd must be a byte in hex with a 0x prefix or in decimal, got '{}'",
+                q.cmd
+            ),
+        ));
+    };
+    let cmd = InfraredCommand::Raw(byte);
+    let address = q.address.unwrap_or(tx.address());
+    Ok(Json(FrameTimeline {
+        frame: frame_hex(cmd, address),
+        durations_us: nec_ticks(cmd.encode(address))
+            .into_iter()
+            .map(|t| t as f64 * NEC_TICK_US)
+            .collect(),
+    }))
+}
@@ -0,0 +1,86 @@
+//! The JSON body of every error response, `{"error": "<code>", "detail":
+//! "<message>"}`. The code is stable for clients to tell errors apart, the
+//! detail is meant for humans.
+
+use poem::{IntoResponse, Response, http::StatusCode, web::Json};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+    pub error: &'static str,
+    pub detail: String,
+}
+
+impl ErrorResponse {
+    pub fn new(code: &'static str, detail: impl Into<String>) -> Self {
+        ErrorResponse {
+            error: code,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// An error response along with its status.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    body: ErrorResponse,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, detail: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            body: ErrorResponse::new(code, detail),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self.body)).into_response()
+    }
+}
+
+impl From<ApiError> for poem::Error {
+    fn from(e: ApiError) -> Self {
+        poem::Error::from_response(e.into_response())
+    }
+}
+
+/// Shorthand for an [`ApiError`] returned from a handler.
+pub fn json_error(
+    status: StatusCode,
+    code: &'static str,
+    detail: impl Into<String>,
+) -> poem::Error {
+    ApiError::new(status, code, detail).into()
+}
+
+/// Gives the errors poem responds with by itself, like for unknown routes or
+/// unparsable parameters, the same body as the ones of the handlers.
+pub async fn json_errors(e: poem::Error) -> Response {
+    let resp = e.into_response();
+    let is_json = resp
+        .content_type()
+        .is_some_and(|ct| ct.starts_with("application/json"));
+    if is_json {
+        return resp;
+    }
+    let status = resp.status();
+    let detail = match resp.into_body().into_string().await {
+        Ok(detail) if !detail.is_empty() => detail,
+        _ => status.canonical_reason().unwrap_or_default().to_owned(),
+    };
+    let code = match status {
+        StatusCode::BAD_REQUEST => "bad_request",
+        StatusCode::UNAUTHORIZED => "unauthorized",
+        StatusCode::NOT_FOUND => "not_found",
+        StatusCode::METHOD_NOT_ALLOWED => "method_not_allowed",
+        StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => "unsupported_media_type",
+        s if s.is_client_error() => "client_error",
+        _ => "internal_error",
+    };
+    ApiError::new(status, code, detail).into_response()
+}
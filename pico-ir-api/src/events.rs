@@ -0,0 +1,167 @@
+//! Live feed of transmitted commands on the `/events` WebSocket, the most
+//! recent of them on `/history`, and the frames picked up by the firmware's
+//! IR receiver for `/learn`.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use futures_util::{SinkExt, StreamExt};
+use poem::{
+    IntoResponse, handler,
+    web::{
+        Data, Json,
+        websocket::{Message, WebSocket},
+    },
+};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, warn};
+
+use crate::{CommandResult, SentFrame, unix_millis};
+
+/// How many events a client may fall behind before it starts missing some.
+const EVENTS_CAPACITY: usize = 64;
+
+/// Sent to every connected client after each command the IR task transmits.
+#[derive(Clone, Debug, Serialize)]
+pub struct CommandEvent {
+    r#type: &'static str,
+    #[serde(flatten)]
+    frame: EventFrame,
+    /// Milliseconds since the Unix epoch.
+    timestamp: u64,
+    success: bool,
+    /// Why the command wasn't transmitted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// What a command transmitted, as described in its event.
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum EventFrame {
+    Nec(SentFrame),
+    /// Mark and space durations, described like the response of `/pulses`.
+    Pulses {
+        pulses: usize,
+        duration_us: u64,
+    },
+    /// A lone NEC repeat code, see [`EventFrame::NEC_REPEAT`].
+    Repeat {
+        repeat: bool,
+    },
+}
+
+impl EventFrame {
+    /// Described as `"repeat": true`.
+    pub const NEC_REPEAT: EventFrame = EventFrame::Repeat { repeat: true };
+}
+
+impl From<SentFrame> for EventFrame {
+    fn from(frame: SentFrame) -> Self {
+        EventFrame::Nec(frame)
+    }
+}
+
+#[derive(Clone)]
+pub struct Events {
+    commands: broadcast::Sender<CommandEvent>,
+    received: broadcast::Sender<u32>,
+    /// The last `history_size` events, oldest first.
+    history: Arc<Mutex<VecDeque<CommandEvent>>>,
+    history_size: usize,
+}
+
+impl Events {
+    /// Keeps the last `history_size` events for `/history`, none when 0.
+    pub fn new(history_size: usize) -> Self {
+        Events {
+            commands: broadcast::channel(EVENTS_CAPACITY).0,
+            received: broadcast::channel(EVENTS_CAPACITY).0,
+            history: Arc::new(Mutex::new(VecDeque::with_capacity(history_size))),
+            history_size,
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<CommandEvent> {
+        self.commands.subscribe()
+    }
+
+    pub fn subscribe_received(&self) -> broadcast::Receiver<u32> {
+        self.received.subscribe()
+    }
+
+    /// Publishes an NEC frame reported by the firmware's IR receiver.
+    pub fn frame_received(&self, frame: u32) {
+        // Fails only when nobody is listening
+        let _ = self.received.send(frame);
+    }
+
+    /// Publishes an event and adds it to the history. Never blocks, clients
+    /// that are too slow to keep up lose the oldest events instead.
+    pub fn command_sent(
+        &self,
+        kind: &'static str,
+        frame: impl Into<EventFrame>,
+        result: &CommandResult,
+    ) {
+        let event = CommandEvent {
+            r#type: kind,
+            frame: frame.into(),
+            timestamp: unix_millis(),
+            success: result.is_ok(),
+            error: result.clone().err(),
+        };
+        if self.history_size > 0 {
+            let mut history = self.history.lock().expect("history lock poisoned");
+            if history.len() == self.history_size {
+                history.pop_front();
+            }
+            history.push_back(event.clone());
+        }
+        // Fails only when nobody is listening
+        let _ = self.commands.send(event);
+    }
+
+    fn history(&self) -> Vec<CommandEvent> {
+        let history = self.history.lock().expect("history lock poisoned");
+        history.iter().cloned().collect()
+    }
+}
+
+/// The most recently transmitted commands, oldest first, in the format of
+/// the `/events`.
+#[handler]
+pub fn get_history(events: Data<&Events>) -> Json<Vec<CommandEvent>> {
+    Json(events.history())
+}
+
+#[handler]
+pub fn get_events(ws: WebSocket, events: Data<&Events>) -> impl IntoResponse {
+    let mut rx = events.subscribe();
+    ws.on_upgrade(async move |socket| {
+        let (mut sink, mut stream) = socket.split();
+        loop {
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Ok(event) => {
+                        let msg = serde_json::to_string(&event).expect("event serializes");
+                        if sink.send(Message::Text(msg)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "Events client is lagging behind")
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                msg = stream.next() => match msg {
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                    // Clients have nothing to say
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+        debug!("Events client disconnected");
+    })
+}
@@ -0,0 +1,164 @@
+//! Replaying the response of POST requests retried with the same
+//! `Idempotency-Key`, so that a retry doesn't transmit the command again.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use poem::{
+    Endpoint, IntoResponse, Middleware, Request, Response, Result,
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+};
+use tokio::{sync::OnceCell, time::Instant};
+use tracing::info;
+
+use crate::json_error;
+
+const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+/// Set on responses replayed for a retried request.
+const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+/// Keys longer than this are refused.
+const MAX_KEY_LEN: usize = 255;
+/// How many keys are remembered at most, the oldest ones are forgotten first.
+const MAX_KEYS: usize = 1024;
+
+/// A successful response, kept to be replayed.
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl CachedResponse {
+    fn response(&self, replayed: bool) -> Response {
+        let mut resp = Response::builder()
+            .status(self.status)
+            .body(self.body.clone());
+        *resp.headers_mut() = self.headers.clone();
+        if replayed {
+            resp.headers_mut()
+                .insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+        }
+        resp
+    }
+}
+
+/// The response to a key, filled in once the first request with it
+/// succeeded. Requests with the same key arriving in the meantime wait for it.
+type Slot = Arc<OnceCell<CachedResponse>>;
+
+/// Responds to a POST request carrying an `Idempotency-Key` header already
+/// seen on a request to the same path within `window` with the response to
+/// that request, without calling the endpoint again. Only successful
+/// responses are replayed, a failed request may be retried.
+pub struct Idempotency {
+    window: Duration,
+}
+
+impl Idempotency {
+    /// A zero `window` disables replaying.
+    pub fn new(window: Duration) -> Self {
+        Idempotency { window }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for Idempotency {
+    type Output = IdempotencyEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        IdempotencyEndpoint {
+            ep,
+            window: self.window,
+            seen: Mutex::default(),
+        }
+    }
+}
+
+pub struct IdempotencyEndpoint<E> {
+    ep: E,
+    window: Duration,
+    /// Keys along with the path they were used on, and when first seen.
+    seen: Mutex<HashMap<(String, String), (Instant, Slot)>>,
+}
+
+impl<E> IdempotencyEndpoint<E> {
+    /// The slot of `key` on `path`, created when the key is new or was seen
+    /// too long ago.
+    fn slot(&self, path: String, key: String) -> Slot {
+        let mut seen = self.seen.lock().expect("idempotency lock poisoned");
+        let now = Instant::now();
+        seen.retain(|_, (at, _)| now.duration_since(*at) < self.window);
+        if let Some((_, slot)) = seen.get(&(path.clone(), key.clone())) {
+            return Arc::clone(slot);
+        }
+        if seen.len() >= MAX_KEYS
+            && let Some(oldest) = seen
+                .iter()
+                .min_by_key(|(_, (at, _))| *at)
+                .map(|(k, _)| k.clone())
+        {
+            seen.remove(&oldest);
+        }
+        let slot = Slot::default();
+        seen.insert((path, key), (now, Arc::clone(&slot)));
+        slot
+    }
+}
+
+impl<E: Endpoint> Endpoint for IdempotencyEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        let key = req.headers().get(&IDEMPOTENCY_KEY);
+        if self.window.is_zero() || req.method() != Method::POST || key.is_none() {
+            return self.ep.call(req).await.map(IntoResponse::into_response);
+        }
+        let key = match key.and_then(|k| k.to_str().ok()) {
+            Some(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => key.to_owned(),
+            _ => {
+                return Err(json_error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_idempotency_key",
+                    format!("Idempotency-Key must be 1 to {MAX_KEY_LEN} visible ASCII characters"),
+                ));
+            }
+        };
+        let slot = self.slot(req.uri().path().to_owned(), key.clone());
+        // Unless a request with the key already succeeded, this is either the
+        // first one, or a retry of one still in progress, which is waited
+        // for. A failed request leaves the slot empty, and the next request
+        // with the key is let through.
+        let mut first = false;
+        let mut failed = None;
+        let cached = slot
+            .get_or_try_init(|| async {
+                first = true;
+                let resp = match self.ep.call(req).await {
+                    Ok(resp) => resp.into_response(),
+                    Err(e) => e.into_response(),
+                };
+                if !resp.status().is_success() {
+                    failed = Some(resp);
+                    return Err(());
+                }
+                let (parts, body) = resp.into_parts();
+                Ok(CachedResponse {
+                    status: parts.status,
+                    headers: parts.headers,
+                    body: body.into_vec().await.map_err(|_| ())?,
+                })
+            })
+            .await;
+        match cached {
+            Ok(cached) => {
+                if !first {
+                    info!(key, "Replaying the response to a retried request");
+                }
+                Ok(cached.response(!first))
+            }
+            Err(()) => {
+                Ok(failed.unwrap_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_response()))
+            }
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! The connection to the firmware that the IR task sends its frames over.
+
+use anyhow::Context;
+use backon::{ExponentialBuilder, Retryable};
+use pico_ir_proto::{FirmwareInfo, wire};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::{self, Duration},
+};
+use tokio_serial::SerialStream;
+use tracing::{debug, error, info, warn};
+
+use crate::{SerialState, events::Events, metrics::Metrics, mqtt::MqttLink};
+
+const ACK_TIMEOUT: Duration = Duration::from_secs(1);
+/// How many times the serial port is reopened to write a single frame before
+/// the frame is given up on.
+const MAX_REOPENS_PER_FRAME: u32 = 3;
+
+/// Why the firmware didn't take a frame.
+#[derive(Debug)]
+pub enum TransmitError {
+    /// The firmware responded with an error, so the frame was not
+    /// transmitted and can safely be sent again.
+    Rejected(String),
+    /// No acknowledgement arrived, the frame may have been transmitted or not.
+    Unacknowledged(String),
+    /// The frame couldn't be written to the serial port even after reopening
+    /// it, so the firmware never saw it.
+    Unwritten(String),
+    /// The link can't carry the frame at all, so sending it again is no use.
+    Unsupported(String),
+}
+
+impl std::fmt::Display for TransmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransmitError::Rejected(ack) => write!(f, "firmware rejected the command: {ack}"),
+            TransmitError::Unacknowledged(reason) => f.write_str(reason),
+            TransmitError::Unwritten(e) => write!(f, "failed to write to the serial port: {e}"),
+            TransmitError::Unsupported(reason) => f.write_str(reason),
+        }
+    }
+}
+
+/// What the IR task needs from the firmware. Implemented by [`SerialLink`],
+/// by [`MqttLink`] for a firmware attached to another host, and by a mock
+/// recording the frames in the tests.
+pub trait IrLink {
+    /// Sends `frame`, a command framed as by [`wire`], and waits for the
+    /// firmware to acknowledge it. An error means the link is gone for good,
+    /// while a frame the firmware didn't take is reported in the inner result.
+    async fn transmit(
+        &mut self,
+        kind: &'static str,
+        frame: &[u8],
+    ) -> anyhow::Result<Result<(), TransmitError>>;
+
+    /// Checks that the firmware is still there and responding.
+    async fn ping(&mut self) -> anyhow::Result<()>;
+
+    /// Reads a line written by the firmware between commands. Nothing may be
+    /// lost when the future is dropped before it completes.
+    async fn read_line(&mut self) -> std::io::Result<String>;
+
+    /// Connects again after the link failed.
+    async fn reopen(&mut self) -> anyhow::Result<()>;
+}
+
+async fn open_serial(
+    path: &str,
+    baud: u32,
+    backoff: ExponentialBuilder,
+) -> anyhow::Result<SerialStream> {
+    let s = (async || -> anyhow::Result<SerialStream> {
+        let path = path.to_owned();
+        tokio::task::spawn_blocking(move || {
+            Ok(tokio_serial::SerialStream::open(&tokio_serial::new(
+                path, baud,
+            ))?)
+        })
+        .await?
+    })
+    .retry(backoff)
+    .notify(|e, d| warn!(error = %e, retry_in_s = d.as_secs(), "Failed to open serial, retrying"))
+    .await
+    .context("Could not open serial port")?;
+    Ok(s)
+}
+
+/// The open serial port, along with the part of a line read from it so far.
+pub struct SerialLink {
+    path: String,
+    baud: u32,
+    backoff: ExponentialBuilder,
+    stream: SerialStream,
+    line: Vec<u8>,
+    /// Whether the firmware takes length-prefixed commands, see
+    /// [`wire::framed`].
+    framed: bool,
+    state: SerialState,
+    metrics: Metrics,
+    /// Where frames reported by the firmware's IR receiver go.
+    events: Events,
+}
+
+impl SerialLink {
+    /// Opens the serial port, retrying with `backoff`, and asks the firmware
+    /// to describe itself, marking `state` connected once done.
+    pub async fn open(
+        path: String,
+        baud: u32,
+        backoff: ExponentialBuilder,
+        state: SerialState,
+        metrics: Metrics,
+        events: Events,
+    ) -> anyhow::Result<Self> {
+        let mut link = SerialLink {
+            stream: open_serial(&path, baud, backoff).await?,
+            path,
+            baud,
+            backoff,
+            line: Vec::new(),
+            framed: false,
+            state,
+            metrics,
+            events,
+        };
+        link.handshake().await;
+        Ok(link)
+    }
+
+    async fn handshake(&mut self) {
+        let info = match self.query_info().await {
+            Ok(info) => {
+                info!(
+                    version = info.version.as_deref().unwrap_or("unknown"),
+                    protocols = info.protocols.join(","),
+                    "Connected to firmware"
+                );
+                Some(info)
+            }
+            Err(e) => {
+                warn!(error = format!("{e:#}"), "Firmware did not describe itself");
+                None
+            }
+        };
+        self.framed = info.as_ref().is_some_and(|info| info.supports("framed"));
+        self.state.set_firmware(info);
+        self.state.set_connected(true);
+    }
+
+    async fn query_info(&mut self) -> anyhow::Result<FirmwareInfo> {
+        self.stream.write_all(wire::INFO).await?;
+        let response = time::timeout(ACK_TIMEOUT, self.read_ack())
+            .await
+            .context("Timed out waiting for response")??;
+        FirmwareInfo::parse(&response).with_context(|| format!("Unexpected response {response:?}"))
+    }
+
+    /// Reads a single response line written by the firmware after each
+    /// command. Frames reported by the firmware's IR receiver in the meantime
+    /// are passed on to `events`.
+    async fn read_ack(&mut self) -> anyhow::Result<String> {
+        loop {
+            let line = self.read_line().await?;
+            match line.strip_prefix("RX ") {
+                Some(frame) => frame_received(frame, &self.events),
+                None => return Ok(line),
+            }
+        }
+    }
+}
+
+impl IrLink for SerialLink {
+    /// Writes a frame to the firmware, reopening the serial port and writing
+    /// it again if that fails, and returns whether the firmware acknowledged
+    /// it. A port that keeps failing is only reopened a few times per frame.
+    /// The frame is length-prefixed when the firmware takes that, unless it's
+    /// too long to be.
+    async fn transmit(
+        &mut self,
+        kind: &'static str,
+        frame: &[u8],
+    ) -> anyhow::Result<Result<(), TransmitError>> {
+        debug!(command = kind, len = frame.len(), "Sending command");
+        let mut reopens = 0;
+        loop {
+            let framed = self.framed.then(|| wire::framed(frame)).flatten();
+            let started = time::Instant::now();
+            let written = self
+                .stream
+                .write_all(framed.as_deref().unwrap_or(frame))
+                .await;
+            self.metrics.serial_written(started.elapsed());
+            let Err(e) = written else { break };
+            if reopens == MAX_REOPENS_PER_FRAME {
+                error!(command = kind, success = false, error = ?e, "Failed to write to serial, giving up on command");
+                return Ok(Err(TransmitError::Unwritten(e.to_string())));
+            }
+            reopens += 1;
+            error!(error = ?e, reopens, "Failed to write to serial, reopening");
+            self.reopen().await?;
+            debug!(command = kind, "Sending command again after reopening");
+        }
+        Ok(match time::timeout(ACK_TIMEOUT, self.read_ack()).await {
+            Ok(Ok(ack)) if ack == "OK" => {
+                debug!(
+                    command = kind,
+                    success = true,
+                    "Firmware acknowledged command"
+                );
+                Ok(())
+            }
+            Ok(Ok(ack)) => {
+                error!(
+                    command = kind,
+                    success = false,
+                    ack,
+                    "Firmware rejected command"
+                );
+                Err(TransmitError::Rejected(ack))
+            }
+            Ok(Err(e)) => {
+                warn!(command = kind, success = false, error = ?e, "Failed to read acknowledgement");
+                Err(TransmitError::Unacknowledged(format!(
+                    "failed to read the acknowledgement: {e:#}"
+                )))
+            }
+            Err(_) => {
+                warn!(
+                    command = kind,
+                    success = false,
+                    "Timed out waiting for acknowledgement"
+                );
+                Err(TransmitError::Unacknowledged(
+                    "timed out waiting for the acknowledgement".into(),
+                ))
+            }
+        })
+    }
+
+    async fn ping(&mut self) -> anyhow::Result<()> {
+        self.stream.write_all(&wire::PING).await?;
+        let ack = time::timeout(ACK_TIMEOUT, self.read_ack())
+            .await
+            .context("Timed out waiting for acknowledgement")??;
+        anyhow::ensure!(ack == "OK", "Unexpected response {ack:?}");
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> std::io::Result<String> {
+        loop {
+            match self.stream.read_u8().await? {
+                b'\n' => {
+                    let line = String::from_utf8_lossy(&self.line).into_owned();
+                    self.line.clear();
+                    return Ok(line);
+                }
+                b => self.line.push(b),
+            }
+        }
+    }
+
+    async fn reopen(&mut self) -> anyhow::Result<()> {
+        self.state.set_connected(false);
+        self.stream = open_serial(&self.path, self.baud, self.backoff).await?;
+        self.line.clear();
+        // The firmware may have been replaced in the meantime
+        self.handshake().await;
+        self.metrics.serial_reopened();
+        Ok(())
+    }
+}
+
+/// The link of a device, whichever way its firmware is reached.
+pub enum DeviceLink {
+    Serial(Box<SerialLink>),
+    Mqtt(MqttLink),
+}
+
+impl IrLink for DeviceLink {
+    async fn transmit(
+        &mut self,
+        kind: &'static str,
+        frame: &[u8],
+    ) -> anyhow::Result<Result<(), TransmitError>> {
+        match self {
+            DeviceLink::Serial(link) => link.transmit(kind, frame).await,
+            DeviceLink::Mqtt(link) => link.transmit(kind, frame).await,
+        }
+    }
+
+    async fn ping(&mut self) -> anyhow::Result<()> {
+        match self {
+            DeviceLink::Serial(link) => link.ping().await,
+            DeviceLink::Mqtt(link) => link.ping().await,
+        }
+    }
+
+    async fn read_line(&mut self) -> std::io::Result<String> {
+        match self {
+            DeviceLink::Serial(link) => link.read_line().await,
+            DeviceLink::Mqtt(link) => link.read_line().await,
+        }
+    }
+
+    async fn reopen(&mut self) -> anyhow::Result<()> {
+        match self {
+            DeviceLink::Serial(link) => link.reopen().await,
+            DeviceLink::Mqtt(link) => link.reopen().await,
+        }
+    }
+}
+
+/// Handles a `RX <hexword>` line of the firmware's IR receiver.
+pub fn frame_received(frame: &str, events: &Events) {
+    match u32::from_str_radix(frame, 16) {
+        Ok(v) => {
+            info!(frame, "Firmware received IR frame");
+            events.frame_received(v);
+        }
+        Err(_) => warn!(frame, "Firmware reported an invalid IR frame"),
+    }
+}
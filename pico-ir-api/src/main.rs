@@ -1,216 +1,3393 @@
-use std::time::Duration;
+mod auth;
+mod config;
+mod debug;
+mod error;
+mod events;
+mod idempotency;
+mod link;
+mod metrics;
+mod mqtt;
+#[cfg(feature = "openapi")]
+mod openapi;
+
+use std::collections::{BTreeMap, HashMap};
+use std::os::unix::{
+    fs::{FileTypeExt, PermissionsExt},
+    net::{UnixListener, UnixStream},
+};
+use std::path::PathBuf;
+use std::sync::{
+    Arc, Mutex, RwLock,
+    atomic::{AtomicBool, Ordering},
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
-use backon::{ExponentialBuilder, Retryable};
+use auth::BearerAuth;
+use bpaf::Bpaf;
+use config::{Config, DeviceConfig, InputAfterPower, LogFormat};
+use error::{ErrorResponse, json_error, json_errors};
+use events::{EventFrame, Events};
+use idempotency::Idempotency;
+use link::{DeviceLink, IrLink, SerialLink, TransmitError, frame_received};
 use listenfd::ListenFd;
+use metrics::Metrics;
+use mqtt::{MqttLink, MqttTarget};
+use pico_ir_proto::{AudioInput, FirmwareInfo, InfraredCommand, NecAddress, codes, wire};
 use poem::{
-    EndpointExt, Route, Server, handler,
-    http::StatusCode,
-    listener::{DynAcceptor, Listener, TcpListener, ToDynAcceptor, UnixAcceptor},
-    web::{Data, Query},
+    Endpoint, EndpointExt, IntoResponse, Response, Route, Server, handler,
+    http::{StatusCode, header},
+    listener::{Acceptor, DynAcceptor, Listener, TcpListener, ToDynAcceptor, UnixAcceptor},
+    web::{Data, Json, Path, Query},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    broadcast,
+    mpsc::{self, Receiver, Sender, error::SendTimeoutError},
+    oneshot, watch,
+};
+use tokio::{
+    signal::{self, unix::SignalKind},
+    task::JoinSet,
+    time,
 };
-use serde::Deserialize;
-use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio::{io::AsyncWriteExt, time};
-use tokio_serial::SerialStream;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, warn};
+use tracing::{Instrument, debug, error, info, info_span, warn};
+
+/// The encoding of a command as sent to the firmware, returned by the command
+/// handlers to make it easy to check what was actually transmitted.
+#[derive(Clone, Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(poem_openapi::Object))]
+struct SentFrame {
+    scancode: String,
+    frame: String,
+}
+
+impl SentFrame {
+    fn new(cmd: InfraredCommand, address: NecAddress) -> Self {
+        SentFrame {
+            scancode: format!("{:#04x}", cmd.as_u8()),
+            frame: frame_hex(cmd, address),
+        }
+    }
+}
+
+/// Queues `cmd` for the device at `address`, or at the configured address
+/// when not given.
+async fn send_direct(
+    tx: &CommandSender,
+    cmd: InfraredCommand,
+    address: Option<NecAddress>,
+    wait: bool,
+) -> poem::Result<SentFrame> {
+    let address = address.unwrap_or(tx.address());
+    tx.submit(UserCommand::Direct(cmd, address), wait).await?;
+    Ok(SentFrame::new(cmd, address))
+}
+
+/// Upper bound of the `repeat` parameter.
+const MAX_REPEAT: u8 = 20;
+/// Wait between repeated frames when none is given.
+const DEFAULT_REPEAT_GAP: Duration = Duration::from_millis(150);
+/// Upper bound of the `gap_ms` parameters, between repeated frames and the
+/// toggles of a power-on hack, as they hold up the queue.
+const MAX_GAP: Duration = Duration::from_secs(10);
+
+/// Why `gap_ms` is out of bounds, if it is.
+fn invalid_gap(gap_ms: Option<u64>) -> Option<String> {
+    gap_ms
+        .is_some_and(|ms| Duration::from_millis(ms) > MAX_GAP)
+        .then(|| format!("gap_ms must be at most {}", MAX_GAP.as_millis()))
+}
+
+/// Like [`send_direct`], but has the IR task transmit the command `repeat`
+/// times, `gap_ms` apart.
+async fn send_repeated(
+    tx: &CommandSender,
+    cmd: InfraredCommand,
+    params: &RepeatParams,
+    wait: bool,
+) -> poem::Result<SentFrame> {
+    let count = match params.repeat {
+        None | Some(1) => return send_direct(tx, cmd, params.address, wait).await,
+        Some(count @ 2..=MAX_REPEAT) => count,
+        Some(_) => {
+            return Err(json_error(
+                StatusCode::BAD_REQUEST,
+                "invalid_repeat",
+                format!("repeat must be between 1 and {MAX_REPEAT}"),
+            ));
+        }
+    };
+    if let Some(detail) = invalid_gap(params.gap_ms) {
+        return Err(json_error(StatusCode::BAD_REQUEST, "invalid_gap", detail));
+    }
+    let address = params.address.unwrap_or(tx.address());
+    let command = UserCommand::Repeat {
+        cmd,
+        address,
+        count,
+        gap: params
+            .gap_ms
+            .map_or(DEFAULT_REPEAT_GAP, Duration::from_millis),
+    };
+    tx.submit(command, wait).await?;
+    Ok(SentFrame::new(cmd, address))
+}
+
+#[derive(Debug, Deserialize)]
+struct RepeatParams {
+    address: Option<NecAddress>,
+    repeat: Option<u8>,
+    gap_ms: Option<u64>,
+}
+
+/// Makes a command handler respond only once the command was transmitted,
+/// with an error when that failed, instead of as soon as it is queued.
+#[derive(Debug, Deserialize)]
+struct WaitParams {
+    #[serde(default)]
+    wait: bool,
+}
+
+/// Lets a command target another NEC device than the configured one.
+#[derive(Debug, Deserialize)]
+struct AddressParams {
+    address: Option<NecAddress>,
+}
 
 #[handler]
-async fn post_toggle_power(tx: Data<&CommandSender>) -> poem::Result<()> {
-    tx.send(UserCommand::Direct(InfraredCommand::TogglePower))
+async fn post_toggle_power(
+    tx: Data<&CommandSender>,
+    q: Query<AddressParams>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<SentFrame>> {
+    send_direct(&tx, InfraredCommand::TogglePower, q.address, w.wait)
         .await
-        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
-    Ok(())
+        .map(Json)
+}
+
+#[derive(Debug, Deserialize)]
+struct PowerOnHackParams {
+    gap_ms: Option<u64>,
+    address: Option<NecAddress>,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(poem_openapi::Object))]
+struct PowerOnHackResponse {
+    frames: [SentFrame; 2],
+    /// Nothing was sent, because a power-on hack finished moments ago.
+    skipped: bool,
+}
+
+async fn send_power_on_hack(
+    tx: &CommandSender,
+    gap_ms: Option<u64>,
+    address: Option<NecAddress>,
+    wait: bool,
+) -> poem::Result<PowerOnHackResponse> {
+    let Some(claim) = tx.power_on.claim() else {
+        return Err(json_error(
+            StatusCode::CONFLICT,
+            "power_on_in_progress",
+            "a power-on hack is already in progress",
+        ));
+    };
+    if let Some(detail) = invalid_gap(gap_ms) {
+        return Err(json_error(StatusCode::BAD_REQUEST, "invalid_gap", detail));
+    }
+    let address = address.unwrap_or(tx.address());
+    let skipped = tx
+        .power_on
+        .finished_within(tx.settings.get(|s| s.power_on_debounce));
+    if skipped {
+        info!("Power-on hack finished moments ago, not sending another");
+    } else {
+        tx.submit(tx.power_on_hack(gap_ms, address, Some(claim)), wait)
+            .await?;
+    }
+    let frame = SentFrame::new(InfraredCommand::TogglePower, address);
+    Ok(PowerOnHackResponse {
+        frames: [frame.clone(), frame],
+        skipped,
+    })
 }
 
 #[handler]
-async fn post_power_on_hack(tx: Data<&CommandSender>) -> poem::Result<()> {
-    tx.send(UserCommand::PowerOnHack)
+async fn post_power_on_hack(
+    tx: Data<&CommandSender>,
+    q: Query<PowerOnHackParams>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<PowerOnHackResponse>> {
+    send_power_on_hack(&tx, q.gap_ms, q.address, w.wait)
         .await
-        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
-    Ok(())
+        .map(Json)
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(poem_openapi::Object))]
+struct ResetResponse {
+    frames: [SentFrame; 3],
+}
+
+/// Turns the device off and on again whatever state it's in: toggles the
+/// power, waits out the time the device ignores toggles after turning on, and
+/// runs a power-on hack. The device is off for a few seconds, or turned on
+/// and off first if it was off.
+async fn send_reset(
+    tx: &CommandSender,
+    address: Option<NecAddress>,
+    wait: bool,
+) -> poem::Result<ResetResponse> {
+    let Some(claim) = tx.power_on.claim() else {
+        return Err(json_error(
+            StatusCode::CONFLICT,
+            "power_on_in_progress",
+            "a power-on hack is already in progress",
+        ));
+    };
+    let address = address.unwrap_or(tx.address());
+    tx.submit(tx.reset(address, claim), wait).await?;
+    let frame = SentFrame::new(InfraredCommand::TogglePower, address);
+    Ok(ResetResponse {
+        frames: [frame.clone(), frame.clone(), frame],
+    })
+}
+
+#[handler]
+async fn post_reset(
+    tx: Data<&CommandSender>,
+    q: Query<AddressParams>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<ResetResponse>> {
+    send_reset(&tx, q.address, w.wait).await.map(Json)
+}
+
+#[handler]
+async fn post_volume_up(
+    tx: Data<&CommandSender>,
+    q: Query<RepeatParams>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<SentFrame>> {
+    send_repeated(&tx, InfraredCommand::VolumeUp, &q, w.wait)
+        .await
+        .map(Json)
+}
+
+#[handler]
+async fn post_volume_down(
+    tx: Data<&CommandSender>,
+    q: Query<RepeatParams>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<SentFrame>> {
+    send_repeated(&tx, InfraredCommand::VolumeDown, &q, w.wait)
+        .await
+        .map(Json)
+}
+
+#[handler]
+async fn post_mute(
+    tx: Data<&CommandSender>,
+    q: Query<AddressParams>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<SentFrame>> {
+    send_direct(&tx, InfraredCommand::Mute, q.address, w.wait)
+        .await
+        .map(Json)
 }
 
 #[derive(Debug, Deserialize)]
 struct SetInputParams {
     input: AudioInput,
+    address: Option<NecAddress>,
 }
 
 #[handler]
-async fn post_set_input(tx: Data<&CommandSender>, q: Query<SetInputParams>) -> poem::Result<()> {
-    tx.send(UserCommand::Direct(InfraredCommand::SetInput(q.input)))
+async fn post_set_input(
+    tx: Data<&CommandSender>,
+    q: Query<SetInputParams>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<SentFrame>> {
+    send_direct(&tx, InfraredCommand::SetInput(q.input), q.address, w.wait)
         .await
-        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
-    Ok(())
+        .map(Json)
+}
+
+/// Same as `/set-input`, with the input in the path.
+#[handler]
+async fn post_input(
+    tx: Data<&CommandSender>,
+    Path(name): Path<String>,
+    q: Query<AddressParams>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<SentFrame>> {
+    let Some(input) = AudioInput::from_name(&name) else {
+        return Err(json_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_input",
+            format!("unknown input '{name}', expected {}", AudioInput::EXPECTED),
+        ));
+    };
+    send_direct(&tx, InfraredCommand::SetInput(input), q.address, w.wait)
+        .await
+        .map(Json)
+}
+
+/// The input after, or before, the last known one, emulating the source
+/// button of the remote. The first input when none is known.
+fn cycled_input(status: &watch::Receiver<DeviceStatus>, forward: bool) -> AudioInput {
+    match status.borrow().last_input {
+        Some(input) if forward => input.next(),
+        Some(input) => input.prev(),
+        None => AudioInput::ALL[0],
+    }
+}
+
+/// The other one of the configured pair of inputs, or the first one when the
+/// last known input is neither.
+fn toggled_input(tx: &CommandSender, status: &watch::Receiver<DeviceStatus>) -> AudioInput {
+    let [first, second] = tx.settings.get(|s| s.toggle_inputs);
+    if status.borrow().last_input == Some(first) {
+        second
+    } else {
+        first
+    }
+}
+
+/// Selects the input after, or before, the last known one.
+async fn send_cycled_input(
+    tx: &CommandSender,
+    status: &watch::Receiver<DeviceStatus>,
+    forward: bool,
+    address: Option<NecAddress>,
+    wait: bool,
+) -> poem::Result<SentFrame> {
+    let input = cycled_input(status, forward);
+    send_direct(tx, InfraredCommand::SetInput(input), address, wait).await
+}
+
+/// Selects the other one of the configured pair of inputs.
+async fn send_toggled_input(
+    tx: &CommandSender,
+    status: &watch::Receiver<DeviceStatus>,
+    address: Option<NecAddress>,
+    wait: bool,
+) -> poem::Result<SentFrame> {
+    let input = toggled_input(tx, status);
+    send_direct(tx, InfraredCommand::SetInput(input), address, wait).await
+}
+
+/// The input a cycling or toggling handler would select right now, and the
+/// frame it would send for it, so that clients can show it beforehand.
+#[derive(Clone, Debug, Serialize)]
+struct InputPreview {
+    input: AudioInput,
+    #[serde(flatten)]
+    frame: SentFrame,
+}
+
+impl InputPreview {
+    fn new(tx: &CommandSender, input: AudioInput, address: Option<NecAddress>) -> Self {
+        let address = address.unwrap_or(tx.address());
+        InputPreview {
+            input,
+            frame: SentFrame::new(InfraredCommand::SetInput(input), address),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(poem_openapi::Object))]
+struct PowerResponse {
+    /// The toggle sent, none when the device already was in the requested
+    /// state.
+    frame: Option<SentFrame>,
+}
+
+/// Turns the device on or off with a single toggle, or does nothing when it
+/// already is. Only works while the power state is known, the power-on hack
+/// is the way out when it isn't.
+async fn send_power(
+    tx: &CommandSender,
+    status: &watch::Receiver<DeviceStatus>,
+    on: bool,
+    address: Option<NecAddress>,
+    wait: bool,
+) -> poem::Result<PowerResponse> {
+    let power = status.borrow().power;
+    match power {
+        Some(power) if power == on => Ok(PowerResponse { frame: None }),
+        Some(_) => {
+            let frame = send_direct(tx, InfraredCommand::TogglePower, address, wait).await?;
+            Ok(PowerResponse { frame: Some(frame) })
+        }
+        None => Err(json_error(
+            StatusCode::CONFLICT,
+            "power_state_unknown",
+            "power state is unknown, send a power-on hack first",
+        )),
+    }
+}
+
+#[handler]
+async fn post_power_on(
+    tx: Data<&CommandSender>,
+    status: Data<&watch::Receiver<DeviceStatus>>,
+    q: Query<AddressParams>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<PowerResponse>> {
+    send_power(&tx, &status, true, q.address, w.wait)
+        .await
+        .map(Json)
+}
+
+#[handler]
+async fn post_power_off(
+    tx: Data<&CommandSender>,
+    status: Data<&watch::Receiver<DeviceStatus>>,
+    q: Query<AddressParams>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<PowerResponse>> {
+    send_power(&tx, &status, false, q.address, w.wait)
+        .await
+        .map(Json)
+}
+
+#[handler]
+async fn post_input_next(
+    tx: Data<&CommandSender>,
+    status: Data<&watch::Receiver<DeviceStatus>>,
+    q: Query<AddressParams>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<SentFrame>> {
+    send_cycled_input(&tx, &status, true, q.address, w.wait)
+        .await
+        .map(Json)
+}
+
+#[handler]
+async fn post_input_prev(
+    tx: Data<&CommandSender>,
+    status: Data<&watch::Receiver<DeviceStatus>>,
+    q: Query<AddressParams>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<SentFrame>> {
+    send_cycled_input(&tx, &status, false, q.address, w.wait)
+        .await
+        .map(Json)
+}
+
+#[handler]
+async fn post_input_toggle(
+    tx: Data<&CommandSender>,
+    status: Data<&watch::Receiver<DeviceStatus>>,
+    q: Query<AddressParams>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<SentFrame>> {
+    send_toggled_input(&tx, &status, q.address, w.wait)
+        .await
+        .map(Json)
+}
+
+#[handler]
+async fn get_input_next_preview(
+    tx: Data<&CommandSender>,
+    status: Data<&watch::Receiver<DeviceStatus>>,
+    q: Query<AddressParams>,
+) -> Json<InputPreview> {
+    Json(InputPreview::new(
+        &tx,
+        cycled_input(&status, true),
+        q.address,
+    ))
+}
+
+#[handler]
+async fn get_input_prev_preview(
+    tx: Data<&CommandSender>,
+    status: Data<&watch::Receiver<DeviceStatus>>,
+    q: Query<AddressParams>,
+) -> Json<InputPreview> {
+    Json(InputPreview::new(
+        &tx,
+        cycled_input(&status, false),
+        q.address,
+    ))
+}
+
+#[handler]
+async fn get_input_toggle_preview(
+    tx: Data<&CommandSender>,
+    status: Data<&watch::Receiver<DeviceStatus>>,
+    q: Query<AddressParams>,
+) -> Json<InputPreview> {
+    Json(InputPreview::new(
+        &tx,
+        toggled_input(&tx, &status),
+        q.address,
+    ))
+}
+
+#[handler]
+async fn get_inputs() -> Json<Vec<&'static str>> {
+    Json(AudioInput::ALL.iter().map(AudioInput::as_str).collect())
 }
 
 #[derive(Debug, Deserialize)]
 struct RawCommandParams {
     cmd: u8,
+    address: Option<NecAddress>,
+    repeat: Option<u8>,
+    gap_ms: Option<u64>,
+}
+
+fn raw_not_permitted(cmd: u8) -> poem::Error {
+    json_error(
+        StatusCode::BAD_REQUEST,
+        "raw_not_permitted",
+        format!("raw command {cmd:#04x} is not permitted"),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct RawFrameParams {
+    frame: String,
+    repeat: Option<u8>,
+    gap_ms: Option<u64>,
+}
+
+/// Queues `frame`, up to eight hex digits, to be sent exactly as given. Raw
+/// frames can carry any command byte, so they are only permitted when raw
+/// commands aren't restricted at all.
+async fn send_raw_frame(
+    tx: &CommandSender,
+    frame: &str,
+    repeat: Option<u8>,
+    gap_ms: Option<u64>,
+    wait: bool,
+) -> poem::Result<SentFrame> {
+    let digits = frame.strip_prefix("0x").unwrap_or(frame);
+    if digits.is_empty() || digits.len() > 8 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(json_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_frame",
+            format!("frame must be up to eight hex digits, got '{frame}'"),
+        ));
+    }
+    if tx.settings.get(|s| !matches!(s.raw_filter, RawFilter::Any)) {
+        return Err(json_error(
+            StatusCode::BAD_REQUEST,
+            "raw_not_permitted",
+            "raw frames are not permitted while raw commands are restricted",
+        ));
+    }
+    let frame = u32::from_str_radix(digits, 16).expect("validated hex digits");
+    let repeat = RepeatParams {
+        address: None,
+        repeat,
+        gap_ms,
+    };
+    send_repeated(tx, InfraredCommand::RawFrame(frame), &repeat, wait).await
 }
 
 #[handler]
-async fn post_raw_command(
+async fn post_raw_frame(
     tx: Data<&CommandSender>,
-    q: Query<RawCommandParams>,
-) -> poem::Result<()> {
-    tx.send(UserCommand::Direct(InfraredCommand::Raw(q.cmd)))
+    q: Query<RawFrameParams>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<SentFrame>> {
+    send_raw_frame(&tx, &q.frame, q.repeat, q.gap_ms, w.wait)
         .await
-        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
-    Ok(())
+        .map(Json)
 }
 
-async fn make_acceptor() -> anyhow::Result<Box<dyn DynAcceptor>> {
-    let mut listenfd = ListenFd::from_env();
-    Ok(match listenfd.take_unix_listener(0)? {
-        Some(listener) => {
-            listener.set_nonblocking(true)?;
-            Box::new(ToDynAcceptor(UnixAcceptor::from_std(listener)?))
-        }
-        None => {
-            warn!("Did not receive Unix socket, falling back to TCP.");
-            Box::new(ToDynAcceptor(
-                TcpListener::bind("127.0.0.1:9912").into_acceptor().await?,
-            ))
-        }
-    })
+/// Whether the connected firmware is known not to support `protocol`. Unknown
+/// without a connection, in which case the firmware connected by the time the
+/// command is sent may well support it.
+fn firmware_lacks(state: &SerialState, protocol: &str) -> bool {
+    state
+        .firmware()
+        .is_some_and(|info| !info.supports(protocol))
 }
 
-enum UserCommand {
-    /// Directly transmit an infrared command
-    Direct(InfraredCommand),
+/// Longest a button stays held, in case the client never releases it.
+const MAX_HOLD: Duration = Duration::from_secs(30);
 
-    /// The Power button is a toggle, so unless we know the current state,
-    /// we cannot reliably turn the device On.
-    /// However, the device ignores a repeated power-toggle command within
-    /// a few seconds of turning on, whereas this window is shorter when turning
-    /// off. So sending a second power-toggle command in this gap causes the
-    /// device to eventually reach the On state, with the downside of a few
-    /// seconds delay if it was already on.
-    PowerOnHack,
+#[derive(Debug, Deserialize)]
+struct HoldParams {
+    /// The command byte, in hex with a `0x` prefix or in decimal.
+    cmd: String,
+    address: Option<NecAddress>,
 }
 
-enum InfraredCommand {
-    TogglePower,
-    SetInput(AudioInput),
-    Raw(u8),
+/// Parses a command byte given in hex with a `0x` prefix or in decimal.
+fn parse_command_byte(cmd: &str) -> Option<u8> {
+    match cmd.strip_prefix("0x") {
+        Some(hex) if hex.bytes().all(|b| b.is_ascii_hexdigit()) => u8::from_str_radix(hex, 16).ok(),
+        Some(_) => None,
+        None if cmd.bytes().all(|b| b.is_ascii_digit()) => cmd.parse().ok(),
+        None => None,
+    }
 }
 
-#[derive(Clone, Copy, Debug, Deserialize)]
-#[serde(rename_all = "lowercase")]
-enum AudioInput {
-    Bluetooth,
-    #[serde(rename = "3.5mm")]
-    _3_5mm,
-    Optical,
-    Rca,
+/// Queues the frame of `cmd`, followed by NEC repeat codes as if its button
+/// were held, until [`stop_hold`] or [`MAX_HOLD`]. Raw command bytes are
+/// restricted as usual, and only one button is held at a time.
+async fn start_hold(
+    tx: &CommandSender,
+    state: &SerialState,
+    cmd: &str,
+    address: Option<NecAddress>,
+) -> poem::Result<SentFrame> {
+    let Some(byte) = parse_command_byte(cmd) else {
+        return Err(json_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_command",
+            format!("cmd must be a byte in hex with a 0x prefix or in decimal, got '{cmd}'"),
+        ));
+    };
+    if !tx.permits_raw(byte) {
+        return Err(raw_not_permitted(byte));
+    }
+    if firmware_lacks(state, "nec-repeat") {
+        return Err(json_error(
+            StatusCode::NOT_IMPLEMENTED,
+            "unsupported_by_firmware",
+            "the firmware does not support holding buttons",
+        ));
+    }
+    let Some(release) = tx.hold.start() else {
+        return Err(json_error(
+            StatusCode::CONFLICT,
+            "hold_in_progress",
+            "a button is already held, stop holding it first",
+        ));
+    };
+    let cmd = InfraredCommand::Raw(byte);
+    let address = address.unwrap_or(tx.address());
+    let command = UserCommand::Hold {
+        cmd,
+        address,
+        release: release.clone(),
+    };
+    if let Err(e) = tx.submit(command, false).await {
+        release.cancel();
+        return Err(e);
+    }
+    Ok(SentFrame::new(cmd, address))
 }
 
-impl InfraredCommand {
-    pub fn as_u8(&self) -> u8 {
-        match self {
-            InfraredCommand::TogglePower => 0x66,
-            InfraredCommand::SetInput(AudioInput::Bluetooth) => 0x86,
-            InfraredCommand::SetInput(AudioInput::_3_5mm) => 0x97,
-            InfraredCommand::SetInput(AudioInput::Optical) => 0x88,
-            InfraredCommand::SetInput(AudioInput::Rca) => 0x96,
-            InfraredCommand::Raw(b) => *b,
-        }
+#[handler]
+async fn post_hold_start(
+    tx: Data<&CommandSender>,
+    state: Data<&SerialState>,
+    q: Query<HoldParams>,
+) -> poem::Result<Json<SentFrame>> {
+    start_hold(&tx, &state, &q.cmd, q.address).await.map(Json)
+}
+
+/// What `/hold/stop` did.
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(poem_openapi::Object))]
+struct HoldStopResponse {
+    /// Whether a button was held.
+    released: bool,
+}
+
+/// Releases the button held by [`start_hold`]. When the hold is still queued,
+/// only its first frame is sent, like a short press.
+fn stop_hold(tx: &CommandSender) -> HoldStopResponse {
+    HoldStopResponse {
+        released: tx.hold.stop(),
     }
+}
+
+#[handler]
+async fn post_hold_stop(tx: Data<&CommandSender>) -> Json<HoldStopResponse> {
+    Json(stop_hold(&tx))
+}
 
-    pub fn as_u32_le(&self) -> u32 {
-        const ADDRESS: u32 = 0x2385;
-        (self.as_u8() as u32) << 24 | (!self.as_u8() as u32) << 16 | ADDRESS
+/// What was queued by `/pulses`.
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(poem_openapi::Object))]
+struct PulsesResponse {
+    /// Number of marks and spaces.
+    pulses: usize,
+    /// How long transmitting the sequence takes, in microseconds.
+    duration_us: u64,
+}
+
+/// How long transmitting `pulses` takes.
+fn pulses_duration(pulses: &[u16]) -> Duration {
+    Duration::from_micros(pulses.iter().map(|&d| d as u64).sum())
+}
+
+/// Queues a sequence of mark and space durations in microseconds, starting
+/// with a mark, to be transmitted on the NEC carrier as it is. Like raw
+/// frames, only permitted when raw commands aren't restricted, and only
+/// sent to firmware that supports it.
+async fn send_pulses(
+    tx: &CommandSender,
+    state: &SerialState,
+    durations: &[u32],
+    wait: bool,
+) -> poem::Result<PulsesResponse> {
+    if durations.is_empty() || durations.len() > wire::MAX_PULSES {
+        return Err(json_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_pulses",
+            format!("between 1 and {} pulses are needed", wire::MAX_PULSES),
+        ));
     }
+    let Some(pulses) = durations
+        .iter()
+        .map(|&d| u16::try_from(d).ok().filter(|&d| d > 0))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return Err(json_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_pulses",
+            format!("pulses must be between 1 and {} microseconds", u16::MAX),
+        ));
+    };
+    if tx.settings.get(|s| !matches!(s.raw_filter, RawFilter::Any)) {
+        return Err(json_error(
+            StatusCode::BAD_REQUEST,
+            "raw_not_permitted",
+            "pulses are not permitted while raw commands are restricted",
+        ));
+    }
+    if firmware_lacks(state, "pulses") {
+        return Err(json_error(
+            StatusCode::NOT_IMPLEMENTED,
+            "unsupported_by_firmware",
+            "the firmware does not support pulse sequences",
+        ));
+    }
+    let response = PulsesResponse {
+        pulses: pulses.len(),
+        duration_us: pulses_duration(&pulses).as_micros() as u64,
+    };
+    tx.submit(UserCommand::Pulses(pulses), wait).await?;
+    Ok(response)
 }
 
-#[derive(Clone)]
-struct CommandSender(Sender<UserCommand>);
+#[handler]
+async fn post_pulses(
+    tx: Data<&CommandSender>,
+    state: Data<&SerialState>,
+    durations: Json<Vec<u32>>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<PulsesResponse>> {
+    send_pulses(&tx, &state, &durations, w.wait).await.map(Json)
+}
 
-impl CommandSender {
-    async fn send(&self, command: UserCommand) -> Result<(), ()> {
-        const CMD_TIMEOUT: Duration = Duration::from_secs(5);
+/// What was queued by `/repeat`.
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(poem_openapi::Object))]
+struct NecRepeatResponse {
+    /// How many repeat codes.
+    count: u8,
+}
 
-        self.0
-            .send_timeout(command, CMD_TIMEOUT)
-            .await
-            .map_err(|_| ())
+#[derive(Debug, Deserialize)]
+struct NecRepeatParams {
+    count: Option<u8>,
+}
+
+/// Queues `count` lone NEC repeat codes, one by default, for devices that
+/// expect them on their own rather than after a frame of a held button. The
+/// firmware paces them like the repeats of a held button.
+async fn send_nec_repeat(
+    tx: &CommandSender,
+    state: &SerialState,
+    count: Option<u8>,
+    wait: bool,
+) -> poem::Result<NecRepeatResponse> {
+    let count = count.unwrap_or(1);
+    if !(1..=MAX_REPEAT).contains(&count) {
+        return Err(json_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_count",
+            format!("count must be between 1 and {MAX_REPEAT}"),
+        ));
     }
+    if firmware_lacks(state, "nec-repeat") {
+        return Err(json_error(
+            StatusCode::NOT_IMPLEMENTED,
+            "unsupported_by_firmware",
+            "the firmware does not support NEC repeat codes",
+        ));
+    }
+    tx.submit(UserCommand::NecRepeat(count), wait).await?;
+    Ok(NecRepeatResponse { count })
 }
 
-async fn open_serial() -> anyhow::Result<SerialStream> {
-    let s = (async || -> anyhow::Result<SerialStream> {
-        tokio::task::spawn_blocking(|| {
-            Ok(tokio_serial::SerialStream::open(&tokio_serial::new(
-                "/dev/serial/by-id/usb-Jabu_Infrared_1-if00",
-                115200,
-            ))?)
-        })
-        .await?
-    })
-    .retry(ExponentialBuilder::default().with_max_times(16))
-    .notify(|e, d| warn!("Failed to open serial, retrying in {} s: {e}", d.as_secs()))
-    .await
-    .context("Could not open serial port")?;
-    Ok(s)
+#[handler]
+async fn post_repeat(
+    tx: Data<&CommandSender>,
+    state: Data<&SerialState>,
+    q: Query<NecRepeatParams>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<NecRepeatResponse>> {
+    send_nec_repeat(&tx, &state, q.count, w.wait)
+        .await
+        .map(Json)
+}
+
+#[handler]
+async fn post_raw_command(
+    tx: Data<&CommandSender>,
+    q: Query<RawCommandParams>,
+    w: Query<WaitParams>,
+) -> poem::Result<Json<SentFrame>> {
+    if !tx.permits_raw(q.cmd) {
+        return Err(raw_not_permitted(q.cmd));
+    }
+    let repeat = RepeatParams {
+        address: q.address,
+        repeat: q.repeat,
+        gap_ms: q.gap_ms,
+    };
+    send_repeated(&tx, InfraredCommand::Raw(q.cmd), &repeat, w.wait)
+        .await
+        .map(Json)
+}
+
+/// A single step of a `/command` batch or of a macro, optionally targeting
+/// another NEC device than the configured one.
+#[derive(Clone, Debug, Deserialize)]
+struct BatchEntry {
+    #[serde(flatten)]
+    cmd: BatchCommand,
+    address: Option<NecAddress>,
 }
 
-async fn ir_task(mut rx: Receiver<UserCommand>) -> anyhow::Result<()> {
-    async fn ir(serial: &mut SerialStream, cmd: InfraredCommand) -> anyhow::Result<()> {
-        let v = cmd.as_u32_le();
-        let hex = format!("{v:x}");
-        debug!("Sending command: {hex}");
-        while let Err(e) = serial.write_all(hex.as_bytes()).await {
-            error!("Failed to write to serial, reopening: {e:?}");
-            *serial = open_serial().await?;
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum BatchCommand {
+    Power,
+    PowerOnHack { gap_ms: Option<u64> },
+    VolumeUp,
+    VolumeDown,
+    Mute,
+    Input { input: AudioInput },
+    Raw { cmd: u8 },
+    Delay { ms: u64 },
+}
+
+/// Longest `delay` step, any longer would hold up the queue of every client.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+impl BatchCommand {
+    /// Why the step can't be queued, whatever the settings.
+    fn invalid(&self) -> Option<String> {
+        match *self {
+            BatchCommand::Delay { ms } if Duration::from_millis(ms) > MAX_DELAY => Some(format!(
+                "delays must be at most {}ms",
+                MAX_DELAY.as_millis()
+            )),
+            BatchCommand::PowerOnHack { gap_ms } => invalid_gap(gap_ms),
+            _ => None,
         }
-        Ok(())
     }
 
-    let mut serial = open_serial().await?;
-    loop {
-        let Some(cmd) = rx.recv().await else {
-            // All senders died, we're done here
-            return Ok(());
-        };
-        match cmd {
-            UserCommand::Direct(v) => ir(&mut serial, v).await?,
-            UserCommand::PowerOnHack => {
-                ir(&mut serial, InfraredCommand::TogglePower).await?;
-                time::sleep(Duration::from_secs_f32(3.)).await;
-                ir(&mut serial, InfraredCommand::TogglePower).await?;
-                time::sleep(Duration::from_secs_f32(3.)).await;
-            }
+    fn into_user_command(self, tx: &CommandSender, address: NecAddress) -> UserCommand {
+        let direct = |cmd| UserCommand::Direct(cmd, address);
+        match self {
+            BatchCommand::Power => direct(InfraredCommand::TogglePower),
+            BatchCommand::PowerOnHack { gap_ms } => tx.power_on_hack(gap_ms, address, None),
+            BatchCommand::VolumeUp => direct(InfraredCommand::VolumeUp),
+            BatchCommand::VolumeDown => direct(InfraredCommand::VolumeDown),
+            BatchCommand::Mute => direct(InfraredCommand::Mute),
+            BatchCommand::Input { input } => direct(InfraredCommand::SetInput(input)),
+            BatchCommand::Raw { cmd } => direct(InfraredCommand::Raw(cmd)),
+            BatchCommand::Delay { ms } => UserCommand::Delay(Duration::from_millis(ms)),
         }
     }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-
-    let (tx, rx) = mpsc::channel::<UserCommand>(1);
-    let app = Route::new()
-        .at("/toggle-power", poem::post(post_toggle_power))
-        .at("/power-on-hack", poem::post(post_power_on_hack))
-        .at("/set-input", poem::post(post_set_input))
-        .at("/raw-command", poem::post(post_raw_command))
-        .data(CommandSender(tx));
-    let acceptor = make_acceptor().await?;
+#[derive(Debug, Serialize)]
+struct BatchResponse {
+    sent: usize,
+}
 
-    let cancel_token = CancellationToken::new();
+/// Why a batch was cut short, along with how much of it made it into the
+/// queue.
+#[derive(Debug, Serialize)]
+struct BatchError {
+    #[serde(flatten)]
+    error: ErrorResponse,
+    sent: usize,
+}
 
-    let cancel_token_ir = cancel_token.clone();
-    tokio::spawn(async move {
-        if let Err(e) = ir_task(rx).await {
-            error!("IR Task died, cleaning up: {e:#}");
-            cancel_token_ir.cancel();
+/// Queues the steps of `batch` in order, responding with how many of them
+/// made it into the queue.
+async fn send_batch(
+    tx: &CommandSender,
+    batch: Vec<BatchEntry>,
+    wait: bool,
+) -> poem::Result<Response> {
+    // Reject the whole batch up front rather than sending a part of it
+    for entry in &batch {
+        if let Some(detail) = entry.cmd.invalid() {
+            return Err(json_error(StatusCode::BAD_REQUEST, "invalid_step", detail));
         }
-    });
+        if let BatchCommand::Raw { cmd } = entry.cmd
+            && !tx.permits_raw(cmd)
+        {
+            return Err(raw_not_permitted(cmd));
+        }
+    }
+    let mut sent = 0;
+    let mut results = Vec::new();
+    for entry in batch {
+        let cmd = entry
+            .cmd
+            .into_user_command(tx, entry.address.unwrap_or(tx.address()));
+        let reply = wait.then(|| {
+            let (reply, result) = oneshot::channel();
+            results.push(result);
+            reply
+        });
+        if let Err(e) = tx.send(cmd, reply).await {
+            // Tell how much of the batch made it into the queue
+            let error = e.body();
+            return Ok(e.respond(Json(BatchError { error, sent })));
+        }
+        sent += 1;
+    }
+    // The queue is processed in order, so the results arrive in order too
+    for result in results {
+        CommandSender::wait(result).await?;
+    }
+    Ok(Json(BatchResponse { sent }).into_response())
+}
 
-    Server::new_with_acceptor(acceptor)
-        .run_with_graceful_shutdown(app, cancel_token.cancelled(), None)
-        .await?;
-    Ok(())
+#[handler]
+async fn post_command(
+    tx: Data<&CommandSender>,
+    w: Query<WaitParams>,
+    batch: Json<Vec<BatchEntry>>,
+) -> poem::Result<Response> {
+    send_batch(&tx, batch.0, w.wait).await
+}
+
+/// Runs a macro defined in the config.
+#[handler]
+async fn post_macro(
+    tx: Data<&CommandSender>,
+    Path(name): Path<String>,
+    w: Query<WaitParams>,
+) -> poem::Result<Response> {
+    let Some(steps) = tx.settings.get(|s| s.macros.get(&name).cloned()) else {
+        return Err(json_error(
+            StatusCode::NOT_FOUND,
+            "unknown_macro",
+            format!("no macro named '{name}'"),
+        ));
+    };
+    send_batch(&tx, steps, w.wait).await
+}
+
+const DEFAULT_SELFTEST_DWELL: Duration = Duration::from_secs(3);
+const MAX_SELFTEST_DWELL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize)]
+struct SelfTestParams {
+    dwell_ms: Option<u64>,
+    address: Option<NecAddress>,
+}
+
+/// What `/selftest` sent.
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(poem_openapi::Object))]
+struct SelfTestResponse {
+    /// One frame per input, in order, then the one restoring the input
+    /// selected before, if it was known.
+    frames: Vec<SentFrame>,
+    restored: bool,
+}
+
+/// Selects `input` and waits until it was, without coalescing it with a
+/// selection of the same input moments ago.
+async fn select_input(
+    tx: &CommandSender,
+    input: AudioInput,
+    address: Option<NecAddress>,
+) -> poem::Result<SentFrame> {
+    let address = address.unwrap_or(tx.address());
+    tx.submit(UserCommand::SelectInput(input, address), true)
+        .await?;
+    Ok(SentFrame::new(InfraredCommand::SetInput(input), address))
+}
+
+/// Selects every input in turn, `dwell_ms` apart, to check while setting up
+/// the device that it follows each of them, then goes back to the last known
+/// input. Each step waits for its frame to be transmitted, so the steps show
+/// up on `/events` and in the log as they go, and the response only comes
+/// once all of them are done. Only one self-test runs at a time.
+async fn run_selftest(
+    tx: &CommandSender,
+    status: &watch::Receiver<DeviceStatus>,
+    dwell_ms: Option<u64>,
+    address: Option<NecAddress>,
+) -> poem::Result<SelfTestResponse> {
+    let dwell = dwell_ms.map_or(DEFAULT_SELFTEST_DWELL, Duration::from_millis);
+    if dwell > MAX_SELFTEST_DWELL {
+        return Err(json_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_dwell",
+            format!(
+                "dwell_ms must be at most {}",
+                MAX_SELFTEST_DWELL.as_millis()
+            ),
+        ));
+    }
+    let Some(_running) = tx.selftest.start() else {
+        return Err(json_error(
+            StatusCode::CONFLICT,
+            "selftest_in_progress",
+            "a self-test is already in progress",
+        ));
+    };
+    let previous = status.borrow().last_input;
+    let mut frames = Vec::new();
+    for (step, input) in AudioInput::ALL.into_iter().enumerate() {
+        if step > 0 {
+            time::sleep(dwell).await;
+        }
+        info!(
+            step = step + 1,
+            of = AudioInput::ALL.len(),
+            input = input.as_str(),
+            "Self-test selecting input"
+        );
+        frames.push(select_input(tx, input, address).await?);
+    }
+    if let Some(input) = previous {
+        time::sleep(dwell).await;
+        info!(input = input.as_str(), "Self-test restoring input");
+        frames.push(select_input(tx, input, address).await?);
+    }
+    info!("Self-test finished");
+    Ok(SelfTestResponse {
+        frames,
+        restored: previous.is_some(),
+    })
+}
+
+#[handler]
+async fn post_selftest(
+    tx: Data<&CommandSender>,
+    status: Data<&watch::Receiver<DeviceStatus>>,
+    q: Query<SelfTestParams>,
+) -> poem::Result<Json<SelfTestResponse>> {
+    run_selftest(&tx, &status, q.dwell_ms, q.address)
+        .await
+        .map(Json)
+}
+
+#[handler]
+async fn get_macros(tx: Data<&CommandSender>) -> Json<Vec<String>> {
+    let mut names = tx
+        .settings
+        .get(|s| s.macros.keys().cloned().collect::<Vec<_>>());
+    names.sort();
+    Json(names)
+}
+
+/// An NEC frame picked up by the firmware's IR receiver.
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(poem_openapi::Object))]
+struct LearnedFrame {
+    /// The whole frame, in the same format as [`SentFrame::frame`].
+    frame: String,
+    address: String,
+    /// The command byte, when followed by its complement as NEC requires.
+    scancode: Option<String>,
+}
+
+impl LearnedFrame {
+    fn new(frame: u32) -> Self {
+        let [address_lo, address_hi, inverted, cmd] = frame.to_le_bytes();
+        LearnedFrame {
+            frame: format!("{frame:08x}"),
+            address: format!("{:#06x}", u16::from_le_bytes([address_lo, address_hi])),
+            scancode: (inverted == !cmd).then(|| format!("{cmd:#04x}")),
+        }
+    }
+}
+
+const DEFAULT_LEARN_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_LEARN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Waits up to `timeout_ms` for the next frame received by the firmware.
+async fn learn(events: &Events, timeout_ms: Option<u64>) -> poem::Result<LearnedFrame> {
+    let timeout = timeout_ms.map_or(DEFAULT_LEARN_TIMEOUT, Duration::from_millis);
+    if timeout > MAX_LEARN_TIMEOUT {
+        return Err(json_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_timeout",
+            format!(
+                "timeout_ms must be at most {}",
+                MAX_LEARN_TIMEOUT.as_millis()
+            ),
+        ));
+    }
+    let mut received = events.subscribe_received();
+    let next = async {
+        loop {
+            match received.recv().await {
+                Ok(frame) => return Some(frame),
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    };
+    match time::timeout(timeout, next).await {
+        Ok(Some(frame)) => Ok(LearnedFrame::new(frame)),
+        Ok(None) => Err(json_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "ir_task_stopped",
+            "IR task is not running",
+        )),
+        Err(_) => Err(json_error(
+            StatusCode::GATEWAY_TIMEOUT,
+            "learn_timeout",
+            "no IR frame received",
+        )),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LearnParams {
+    timeout_ms: Option<u64>,
+}
+
+/// Returns the next frame picked up by the firmware's IR receiver, so that
+/// the codes of a remote can be discovered by pressing its buttons.
+#[handler]
+async fn post_learn(
+    events: Data<&Events>,
+    q: Query<LearnParams>,
+) -> poem::Result<Json<LearnedFrame>> {
+    learn(&events, q.timeout_ms).await.map(Json)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[cfg_attr(
+    feature = "openapi",
+    derive(poem_openapi::Enum),
+    oai(rename_all = "lowercase")
+)]
+enum SerialHealth {
+    Connected,
+    Disconnected,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(poem_openapi::Object))]
+struct HealthResponse {
+    serial: SerialHealth,
+}
+
+/// How long a ping through the firmware took.
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(poem_openapi::Object))]
+struct PingResponse {
+    /// From writing the ping until the firmware acknowledged it, in
+    /// microseconds. Excludes the time spent in the queue.
+    round_trip_us: u64,
+}
+
+/// Has the IR task ping the firmware once it's done with the commands queued
+/// before, and waits for the answer. Unlike `/health`, this checks the whole
+/// path to the firmware right now.
+async fn ping(tx: &CommandSender) -> poem::Result<PingResponse> {
+    /// Like for waiting on a command, see [`CommandSender::wait`].
+    const PING_TIMEOUT: Duration = Duration::from_secs(30);
+
+    let (reply, round_trip) = oneshot::channel();
+    tx.send(UserCommand::Ping(reply), None).await?;
+    match time::timeout(PING_TIMEOUT, round_trip).await {
+        Ok(Ok(Ok(round_trip))) => Ok(PingResponse {
+            round_trip_us: round_trip.as_micros() as u64,
+        }),
+        Ok(Ok(Err(e))) => Err(json_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "ping_failed",
+            e,
+        )),
+        Ok(Err(_)) => Err(json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "ir_task_stopped",
+            "IR task stopped before sending the ping",
+        )),
+        Err(_) => Err(json_error(
+            StatusCode::GATEWAY_TIMEOUT,
+            "ping_timeout",
+            "timed out waiting for the ping to be sent",
+        )),
+    }
+}
+
+/// How many queued commands `/queue/clear` discarded.
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(poem_openapi::Object))]
+struct QueueClearResponse {
+    cleared: usize,
+}
+
+/// Has the IR task discard the commands waiting in the queue, once it's done
+/// with the one it's transmitting, if any. Handlers waiting for them fail.
+async fn clear_queue(tx: &CommandSender) -> poem::Result<QueueClearResponse> {
+    /// Like for waiting on a command, see [`CommandSender::wait`].
+    const CLEAR_TIMEOUT: Duration = Duration::from_secs(30);
+
+    let stopped = || {
+        json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "ir_task_stopped",
+            "IR task stopped before clearing the queue",
+        )
+    };
+    let (reply, cleared) = oneshot::channel();
+    tx.clear.send(reply).await.map_err(|_| stopped())?;
+    match time::timeout(CLEAR_TIMEOUT, cleared).await {
+        Ok(Ok(cleared)) => Ok(QueueClearResponse { cleared }),
+        Ok(Err(_)) => Err(stopped()),
+        Err(_) => Err(json_error(
+            StatusCode::GATEWAY_TIMEOUT,
+            "clear_timeout",
+            "timed out waiting for the current command to finish",
+        )),
+    }
+}
+
+#[handler]
+async fn post_queue_clear(tx: Data<&CommandSender>) -> poem::Result<Json<QueueClearResponse>> {
+    clear_queue(&tx).await.map(Json)
+}
+
+#[handler]
+async fn get_ping(tx: Data<&CommandSender>) -> poem::Result<Json<PingResponse>> {
+    ping(&tx).await.map(Json)
+}
+
+#[handler]
+async fn get_health(state: Data<&SerialState>) -> (StatusCode, Json<HealthResponse>) {
+    if state.is_connected() {
+        (
+            StatusCode::OK,
+            Json(HealthResponse {
+                serial: SerialHealth::Connected,
+            }),
+        )
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(HealthResponse {
+                serial: SerialHealth::Disconnected,
+            }),
+        )
+    }
+}
+
+/// What is running, for telling deployments apart.
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(poem_openapi::Object))]
+struct VersionResponse {
+    /// Version of this server.
+    api: String,
+    /// Version the firmware reported. Missing while disconnected, or when the
+    /// firmware is too old to report one.
+    firmware: Option<String>,
+}
+
+impl VersionResponse {
+    fn new(state: &SerialState) -> Self {
+        VersionResponse {
+            api: env!("CARGO_PKG_VERSION").to_owned(),
+            firmware: state
+                .firmware()
+                .filter(|_| state.is_connected())
+                .and_then(|info| info.version),
+        }
+    }
+}
+
+#[handler]
+async fn get_version(state: Data<&SerialState>) -> Json<VersionResponse> {
+    Json(VersionResponse::new(&state))
+}
+
+/// Best-effort state of the device, as far as it can be inferred from the
+/// commands sent through this server. Changes made with the physical remote,
+/// or through another controller, are not reflected.
+#[derive(Clone, Debug, Default, Serialize)]
+struct DeviceStatus {
+    /// Whether the device is on, known after a power-on hack and tracked
+    /// through the toggles since.
+    power: Option<bool>,
+    /// The input selected by the last successful `SetInput`, or the one the
+    /// device is expected to be on after a power toggle since.
+    last_input: Option<AudioInput>,
+    /// When the last successful command of any kind was sent, in milliseconds
+    /// since the Unix epoch.
+    last_command_at: Option<u64>,
+}
+
+impl DeviceStatus {
+    fn command_sent(&mut self, cmd: InfraredCommand, after_power: InputAfterPower) {
+        match cmd {
+            InfraredCommand::SetInput(input) => self.last_input = Some(input),
+            InfraredCommand::TogglePower | InfraredCommand::Raw(codes::TOGGLE_POWER) => {
+                self.power = self.power.map(|on| !on);
+                match after_power {
+                    InputAfterPower::Keep => {}
+                    InputAfterPower::Unknown => self.last_input = None,
+                    InputAfterPower::Input(input) => self.last_input = Some(input),
+                }
+            }
+            _ => {}
+        }
+        self.last_command_at = Some(unix_millis());
+    }
+}
+
+#[handler]
+async fn get_status(status: Data<&watch::Receiver<DeviceStatus>>) -> Json<DeviceStatus> {
+    Json(status.borrow().clone())
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(poem_openapi::Object))]
+struct QueueResponse {
+    queued: usize,
+    capacity: usize,
+}
+
+impl QueueResponse {
+    fn new(tx: &CommandSender) -> Self {
+        let capacity = tx.tx.max_capacity();
+        QueueResponse {
+            queued: capacity - tx.tx.capacity(),
+            capacity,
+        }
+    }
+}
+
+/// What this device and its firmware can do, so that clients can discover it
+/// rather than hardcode it.
+#[derive(Debug, Serialize)]
+struct Capabilities {
+    /// Paths of the command endpoints, relative to the device.
+    commands: &'static [&'static str],
+    inputs: Vec<&'static str>,
+    raw: RawFilter,
+    macros: Vec<String>,
+    nec_address: NecAddress,
+    codes: Codes,
+    /// Missing while disconnected, or when the firmware is too old to
+    /// describe itself.
+    firmware: Option<FirmwareInfo>,
+}
+
+/// The command bytes behind the named commands, in hex like the `scancode`
+/// of the responses.
+#[derive(Debug, Serialize)]
+struct Codes {
+    commands: BTreeMap<&'static str, String>,
+    inputs: BTreeMap<&'static str, String>,
+    /// The bytes a raw command may be, further limited by the `raw`
+    /// restrictions.
+    raw: &'static str,
+}
+
+impl Codes {
+    fn new() -> Self {
+        let hex = |cmd: InfraredCommand| format!("{:#04x}", cmd.as_u8());
+        let commands = [
+            InfraredCommand::TogglePower,
+            InfraredCommand::VolumeUp,
+            InfraredCommand::VolumeDown,
+            InfraredCommand::Mute,
+        ];
+        Codes {
+            commands: commands
+                .into_iter()
+                .map(|cmd| (command_kind(&cmd), hex(cmd)))
+                .collect(),
+            inputs: AudioInput::ALL
+                .into_iter()
+                .map(|input| (input.as_str(), hex(InfraredCommand::SetInput(input))))
+                .collect(),
+            raw: "0x00-0xff",
+        }
+    }
+}
+
+#[handler]
+async fn get_capabilities(
+    tx: Data<&CommandSender>,
+    state: Data<&SerialState>,
+) -> Json<Capabilities> {
+    const COMMANDS: &[&str] = &[
+        "toggle-power",
+        "power-on-hack",
+        "reset",
+        "volume-up",
+        "volume-down",
+        "mute",
+        "set-input",
+        "input/next",
+        "input/prev",
+        "input/toggle",
+        "input/{name}",
+        "power/on",
+        "power/off",
+        "raw-command",
+        "raw-frame",
+        "pulses",
+        "repeat",
+        "hold/start",
+        "hold/stop",
+        "command",
+        "macro/{name}",
+        "learn",
+        "selftest",
+    ];
+
+    let (raw, mut macros) = tx.settings.get(|s| {
+        (
+            s.raw_filter.clone(),
+            s.macros.keys().cloned().collect::<Vec<_>>(),
+        )
+    });
+    macros.sort();
+    Json(Capabilities {
+        commands: COMMANDS,
+        inputs: AudioInput::ALL.iter().map(AudioInput::as_str).collect(),
+        raw,
+        macros,
+        nec_address: tx.address(),
+        codes: Codes::new(),
+        firmware: state.firmware(),
+    })
+}
+
+#[handler]
+async fn get_queue(tx: Data<&CommandSender>) -> Json<QueueResponse> {
+    Json(QueueResponse::new(&tx))
+}
+
+#[handler]
+async fn get_metrics(metrics: Data<&Metrics>, state: Data<&SerialState>) -> Response {
+    Response::builder()
+        .content_type("application/openmetrics-text; version=1.0.0; charset=utf-8")
+        .body(metrics.encode(state.is_connected()))
+}
+
+/// The routes of a single device. The first device is served at the root,
+/// and every device under `/device/<name>`.
+fn device_routes() -> Route {
+    Route::new()
+        .at("/health", poem::get(get_health))
+        .at("/ping", poem::get(get_ping))
+        .at("/metrics", poem::get(get_metrics))
+        .at("/events", poem::get(events::get_events))
+        .at("/history", poem::get(events::get_history))
+        .at("/queue", poem::get(get_queue))
+        .at("/queue/clear", poem::post(post_queue_clear))
+        .at("/status", poem::get(get_status))
+        .at("/capabilities", poem::get(get_capabilities))
+        .at("/version", poem::get(get_version))
+        .at("/toggle-power", poem::post(post_toggle_power))
+        .at("/power-on-hack", poem::post(post_power_on_hack))
+        .at("/reset", poem::post(post_reset))
+        .at("/volume-up", poem::post(post_volume_up))
+        .at("/volume-down", poem::post(post_volume_down))
+        .at("/mute", poem::post(post_mute))
+        .at("/set-input", poem::post(post_set_input))
+        .at("/input/next", poem::post(post_input_next))
+        .at("/input/prev", poem::post(post_input_prev))
+        .at("/input/toggle", poem::post(post_input_toggle))
+        .at("/input/next/preview", poem::get(get_input_next_preview))
+        .at("/input/prev/preview", poem::get(get_input_prev_preview))
+        .at("/input/toggle/preview", poem::get(get_input_toggle_preview))
+        .at("/input/:name", poem::post(post_input))
+        .at("/power/on", poem::post(post_power_on))
+        .at("/power/off", poem::post(post_power_off))
+        .at("/inputs", poem::get(get_inputs))
+        .at("/raw-command", poem::post(post_raw_command))
+        .at("/raw-frame", poem::post(post_raw_frame))
+        .at("/pulses", poem::post(post_pulses))
+        .at("/repeat", poem::post(post_repeat))
+        .at("/hold/start", poem::post(post_hold_start))
+        .at("/hold/stop", poem::post(post_hold_stop))
+        .at("/command", poem::post(post_command))
+        .at("/macros", poem::get(get_macros))
+        .at("/macro/:name", poem::post(post_macro))
+        .at("/learn", poem::post(post_learn))
+        .at("/selftest", poem::post(post_selftest))
+        .at("/debug/frame", poem::get(debug::get_frame))
+}
+
+/// What the routes of a device need to talk to its IR task.
+struct DeviceHandles {
+    sender: CommandSender,
+    state: SerialState,
+    metrics: Metrics,
+    events: Events,
+    status: watch::Receiver<DeviceStatus>,
+}
+
+impl DeviceHandles {
+    /// Creates the handles of a device, along with the ends of the channels
+    /// its IR task holds.
+    fn new(
+        config: &Config,
+        device: &DeviceConfig,
+    ) -> (Self, CommandQueue, watch::Sender<DeviceStatus>) {
+        let (tx, commands) = mpsc::channel(config.queue_capacity);
+        let (clear_tx, clear) = mpsc::channel(4);
+        let (status_tx, status_rx) = watch::channel(DeviceStatus::default());
+        let metrics = Metrics::new();
+        let handles = DeviceHandles {
+            sender: CommandSender {
+                tx,
+                clear: clear_tx,
+                metrics: metrics.clone(),
+                settings: SharedSettings::new(DeviceSettings::new(config, device)),
+                power_on: PowerOnHacks::default(),
+                hold: Holds::default(),
+                selftest: SelfTests::default(),
+            },
+            state: SerialState::default(),
+            metrics,
+            events: Events::new(config.history_size),
+            status: status_rx,
+        };
+        (handles, CommandQueue { commands, clear }, status_tx)
+    }
+
+    fn attach(&self, routes: Route) -> impl Endpoint + use<> {
+        routes
+            .data(self.sender.clone())
+            .data(self.state.clone())
+            .data(self.metrics.clone())
+            .data(self.events.clone())
+            .data(self.status.clone())
+    }
+}
+
+#[derive(Clone)]
+struct DeviceNames(Arc<[String]>);
+
+#[handler]
+async fn get_devices(Data(names): Data<&DeviceNames>) -> Json<Vec<String>> {
+    Json(names.0.to_vec())
+}
+
+/// Stops the server like SIGTERM does, for `/admin/shutdown`. Only offered
+/// when a token is configured, so that not just anyone can stop it.
+#[derive(Clone)]
+struct AdminShutdown {
+    enabled: bool,
+    cancel_token: CancellationToken,
+}
+
+#[derive(Debug, Serialize)]
+struct ShutdownResponse {
+    shutting_down: bool,
+}
+
+/// Shuts the server down gracefully, draining the command queues and closing
+/// the serial ports. Responds right away, while that's still going on.
+#[handler]
+async fn post_admin_shutdown(
+    Data(shutdown): Data<&AdminShutdown>,
+) -> poem::Result<Json<ShutdownResponse>> {
+    if !shutdown.enabled {
+        return Err(json_error(
+            StatusCode::FORBIDDEN,
+            "shutdown_disabled",
+            "shutting down over the API requires a token to be configured",
+        ));
+    }
+    info!("Shutdown requested over the API");
+    shutdown.cancel_token.cancel();
+    Ok(Json(ShutdownResponse {
+        shutting_down: true,
+    }))
+}
+
+/// Binds a Unix socket at `path` with permissions `mode`. A socket nobody
+/// listens on anymore, left behind by a previous run, is replaced.
+fn bind_unix_socket(path: &std::path::Path, mode: u32) -> anyhow::Result<UnixListener> {
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        anyhow::ensure!(
+            meta.file_type().is_socket(),
+            "{} exists and is not a socket",
+            path.display()
+        );
+        anyhow::ensure!(
+            UnixStream::connect(path).is_err(),
+            "{} is in use by another server",
+            path.display()
+        );
+        info!(path = %path.display(), "Removing stale socket");
+        std::fs::remove_file(path)
+            .with_context(|| format!("Could not remove {}", path.display()))?;
+    }
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("Could not bind {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Could not set the permissions of {}", path.display()))?;
+    Ok(listener)
+}
+
+/// Listens on the Unix socket passed by systemd, or else on the configured
+/// Unix socket or TCP addresses.
+async fn make_acceptor(config: &Config) -> anyhow::Result<Box<dyn DynAcceptor>> {
+    let mut listenfd = ListenFd::from_env();
+    let listener = match (listenfd.take_unix_listener(0)?, &config.unix_socket) {
+        (Some(listener), _) => Some(listener),
+        (None, Some(path)) => {
+            let listener = bind_unix_socket(path, config.unix_socket_mode)?;
+            info!(path = %path.display(), "Listening");
+            Some(listener)
+        }
+        (None, None) => None,
+    };
+    Ok(match listener {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            Box::new(ToDynAcceptor(UnixAcceptor::from_std(listener)?))
+        }
+        None => {
+            warn!("Did not receive Unix socket, falling back to TCP.");
+            let acceptor = config
+                .bind_addresses()
+                .map(|addr| TcpListener::bind(addr.to_owned()).boxed())
+                .reduce(|a, b| a.combine(b).boxed())
+                .expect("split yields at least one address")
+                .into_acceptor()
+                .await
+                .with_context(|| format!("Could not listen on {}", config.bind))?;
+            for addr in acceptor.local_addr() {
+                info!(%addr, "Listening");
+            }
+            acceptor
+        }
+    })
+}
+
+enum UserCommand {
+    /// Directly transmit an infrared command to the device at the address
+    Direct(InfraredCommand, NecAddress),
+
+    /// The Power button is a toggle, so unless we know the current state,
+    /// we cannot reliably turn the device On.
+    /// However, the device ignores a repeated power-toggle command within
+    /// a few seconds of turning on, whereas this window is shorter when turning
+    /// off. So sending a second power-toggle command in this gap causes the
+    /// device to eventually reach the On state, with the downside of a few
+    /// seconds delay if it was already on.
+    ///
+    /// `gap` is how long to wait between the two toggles, `settle` how long
+    /// to keep the IR task waiting after the second one, if at all. With
+    /// `reset`, the power is toggled once more beforehand, waiting that long
+    /// after, so that the device is turned off first. `claim` keeps other
+    /// requests from queueing another one until this one is done.
+    PowerOnHack {
+        gap: Duration,
+        settle: Duration,
+        address: NecAddress,
+        reset: Option<Duration>,
+        claim: Option<PowerOnHackClaim>,
+    },
+
+    /// Transmit a command `count` times, waiting `gap` between them
+    Repeat {
+        cmd: InfraredCommand,
+        address: NecAddress,
+        count: u8,
+        gap: Duration,
+    },
+
+    /// Pause the command queue, used to space out commands of a batch
+    Delay(Duration),
+
+    /// Transmit mark and space durations in microseconds, see [`send_pulses`]
+    Pulses(Vec<u16>),
+
+    /// Transmit a command, then NEC repeat codes until `release` fires, see
+    /// [`start_hold`]. The IR task fires it once it's done, so that the next
+    /// hold may start.
+    Hold {
+        cmd: InfraredCommand,
+        address: NecAddress,
+        release: CancellationToken,
+    },
+
+    /// Transmit this many lone NEC repeat codes, see [`send_nec_repeat`]
+    NecRepeat(u8),
+
+    /// Select an input like a `Direct` command would, even when it was
+    /// selected moments ago, see [`run_selftest`]
+    SelectInput(AudioInput, NecAddress),
+
+    /// Ping the firmware, replying with the round trip, see [`ping`]
+    Ping(oneshot::Sender<Result<Duration, String>>),
+}
+
+/// Whether a command was transmitted, with the reason when it wasn't.
+type CommandResult = Result<(), String>;
+
+/// A command in the queue of the IR task.
+struct QueuedCommand {
+    command: UserCommand,
+    /// Receives the result once the command was transmitted, when the
+    /// handler waits for it.
+    reply: Option<oneshot::Sender<CommandResult>>,
+    /// When the command was put in the queue.
+    queued_at: time::Instant,
+}
+
+impl QueuedCommand {
+    /// Drops the command without transmitting it, failing whoever waits for
+    /// it.
+    fn discard(self) {
+        const REASON: &str = "cleared from the queue";
+        match self.command {
+            UserCommand::Ping(reply) => _ = reply.send(Err(REASON.into())),
+            // So that the next hold may start
+            UserCommand::Hold { release, .. } => release.cancel(),
+            _ => {}
+        }
+        if let Some(reply) = self.reply {
+            let _ = reply.send(Err(REASON.into()));
+        }
+    }
+}
+
+/// The ends of the channels of a device that its IR task reads.
+struct CommandQueue {
+    commands: Receiver<QueuedCommand>,
+    /// Asks to discard the queued commands, replying how many there were.
+    clear: Receiver<oneshot::Sender<usize>>,
+}
+
+impl CommandQueue {
+    /// Discards the queued commands, along with `taken` off the queue already
+    /// if any, and replies how many there were.
+    fn discard_all(&mut self, taken: Option<QueuedCommand>, reply: oneshot::Sender<usize>) {
+        let mut cleared = 0;
+        for queued in taken
+            .into_iter()
+            .chain(std::iter::from_fn(|| self.commands.try_recv().ok()))
+        {
+            queued.discard();
+            cleared += 1;
+        }
+        info!(cleared, "Cleared the command queue");
+        let _ = reply.send(cleared);
+    }
+}
+
+/// Name of the command used in metrics and logs.
+fn command_kind(cmd: &InfraredCommand) -> &'static str {
+    match cmd {
+        InfraredCommand::TogglePower => "toggle_power",
+        InfraredCommand::VolumeUp => "volume_up",
+        InfraredCommand::VolumeDown => "volume_down",
+        InfraredCommand::Mute => "mute",
+        InfraredCommand::SetInput(_) => "set_input",
+        InfraredCommand::Raw(_) => "raw",
+        InfraredCommand::RawFrame(_) => "raw_frame",
+    }
+}
+
+/// Milliseconds since the Unix epoch, used for timestamps in responses.
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// The frame of a command in hex, as shown in responses and logs.
+fn frame_hex(cmd: InfraredCommand, address: NecAddress) -> String {
+    format!("{:08x}", cmd.encode(address))
+}
+
+impl UserCommand {
+    /// Name of the command used in metrics and logs.
+    fn kind(&self) -> &'static str {
+        match self {
+            UserCommand::Direct(cmd, _) | UserCommand::Repeat { cmd, .. } => command_kind(cmd),
+            UserCommand::PowerOnHack { reset: None, .. } => "power_on_hack",
+            UserCommand::PowerOnHack { reset: Some(_), .. } => "reset",
+            UserCommand::Delay(_) => "delay",
+            UserCommand::Pulses(_) => "pulses",
+            UserCommand::NecRepeat(_) => "nec_repeat",
+            UserCommand::SelectInput(..) => "set_input",
+            UserCommand::Hold { .. } => "hold",
+            UserCommand::Ping(_) => "ping",
+        }
+    }
+}
+
+/// Settings of a device that are looked up for every command rather than
+/// fixed at startup, so that reloading the config can change them.
+#[derive(Debug)]
+struct DeviceSettings {
+    /// NEC address used by commands that don't specify one.
+    address: NecAddress,
+    /// Gap of power-on hacks that don't specify one.
+    power_on_gap: Duration,
+    /// Wait after the second toggle of a power-on hack.
+    power_on_settle: Duration,
+    /// How long after a power-on hack finished further ones are dropped.
+    power_on_debounce: Duration,
+    /// How long after an input was selected selecting it again is dropped.
+    input_coalesce: Duration,
+    /// The inputs `/input/toggle` switches between.
+    toggle_inputs: [AudioInput; 2],
+    /// What the last known input becomes when the power is toggled.
+    input_after_power_toggle: InputAfterPower,
+    /// How many times the code of each input is sent to select it, by name.
+    input_repeats: HashMap<String, u8>,
+    /// Frames queued faster than this are held back until it has passed.
+    frame_spacing: Duration,
+    /// Pause after each frame written before taking the next command.
+    write_delay: Duration,
+    /// How many times, and how long after, frames the firmware rejected are
+    /// sent again.
+    reject_retries: u8,
+    reject_retry_delay: Duration,
+    /// Which bytes may be sent as raw commands.
+    raw_filter: RawFilter,
+    /// How long a command waits for room in a full queue.
+    enqueue_timeout: Duration,
+    /// Named batches runnable with `/macro/<name>`.
+    macros: Arc<HashMap<String, Vec<BatchEntry>>>,
+}
+
+impl DeviceSettings {
+    fn new(config: &Config, device: &DeviceConfig) -> Self {
+        DeviceSettings {
+            address: device.address.unwrap_or(config.nec_address),
+            power_on_gap: config.power_on_gap(),
+            power_on_settle: config.power_on_settle(),
+            power_on_debounce: config.power_on_debounce(),
+            input_coalesce: config.input_coalesce(),
+            toggle_inputs: config.toggle_inputs,
+            input_after_power_toggle: config.input_after_power_toggle,
+            input_repeats: config.input_repeats.clone(),
+            frame_spacing: config.min_frame_spacing(),
+            write_delay: config.write_delay(),
+            reject_retries: config.reject_retries,
+            reject_retry_delay: config.reject_retry_delay(),
+            raw_filter: RawFilter::from_config(config),
+            enqueue_timeout: config.enqueue_timeout(),
+            macros: Arc::new(config.macros.clone()),
+        }
+    }
+
+    /// How many times the code of `input` is sent to select it.
+    fn input_repeats(&self, input: AudioInput) -> u8 {
+        self.input_repeats.get(input.as_str()).copied().unwrap_or(1)
+    }
+}
+
+/// The settings of a device, shared by its routes and its IR task.
+#[derive(Clone, Debug)]
+struct SharedSettings(Arc<RwLock<DeviceSettings>>);
+
+impl SharedSettings {
+    fn new(settings: DeviceSettings) -> Self {
+        SharedSettings(Arc::new(RwLock::new(settings)))
+    }
+
+    fn get<T>(&self, f: impl FnOnce(&DeviceSettings) -> T) -> T {
+        f(&self.0.read().expect("settings lock poisoned"))
+    }
+
+    fn set(&self, settings: DeviceSettings) {
+        *self.0.write().expect("settings lock poisoned") = settings;
+    }
+}
+
+/// Why a command could not be queued.
+#[derive(Clone, Copy, Debug)]
+enum QueueError {
+    /// The queue stayed full for the whole enqueue timeout.
+    Full,
+    /// The IR task is gone, so nothing will ever be sent.
+    Closed,
+}
+
+impl QueueError {
+    /// Suggested wait before retrying a command rejected by a full queue.
+    const RETRY_AFTER_S: u64 = 1;
+
+    /// Responds with `body` and the status matching the error.
+    fn respond(self, body: impl IntoResponse) -> Response {
+        match self {
+            QueueError::Full => (StatusCode::SERVICE_UNAVAILABLE, body)
+                .with_header(header::RETRY_AFTER, Self::RETRY_AFTER_S)
+                .into_response(),
+            QueueError::Closed => (StatusCode::INTERNAL_SERVER_ERROR, body).into_response(),
+        }
+    }
+
+    fn body(self) -> ErrorResponse {
+        match self {
+            QueueError::Full => ErrorResponse::new("queue_full", "command queue is full"),
+            QueueError::Closed => ErrorResponse::new("ir_task_stopped", "IR task is not running"),
+        }
+    }
+}
+
+impl From<QueueError> for poem::Error {
+    fn from(e: QueueError) -> Self {
+        poem::Error::from_response(e.respond(Json(e.body())))
+    }
+}
+
+/// Tracks the power-on hacks of a device, so that a double-clicked power-on
+/// button doesn't toggle the power four times and leave the device off.
+#[derive(Clone, Debug, Default)]
+struct PowerOnHacks(Arc<Mutex<PowerOnHackState>>);
+
+#[derive(Debug, Default)]
+struct PowerOnHackState {
+    running: bool,
+    /// A request queued a power-on hack that hasn't finished yet.
+    claimed: bool,
+    finished_at: Option<time::Instant>,
+}
+
+impl PowerOnHacks {
+    fn lock(&self) -> std::sync::MutexGuard<'_, PowerOnHackState> {
+        self.0.lock().expect("power-on hack lock poisoned")
+    }
+
+    /// Returns a claim keeping other requests from queueing a power-on hack
+    /// until it's dropped, or `None` while one is queued or runs.
+    fn claim(&self) -> Option<PowerOnHackClaim> {
+        let mut state = self.lock();
+        if state.running || state.claimed {
+            return None;
+        }
+        state.claimed = true;
+        Some(PowerOnHackClaim(self.clone()))
+    }
+
+    /// Whether the last power-on hack finished less than `window` ago.
+    fn finished_within(&self, window: Duration) -> bool {
+        self.lock()
+            .finished_at
+            .is_some_and(|at| at.elapsed() < window)
+    }
+
+    fn set_running(&self, running: bool) {
+        let mut state = self.lock();
+        state.running = running;
+        if !running {
+            state.finished_at = Some(time::Instant::now());
+        }
+    }
+}
+
+/// Dropped along with the power-on hack carrying it, once it finished or was
+/// cleared from the queue.
+#[derive(Debug)]
+struct PowerOnHackClaim(PowerOnHacks);
+
+impl Drop for PowerOnHackClaim {
+    fn drop(&mut self) {
+        self.0.lock().claimed = false;
+    }
+}
+
+/// The button of a device held down by `/hold/start`, if any, released by
+/// cancelling its token.
+#[derive(Clone, Debug, Default)]
+struct Holds(Arc<Mutex<Option<CancellationToken>>>);
+
+impl Holds {
+    /// Returns the token releasing a new hold, or `None` while another one
+    /// is held.
+    fn start(&self) -> Option<CancellationToken> {
+        let mut held = self.0.lock().expect("hold lock poisoned");
+        if held.as_ref().is_some_and(|release| !release.is_cancelled()) {
+            return None;
+        }
+        let release = CancellationToken::new();
+        *held = Some(release.clone());
+        Some(release)
+    }
+
+    /// Releases the held button, returning whether one was held.
+    fn stop(&self) -> bool {
+        let held = self.0.lock().expect("hold lock poisoned").take();
+        held.is_some_and(|release| {
+            let was_held = !release.is_cancelled();
+            release.cancel();
+            was_held
+        })
+    }
+}
+
+/// Whether a `/selftest` of a device is running.
+#[derive(Clone, Debug, Default)]
+struct SelfTests(Arc<AtomicBool>);
+
+impl SelfTests {
+    /// Returns a guard marking the self-test running until it's dropped, or
+    /// `None` while another one is.
+    fn start(&self) -> Option<SelfTestGuard> {
+        let running = self.0.swap(true, Ordering::AcqRel);
+        (!running).then(|| SelfTestGuard(self.0.clone()))
+    }
+}
+
+/// Also dropped when the client goes away, which cancels the self-test.
+struct SelfTestGuard(Arc<AtomicBool>);
+
+impl Drop for SelfTestGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+#[derive(Clone)]
+struct CommandSender {
+    tx: Sender<QueuedCommand>,
+    /// See [`CommandQueue::clear`].
+    clear: Sender<oneshot::Sender<usize>>,
+    metrics: Metrics,
+    settings: SharedSettings,
+    power_on: PowerOnHacks,
+    hold: Holds,
+    selftest: SelfTests,
+}
+
+impl CommandSender {
+    /// NEC address used by commands that don't specify one.
+    fn address(&self) -> NecAddress {
+        self.settings.get(|s| s.address)
+    }
+
+    fn permits_raw(&self, cmd: u8) -> bool {
+        self.settings.get(|s| s.raw_filter.permits(cmd))
+    }
+
+    fn power_on_hack(
+        &self,
+        gap_ms: Option<u64>,
+        address: NecAddress,
+        claim: Option<PowerOnHackClaim>,
+    ) -> UserCommand {
+        UserCommand::PowerOnHack {
+            gap: gap_ms.map_or_else(
+                || self.settings.get(|s| s.power_on_gap),
+                Duration::from_millis,
+            ),
+            settle: self.settings.get(|s| s.power_on_settle),
+            address,
+            reset: None,
+            claim,
+        }
+    }
+
+    /// A power-on hack preceded by a toggle turning the device off. Should
+    /// the device have been off, that toggle turned it on instead, and the
+    /// hack has to wait until the device takes toggles again, which is what
+    /// the power-on debounce is configured to.
+    fn reset(&self, address: NecAddress, claim: PowerOnHackClaim) -> UserCommand {
+        let (gap, settle, debounce) = self
+            .settings
+            .get(|s| (s.power_on_gap, s.power_on_settle, s.power_on_debounce));
+        UserCommand::PowerOnHack {
+            gap,
+            settle,
+            address,
+            reset: Some(debounce),
+            claim: Some(claim),
+        }
+    }
+
+    async fn send(
+        &self,
+        command: UserCommand,
+        reply: Option<oneshot::Sender<CommandResult>>,
+    ) -> Result<(), QueueError> {
+        let kind = command.kind();
+        let queued = QueuedCommand {
+            command,
+            reply,
+            queued_at: time::Instant::now(),
+        };
+        let timeout = self.settings.get(|s| s.enqueue_timeout);
+        let error = match self.tx.send_timeout(queued, timeout).await {
+            Ok(()) => {
+                debug!(command = kind, "Queued command");
+                self.metrics.command_queued(kind);
+                return Ok(());
+            }
+            Err(SendTimeoutError::Timeout(_)) => QueueError::Full,
+            Err(SendTimeoutError::Closed(_)) => QueueError::Closed,
+        };
+        warn!(command = kind, ?error, "Failed to queue command");
+        self.metrics.command_failed();
+        Err(error)
+    }
+
+    /// Queues `command`, and when `wait` is set, waits until it was
+    /// transmitted and fails if that didn't succeed.
+    async fn submit(&self, command: UserCommand, wait: bool) -> poem::Result<()> {
+        let (reply, result) = if wait {
+            let (reply, result) = oneshot::channel();
+            (Some(reply), Some(result))
+        } else {
+            (None, None)
+        };
+        self.send(command, reply).await?;
+        match result {
+            Some(result) => Self::wait(result).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Waits for the IR task to report the result of a queued command.
+    async fn wait(result: oneshot::Receiver<CommandResult>) -> poem::Result<()> {
+        /// Long enough for a full queue of slow commands, but a wedged IR task
+        /// must not hold the request forever.
+        const WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+        match time::timeout(WAIT_TIMEOUT, result).await {
+            Ok(Ok(Ok(()))) => Ok(()),
+            Ok(Ok(Err(e))) => Err(json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "transmit_failed",
+                e,
+            )),
+            Ok(Err(_)) => Err(json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "ir_task_stopped",
+                "IR task stopped before sending the command",
+            )),
+            Err(_) => Err(json_error(
+                StatusCode::GATEWAY_TIMEOUT,
+                "send_timeout",
+                "timed out waiting for the command to be sent",
+            )),
+        }
+    }
+}
+
+/// Whether `ir_task` currently holds an open serial port, and what the
+/// firmware on the other end said about itself.
+#[derive(Clone, Default)]
+struct SerialState {
+    connected: Arc<AtomicBool>,
+    firmware: Arc<RwLock<Option<FirmwareInfo>>>,
+}
+
+impl SerialState {
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Relaxed);
+    }
+
+    fn firmware(&self) -> Option<FirmwareInfo> {
+        self.firmware
+            .read()
+            .expect("firmware lock poisoned")
+            .clone()
+    }
+
+    fn set_firmware(&self, info: Option<FirmwareInfo>) {
+        *self.firmware.write().expect("firmware lock poisoned") = info;
+    }
+}
+
+/// Restricts which bytes may be sent as raw commands. Configured with either
+/// `raw_allow` or `raw_deny`.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum RawFilter {
+    #[default]
+    Any,
+    Allow(Vec<u8>),
+    Deny(Vec<u8>),
+}
+
+impl RawFilter {
+    fn from_config(config: &Config) -> Self {
+        match (&config.raw_allow, &config.raw_deny) {
+            (Some(allow), _) => RawFilter::Allow(allow.clone()),
+            (None, Some(deny)) => RawFilter::Deny(deny.clone()),
+            (None, None) => RawFilter::Any,
+        }
+    }
+
+    fn permits(&self, cmd: u8) -> bool {
+        match self {
+            RawFilter::Any => true,
+            RawFilter::Allow(allowed) => allowed.contains(&cmd),
+            RawFilter::Deny(denied) => !denied.contains(&cmd),
+        }
+    }
+}
+
+/// How long to keep sending queued commands after shutting down the server.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+struct IrOptions {
+    /// Holds the spacing between frames, the power-on hack debounce and the
+    /// input coalescing window.
+    settings: SharedSettings,
+    /// Shared with the handlers, which reject power-on hacks while one runs.
+    power_on: PowerOnHacks,
+    /// How long the serial link may sit idle before it's pinged, so that an
+    /// unplugged device is noticed before the next command. `None` disables
+    /// the pings.
+    heartbeat: Option<Duration>,
+}
+
+/// Reads what the firmware sends between commands, never completes in dry run.
+async fn read_idle(link: Option<&mut impl IrLink>) -> std::io::Result<String> {
+    match link {
+        Some(link) => link.read_line().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Completes at `deadline`, or never when there is none.
+async fn sleep_until(deadline: Option<time::Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Transmits `frame`, sending it again as many times as configured while the
+/// firmware rejects it. Frames that went unacknowledged are not retried, as
+/// they may have been transmitted already.
+async fn transmit_with_retries(
+    link: &mut impl IrLink,
+    kind: &'static str,
+    frame: &[u8],
+    settings: &SharedSettings,
+    metrics: &Metrics,
+) -> anyhow::Result<CommandResult> {
+    let (mut retries, delay) = settings.get(|s| (s.reject_retries, s.reject_retry_delay));
+    loop {
+        match link.transmit(kind, frame).await? {
+            Err(TransmitError::Rejected(ack)) if retries > 0 => {
+                warn!(command = kind, ack, retries, "Retrying rejected command");
+                metrics.frame_retried();
+                retries -= 1;
+                time::sleep(delay).await;
+            }
+            result => return Ok(result.map_err(|e| e.to_string())),
+        }
+    }
+}
+
+/// Time between NEC repeat codes. The firmware doesn't take the next one
+/// earlier either.
+const NEC_REPEAT_PERIOD: Duration = Duration::from_millis(108);
+
+/// What the IR task transmits in one go.
+enum Frame {
+    /// A command for the NEC device at the address
+    Nec(InfraredCommand, NecAddress),
+    /// Mark and space durations, see [`send_pulses`]
+    Pulses(Vec<u16>),
+    /// A lone NEC repeat code
+    NecRepeat,
+}
+
+impl Frame {
+    fn kind(&self) -> &'static str {
+        match self {
+            Frame::Nec(cmd, _) => command_kind(cmd),
+            Frame::Pulses(_) => "pulses",
+            Frame::NecRepeat => "nec_repeat",
+        }
+    }
+
+    /// The frame as written to the firmware.
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Frame::Nec(cmd, address) => wire::nec(cmd.encode(*address)).to_vec(),
+            Frame::Pulses(pulses) => wire::pulses(pulses).expect("pulse count checked when queued"),
+            Frame::NecRepeat => wire::NEC_REPEAT.to_vec(),
+        }
+    }
+
+    /// How long the firmware is busy transmitting after acknowledging the
+    /// frame. It acknowledges pulse sequences up front, and doesn't read the
+    /// next command until it's done.
+    fn transmit_time(&self) -> Duration {
+        match self {
+            Frame::Nec(..) | Frame::NecRepeat => Duration::ZERO,
+            Frame::Pulses(pulses) => pulses_duration(pulses),
+        }
+    }
+}
+
+/// Transmits the queued commands over `link` until all senders are gone and
+/// the queue is drained, or until `abort` fires. Without a link, in dry run,
+/// the frames are only logged. Clearing the queue is only done between
+/// commands, so one being transmitted is always finished.
+async fn ir_task<L: IrLink>(
+    mut link: Option<L>,
+    mut queue: CommandQueue,
+    metrics: Metrics,
+    events: Events,
+    status: watch::Sender<DeviceStatus>,
+    options: IrOptions,
+    abort: CancellationToken,
+) -> anyhow::Result<()> {
+    let mut next_frame_at = time::Instant::now();
+    // Accounts for a frame sent as part of the command of `kind`
+    let record = |kind, frame: &Frame, result: &CommandResult| {
+        if result.is_err() {
+            metrics.command_failed();
+        }
+        match frame {
+            Frame::Nec(cmd, address) => {
+                if result.is_ok() {
+                    let after_power = options.settings.get(|s| s.input_after_power_toggle);
+                    status.send_modify(|status| status.command_sent(*cmd, after_power));
+                }
+                events.command_sent(kind, SentFrame::new(*cmd, *address), result);
+            }
+            Frame::Pulses(pulses) => {
+                if result.is_ok() {
+                    status.send_modify(|status| status.last_command_at = Some(unix_millis()));
+                }
+                let frame = EventFrame::Pulses {
+                    pulses: pulses.len(),
+                    duration_us: pulses_duration(pulses).as_micros() as u64,
+                };
+                events.command_sent(kind, frame, result);
+            }
+            Frame::NecRepeat => {
+                if result.is_ok() {
+                    status.send_modify(|status| status.last_command_at = Some(unix_millis()));
+                }
+                events.command_sent(kind, EventFrame::NEC_REPEAT, result);
+            }
+        }
+    };
+    let mut ir = async |link: &mut Option<L>, frame: Frame| -> anyhow::Result<CommandResult> {
+        let kind = frame.kind();
+        time::sleep_until(next_frame_at).await;
+        next_frame_at = time::Instant::now() + options.settings.get(|s| s.frame_spacing);
+        let result = match link {
+            Some(link) => {
+                let result =
+                    transmit_with_retries(link, kind, &frame.encode(), &options.settings, &metrics)
+                        .await?;
+                time::sleep(frame.transmit_time() + options.settings.get(|s| s.write_delay)).await;
+                result
+            }
+            None => {
+                match &frame {
+                    Frame::Nec(cmd, address) => {
+                        let frame = frame_hex(*cmd, *address);
+                        info!(command = kind, frame, "Dry run, not sending command");
+                    }
+                    Frame::Pulses(pulses) => {
+                        let pulses = pulses.len();
+                        info!(command = kind, pulses, "Dry run, not sending command");
+                    }
+                    Frame::NecRepeat => info!(command = kind, "Dry run, not sending command"),
+                }
+                Ok(())
+            }
+        };
+        record(kind, &frame, &result);
+        Ok(result)
+    };
+
+    // Nothing to ping in dry run
+    let heartbeat = options.heartbeat.filter(|_| link.is_some());
+    let mut next_ping = heartbeat.map(|d| time::Instant::now() + d);
+    // The input selected by the previous command, if that's what it did, and
+    // when it was sent
+    let mut last_input: Option<(_, time::Instant)> = None;
+    loop {
+        let cmd = tokio::select! {
+            cmd = queue.commands.recv() => {
+                // Asked for while the previous command was transmitted, so
+                // it goes before this one
+                if let Ok(reply) = queue.clear.try_recv() {
+                    queue.discard_all(cmd, reply);
+                    continue;
+                }
+                cmd
+            }
+            Some(reply) = queue.clear.recv() => {
+                queue.discard_all(None, reply);
+                continue;
+            }
+            () = sleep_until(next_ping) => {
+                if let Some(link) = &mut link
+                    && let Err(e) = link.ping().await
+                {
+                    warn!(error = format!("{e:#}"), "Serial heartbeat failed, reopening");
+                    link.reopen().await?;
+                }
+                next_ping = heartbeat.map(|d| time::Instant::now() + d);
+                continue;
+            }
+            line = read_idle(link.as_mut()) => {
+                match line {
+                    Ok(line) => match line.strip_prefix("RX ") {
+                        Some(frame) => frame_received(frame, &events),
+                        None => warn!(line, "Unexpected line from firmware"),
+                    },
+                    Err(e) => {
+                        error!(error = ?e, "Failed to read from serial, reopening");
+                        if let Some(link) = &mut link {
+                            link.reopen().await?;
+                        }
+                    }
+                }
+                continue;
+            }
+            () = abort.cancelled() => {
+                if !queue.commands.is_empty() {
+                    warn!(dropped = queue.commands.len(), "Dropping queued commands");
+                }
+                return Ok(());
+            }
+        };
+        let Some(QueuedCommand {
+            command,
+            reply,
+            queued_at,
+        }) = cmd
+        else {
+            // All senders died and the queue is empty, we're done here
+            return Ok(());
+        };
+        metrics.command_dequeued(queued_at.elapsed());
+        let input = match command {
+            UserCommand::Direct(InfraredCommand::SetInput(input), address)
+            | UserCommand::SelectInput(input, address) => Some((input, address)),
+            _ => None,
+        };
+        let coalesce = options.settings.get(|s| s.input_coalesce);
+        // Not after a power toggle that may have changed the input since
+        let coalesced = matches!(command, UserCommand::Direct(..))
+            && input.is_some_and(|input| {
+                last_input.is_some_and(|(last, at)| last == input && at.elapsed() < coalesce)
+                    && status.borrow().last_input == Some(input.0)
+            });
+        // Commands made of several frames report the first failure
+        let result = match command {
+            UserCommand::Direct(..) if coalesced => {
+                // Most likely impatient clicking
+                info!(
+                    input = input.map(|(input, _)| input.as_str()),
+                    "Input selected moments ago, skipping"
+                );
+                Ok(())
+            }
+            UserCommand::Direct(InfraredCommand::SetInput(input), address)
+            | UserCommand::SelectInput(input, address) => {
+                // Some inputs need their code more than once to register
+                let count = options.settings.get(|s| s.input_repeats(input));
+                let cmd = InfraredCommand::SetInput(input);
+                let mut result = Ok(());
+                for i in 0..count {
+                    if i > 0 {
+                        time::sleep(DEFAULT_REPEAT_GAP).await;
+                    }
+                    result = result.and(ir(&mut link, Frame::Nec(cmd, address)).await?);
+                }
+                result
+            }
+            UserCommand::Direct(v, address) => ir(&mut link, Frame::Nec(v, address)).await?,
+            UserCommand::PowerOnHack { reset: None, .. }
+                if options
+                    .power_on
+                    .finished_within(options.settings.get(|s| s.power_on_debounce)) =>
+            {
+                // Queued behind another one, most likely by a double click
+                info!("Power-on hack finished moments ago, skipping this one");
+                Ok(())
+            }
+            UserCommand::PowerOnHack {
+                gap,
+                settle,
+                address,
+                reset,
+                claim,
+            } => {
+                options.power_on.set_running(true);
+                let toggles = async {
+                    let toggle = || Frame::Nec(InfraredCommand::TogglePower, address);
+                    let off = match reset {
+                        Some(wait) => {
+                            let off = ir(&mut link, toggle()).await?;
+                            time::sleep(wait).await;
+                            off
+                        }
+                        None => Ok(()),
+                    };
+                    let first = ir(&mut link, toggle()).await?;
+                    time::sleep(gap).await;
+                    let second = ir(&mut link, toggle()).await?;
+                    if !settle.is_zero() {
+                        time::sleep(settle).await;
+                    }
+                    anyhow::Ok(off.and(first).and(second))
+                }
+                .await;
+                options.power_on.set_running(false);
+                drop(claim);
+                let result = toggles?;
+                // Whatever state the device was in, it's on now, unless a
+                // toggle went missing
+                status.send_modify(|status| status.power = result.is_ok().then_some(true));
+                result
+            }
+            UserCommand::Repeat {
+                cmd,
+                address,
+                count,
+                gap,
+            } => {
+                let mut result = Ok(());
+                for i in 0..count {
+                    if i > 0 {
+                        time::sleep(gap).await;
+                    }
+                    result = result.and(ir(&mut link, Frame::Nec(cmd, address)).await?);
+                }
+                result
+            }
+            UserCommand::Delay(d) => {
+                time::sleep(d).await;
+                Ok(())
+            }
+            UserCommand::Pulses(pulses) => ir(&mut link, Frame::Pulses(pulses)).await?,
+            UserCommand::NecRepeat(count) => {
+                let mut result = Ok(());
+                for _ in 0..count {
+                    result = result.and(ir(&mut link, Frame::NecRepeat).await?);
+                }
+                result
+            }
+            UserCommand::Ping(reply) => {
+                let round_trip = match &mut link {
+                    Some(link) => {
+                        let started = time::Instant::now();
+                        link.ping().await.map(|()| started.elapsed())
+                    }
+                    None => Err(anyhow::anyhow!("Dry run, there is no firmware to ping")),
+                }
+                .map_err(|e| format!("{e:#}"));
+                let _ = reply.send(round_trip.clone());
+                if let Err(e) = &round_trip
+                    && let Some(link) = &mut link
+                {
+                    // Like a failed heartbeat
+                    warn!(error = e, "Ping failed, reopening");
+                    link.reopen().await?;
+                }
+                round_trip.map(|_| ())
+            }
+            UserCommand::Hold {
+                cmd,
+                address,
+                release,
+            } => {
+                let mut result = ir(&mut link, Frame::Nec(cmd, address)).await?;
+                let until = time::Instant::now() + MAX_HOLD;
+                let mut next_repeat = time::Instant::now() + NEC_REPEAT_PERIOD;
+                while result.is_ok() && !release.is_cancelled() && !abort.is_cancelled() {
+                    if time::Instant::now() >= until {
+                        warn!("Button held for too long, releasing it");
+                        break;
+                    }
+                    tokio::select! {
+                        () = time::sleep_until(next_repeat) => {}
+                        () = release.cancelled() => break,
+                        () = abort.cancelled() => break,
+                    }
+                    next_repeat += NEC_REPEAT_PERIOD;
+                    // Sent directly rather than by `ir`, which would space
+                    // them out further than the NEC repeat period
+                    result = match &mut link {
+                        Some(link) => link
+                            .transmit("hold", &wire::NEC_REPEAT)
+                            .await?
+                            .map_err(|e| e.to_string()),
+                        None => Ok(()),
+                    };
+                    record("hold", &Frame::NecRepeat, &result);
+                }
+                release.cancel();
+                result
+            }
+        };
+        if !coalesced {
+            // The window starts at the frame actually sent, and only one the
+            // firmware took can stand in for later ones
+            last_input = input
+                .filter(|_| result.is_ok())
+                .map(|input| (input, time::Instant::now()));
+        }
+        if let Some(reply) = reply {
+            // The handler may have given up waiting
+            let _ = reply.send(result);
+        }
+        next_ping = heartbeat.map(|d| time::Instant::now() + d);
+    }
+}
+
+#[derive(Clone, Debug, Bpaf)]
+#[bpaf(options)]
+struct CmdArgs {
+    /// TOML config file, see config.example.toml
+    #[bpaf(long, env("PICO_IR_CONFIG"), argument("PATH"))]
+    config: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = cmd_args().run();
+    let config = Config::load(args.config.as_deref())?;
+
+    match config.log_format {
+        LogFormat::Json => tracing_subscriber::fmt().json().init(),
+        LogFormat::Text => tracing_subscriber::fmt::init(),
+    }
+
+    if config.dry_run {
+        warn!("Dry run, commands will not be sent to the device");
+    }
+    let devices = config.devices();
+
+    let cancel_token = CancellationToken::new();
+    let drain_abort = CancellationToken::new();
+    let mut ir_tasks = JoinSet::new();
+    let mut settings = Vec::new();
+    let mut routes = Route::new()
+        .at("/devices", poem::get(get_devices))
+        .at("/admin/shutdown", poem::post(post_admin_shutdown));
+    for (i, device) in devices.iter().enumerate() {
+        let (handles, queue, status_tx) = DeviceHandles::new(&config, device);
+        settings.push((device.name.clone(), handles.sender.settings.clone()));
+        if i == 0 {
+            let device_routes = device_routes();
+            #[cfg(feature = "openapi")]
+            let device_routes = openapi::mount(device_routes);
+            routes = routes.nest("/", handles.attach(device_routes));
+        }
+        routes = routes.nest(
+            format!("/device/{}", device.name),
+            handles.attach(device_routes()),
+        );
+
+        let options = IrOptions {
+            settings: handles.sender.settings.clone(),
+            power_on: handles.sender.power_on.clone(),
+            heartbeat: config.heartbeat(),
+        };
+        let serial = (!config.dry_run).then(|| (device.serial.clone(), config.baud));
+        let backoff = config.serial_open_backoff();
+        let DeviceHandles {
+            state,
+            metrics,
+            events,
+            ..
+        } = handles;
+        let cancel_token_ir = cancel_token.clone();
+        let drain_abort_ir = drain_abort.clone();
+        let client_id = format!("pico-ir-api-{}", device.name);
+        let ir = async move {
+            let result = async {
+                let link = match serial {
+                    Some((path, baud)) => Some(match MqttTarget::parse(&path) {
+                        Some(target) => {
+                            DeviceLink::Mqtt(MqttLink::connect(target?, client_id, state)?)
+                        }
+                        None => DeviceLink::Serial(Box::new(
+                            SerialLink::open(
+                                path,
+                                baud,
+                                backoff,
+                                state,
+                                metrics.clone(),
+                                events.clone(),
+                            )
+                            .await?,
+                        )),
+                    }),
+                    None => None,
+                };
+                ir_task(
+                    link,
+                    queue,
+                    metrics,
+                    events,
+                    status_tx,
+                    options,
+                    drain_abort_ir,
+                )
+                .await
+            };
+            if let Err(e) = result.await {
+                error!(error = format!("{e:#}"), "IR Task died, cleaning up");
+                cancel_token_ir.cancel();
+            }
+        };
+        ir_tasks.spawn(ir.instrument(info_span!("device", name = device.name)));
+    }
+    let app = routes
+        .data(DeviceNames(devices.into_iter().map(|d| d.name).collect()))
+        .data(AdminShutdown {
+            enabled: config.token.is_some(),
+            cancel_token: cancel_token.clone(),
+        })
+        .with(Idempotency::new(config.idempotency_window()))
+        .catch_all_error(json_errors)
+        .with(BearerAuth::new(config.token.clone()));
+    let acceptor = make_acceptor(&config).await?;
+    tokio::spawn(reload_on_sighup(args.config, config, settings));
+
+    Server::new_with_acceptor(acceptor)
+        .run_with_graceful_shutdown(app, shutdown_signal(cancel_token), None)
+        .await?;
+
+    // The server is gone and with it all the senders, so the IR tasks exit
+    // once they have sent what's left in their queues.
+    info!("Draining command queues");
+    let mut join_all = async || while ir_tasks.join_next().await.is_some() {};
+    if time::timeout(DRAIN_TIMEOUT, join_all()).await.is_err() {
+        drain_abort.cancel();
+        // Let the commands in flight finish, but don't hang on them forever
+        if time::timeout(DRAIN_TIMEOUT, join_all()).await.is_err() {
+            warn!("IR tasks did not stop, exiting anyway");
+        }
+    }
+    Ok(())
+}
+
+/// Reloads the config on every SIGHUP and applies the settings of each device
+/// that can change at runtime. The others are left as `config` has them.
+async fn reload_on_sighup(
+    path: Option<PathBuf>,
+    config: Config,
+    settings: Vec<(String, SharedSettings)>,
+) {
+    let mut sighup =
+        signal::unix::signal(SignalKind::hangup()).expect("failed to install SIGHUP handler");
+    while sighup.recv().await.is_some() {
+        let new = match Config::load(path.as_deref()) {
+            Ok(new) => new,
+            Err(e) => {
+                error!(error = format!("{e:#}"), "Invalid config, not reloading");
+                continue;
+            }
+        };
+        for setting in config.fixed_changes(&new) {
+            warn!(setting, "Setting can't change without a restart, ignoring");
+        }
+        let devices = new.devices();
+        for (name, settings) in &settings {
+            if let Some(device) = devices.iter().find(|d| &d.name == name) {
+                settings.set(DeviceSettings::new(&new, device));
+            }
+        }
+        info!("Reloaded config");
+    }
+}
+
+/// Completes on SIGINT or SIGTERM, or when `cancel_token` is cancelled.
+async fn shutdown_signal(cancel_token: CancellationToken) {
+    let mut sigterm =
+        signal::unix::signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+        _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+        () = cancel_token.cancelled() => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use poem::{
+        endpoint::BoxEndpoint,
+        test::{TestClient, TestResponse},
+    };
+
+    use super::*;
+
+    type Frames = Arc<Mutex<Vec<Vec<u8>>>>;
+
+    /// Records the frames instead of sending them anywhere, pings included,
+    /// unless the firmware is made unreachable.
+    struct MockLink {
+        frames: Frames,
+        unreachable: Arc<AtomicBool>,
+    }
+
+    impl IrLink for MockLink {
+        async fn transmit(
+            &mut self,
+            _kind: &'static str,
+            frame: &[u8],
+        ) -> anyhow::Result<Result<(), TransmitError>> {
+            if self.unreachable.load(Ordering::Relaxed) {
+                return Ok(Err(TransmitError::Unwritten("unreachable".into())));
+            }
+            self.frames.lock().unwrap().push(frame.to_vec());
+            Ok(Ok(()))
+        }
+
+        async fn ping(&mut self) -> anyhow::Result<()> {
+            anyhow::ensure!(
+                !self.unreachable.load(Ordering::Relaxed),
+                "The firmware is unreachable"
+            );
+            self.frames.lock().unwrap().push(wire::PING.to_vec());
+            Ok(())
+        }
+
+        async fn read_line(&mut self) -> std::io::Result<String> {
+            std::future::pending().await
+        }
+
+        async fn reopen(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A device with its IR task transmitting to a [`MockLink`], the frames
+    /// it records, and a client of its routes.
+    struct TestDevice {
+        handles: DeviceHandles,
+        frames: Frames,
+        /// Makes the [`MockLink`] fail while set.
+        unreachable: Arc<AtomicBool>,
+        client: TestClient<BoxEndpoint<'static>>,
+    }
+
+    impl TestDevice {
+        fn spawn() -> Self {
+            let config = Config::default();
+            let (handles, queue, status_tx) = DeviceHandles::new(&config, &config.devices()[0]);
+            let frames = Frames::default();
+            let unreachable = Arc::<AtomicBool>::default();
+            let options = IrOptions {
+                settings: handles.sender.settings.clone(),
+                power_on: handles.sender.power_on.clone(),
+                heartbeat: None,
+            };
+            tokio::spawn(ir_task(
+                Some(MockLink {
+                    frames: Arc::clone(&frames),
+                    unreachable: Arc::clone(&unreachable),
+                }),
+                queue,
+                handles.metrics.clone(),
+                handles.events.clone(),
+                status_tx,
+                options,
+                CancellationToken::new(),
+            ));
+            let client = TestClient::new(handles.attach(device_routes()).map_to_response().boxed());
+            TestDevice {
+                handles,
+                frames,
+                unreachable,
+                client,
+            }
+        }
+
+        /// Replaces the settings of the device with the ones of `config`.
+        fn configure(&self, config: &Config) {
+            self.handles
+                .sender
+                .settings
+                .set(DeviceSettings::new(config, &config.devices()[0]));
+        }
+
+        /// Posts to `path`, waiting for the command to be transmitted, and
+        /// checks that it was.
+        async fn send(&self, path: &str) -> TestResponse {
+            self.send_query(path, &[]).await
+        }
+
+        /// Like [`Self::send`], with numeric query parameters.
+        async fn send_query(&self, path: &str, query: &[(&str, u64)]) -> TestResponse {
+            let request = query
+                .iter()
+                .fold(self.client.post(path), |req, (name, value)| {
+                    req.query(*name, value)
+                });
+            let resp = request.query("wait", &true).send().await;
+            resp.assert_status_is_ok();
+            resp
+        }
+
+        /// The frames transmitted so far.
+        fn sent(&self) -> Vec<Vec<u8>> {
+            self.frames.lock().unwrap().clone()
+        }
+    }
+
+    /// The frame of `cmd` to the configured address.
+    fn nec(cmd: InfraredCommand) -> [u8; 5] {
+        wire::nec(cmd.as_u32_le())
+    }
+
+    fn input_frame(input: AudioInput) -> [u8; 5] {
+        nec(InfraredCommand::SetInput(input))
+    }
+
+    /// Checks that `resp` is an error of `status` with the `code` in its body.
+    async fn assert_error(resp: TestResponse, status: StatusCode, code: &str) {
+        resp.assert_status(status);
+        resp.assert_content_type("application/json; charset=utf-8");
+        resp.json()
+            .await
+            .value()
+            .object()
+            .get("error")
+            .assert_string(code);
+    }
+
+    #[tokio::test]
+    async fn toggle_power_transmits_frame() {
+        let device = TestDevice::spawn();
+
+        device.send("/toggle-power").await;
+
+        assert_eq!(device.sent(), [nec(InfraredCommand::TogglePower)]);
+    }
+
+    #[tokio::test]
+    async fn pulses_transmitted_as_given() {
+        let device = TestDevice::spawn();
+
+        let resp = device
+            .client
+            .post("/pulses")
+            .query("wait", &true)
+            .body_json(&[560, 1690, 560])
+            .send()
+            .await;
+
+        resp.assert_status_is_ok();
+        resp.assert_json(serde_json::json!({"pulses": 3, "duration_us": 2810}))
+            .await;
+        assert_eq!(device.sent(), [wire::pulses(&[560, 1690, 560]).unwrap()]);
+        let history = device.client.get("/history").send().await;
+        let history = history.json().await;
+        let event = history.value().array().get(0).object();
+        event.get("type").assert_string("pulses");
+        event.get("pulses").assert_i64(3);
+        event.get("duration_us").assert_i64(2810);
+        event.get("success").assert_bool(true);
+
+        let resp = device.client.post("/pulses").body_json(&[0]).send().await;
+        resp.assert_status(StatusCode::BAD_REQUEST);
+        resp.assert_json(serde_json::json!({
+            "error": "invalid_pulses",
+            "detail": "pulses must be between 1 and 65535 microseconds",
+        }))
+        .await;
+    }
+
+    #[tokio::test]
+    async fn held_button_repeats_until_stopped() {
+        let device = TestDevice::spawn();
+        let hold = |cmd| device.client.post("/hold/start").query("cmd", &cmd).send();
+
+        hold("0xa8").await.assert_status_is_ok();
+        assert_error(hold("0xb8").await, StatusCode::CONFLICT, "hold_in_progress").await;
+        time::sleep(NEC_REPEAT_PERIOD * 3).await;
+        let resp = device.client.post("/hold/stop").send().await;
+        resp.assert_json(serde_json::json!({"released": true}))
+            .await;
+        // Queued behind the hold, so sent once it was released
+        device.send("/mute").await;
+
+        let frames = device.sent();
+        assert_eq!(frames[0], nec(InfraredCommand::Raw(0xa8)));
+        assert_eq!(frames.last().unwrap(), &nec(InfraredCommand::Mute));
+        let repeats = &frames[1..frames.len() - 1];
+        assert!(!repeats.is_empty());
+        assert!(repeats.iter().all(|f| f[..] == wire::NEC_REPEAT));
+        let history = device.client.get("/history").send().await;
+        let history = history.json().await;
+        let history = history.value().array();
+        history.assert_len(frames.len());
+        history
+            .get(0)
+            .object()
+            .get("scancode")
+            .assert_string("0xa8");
+        for i in 1..frames.len() - 1 {
+            let event = history.get(i).object();
+            event.get("type").assert_string("hold");
+            event.get("repeat").assert_bool(true);
+        }
+    }
+
+    #[tokio::test]
+    async fn queued_power_on_hack_rejects_another() {
+        let device = TestDevice::spawn();
+        device.configure(&Config {
+            power_on_gap_ms: 0,
+            power_on_settle_ms: 0,
+            ..Config::default()
+        });
+        let power_on = || device.client.post("/power-on-hack").send();
+
+        // Keeps the power-on hacks in the queue
+        let resp = device
+            .client
+            .post("/hold/start")
+            .query("cmd", &"0xa8")
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        power_on().await.assert_status_is_ok();
+        let resp = power_on().await;
+        assert_error(resp, StatusCode::CONFLICT, "power_on_in_progress").await;
+
+        device
+            .client
+            .post("/hold/stop")
+            .send()
+            .await
+            .assert_status_is_ok();
+        // Queued behind the first one, which is done by the time this is sent
+        device.send("/mute").await;
+
+        let resp = device.send("/power-on-hack").await;
+        resp.json()
+            .await
+            .value()
+            .object()
+            .get("skipped")
+            .assert_bool(true);
+        let toggles = device
+            .sent()
+            .iter()
+            .filter(|f| **f == nec(InfraredCommand::TogglePower))
+            .count();
+        assert_eq!(toggles, 2);
+    }
+
+    #[tokio::test]
+    async fn retried_toggle_is_not_sent_again() {
+        let device = TestDevice::spawn();
+        let app = device
+            .handles
+            .attach(device_routes())
+            .with(Idempotency::new(Duration::from_secs(60)));
+        let client = TestClient::new(app);
+        let toggle = |key| {
+            client
+                .post("/toggle-power")
+                .header("Idempotency-Key", key)
+                .query("wait", &true)
+                .send()
+        };
+
+        toggle("a").await.assert_status_is_ok();
+        let retry = toggle("a").await;
+        retry.assert_status_is_ok();
+        retry.assert_header("Idempotent-Replayed", "true");
+        assert_eq!(device.sent().len(), 1);
+
+        toggle("b").await.assert_status_is_ok();
+        assert_eq!(device.sent().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn power_toggle_forgets_input() {
+        let device = TestDevice::spawn();
+        let status = async || device.client.get("/status").send().await.json().await;
+
+        device.send("/input/optical").await;
+        status()
+            .await
+            .value()
+            .object()
+            .get("last_input")
+            .assert_string("optical");
+        device.send("/toggle-power").await;
+        status()
+            .await
+            .value()
+            .object()
+            .get("last_input")
+            .assert_null();
+    }
+
+    #[tokio::test]
+    async fn input_repeats_resend_the_code() {
+        let device = TestDevice::spawn();
+        device.configure(&Config {
+            input_repeats: HashMap::from([("bluetooth".into(), 2)]),
+            ..Config::default()
+        });
+
+        device.send("/input/bluetooth").await;
+        device.send("/input/optical").await;
+
+        let bluetooth = input_frame(AudioInput::Bluetooth);
+        let optical = input_frame(AudioInput::Optical);
+        assert_eq!(device.sent(), [bluetooth, bluetooth, optical]);
+    }
+
+    #[tokio::test]
+    async fn clearing_queue_discards_pending_commands() {
+        let device = TestDevice::spawn();
+
+        device
+            .client
+            .post("/command")
+            .body_json(&serde_json::json!([{ "type": "delay", "ms": 300 }]))
+            .send()
+            .await
+            .assert_status_is_ok();
+        // Let the IR task start on the delay, so that the next one waits
+        time::sleep(Duration::from_millis(50)).await;
+        let client = &device.client;
+        client.post("/volume-up").send().await.assert_status_is_ok();
+        let resp = client.post("/queue/clear").send().await;
+
+        resp.assert_status_is_ok();
+        resp.assert_json(serde_json::json!({"cleared": 1})).await;
+        assert!(device.sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn admin_shutdown_needs_token() {
+        let cancel_token = CancellationToken::new();
+        let app = |token: Option<&str>| {
+            let shutdown = AdminShutdown {
+                enabled: token.is_some(),
+                cancel_token: cancel_token.clone(),
+            };
+            TestClient::new(
+                Route::new()
+                    .at("/admin/shutdown", poem::post(post_admin_shutdown))
+                    .data(shutdown)
+                    .with(BearerAuth::new(token.map(Into::into))),
+            )
+        };
+
+        let open = app(None);
+        open.post("/admin/shutdown")
+            .send()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        let client = app(Some("secret"));
+        client
+            .post("/admin/shutdown")
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        assert!(!cancel_token.is_cancelled());
+        client
+            .post("/admin/shutdown")
+            .header("Authorization", "Bearer secret")
+            .send()
+            .await
+            .assert_status_is_ok();
+        assert!(cancel_token.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn repeat_sends_lone_repeat_codes() {
+        let device = TestDevice::spawn();
+
+        device.send_query("/repeat", &[("count", 2)]).await;
+
+        assert_eq!(device.sent(), [wire::NEC_REPEAT, wire::NEC_REPEAT]);
+        let history = device.client.get("/history").send().await;
+        let history = history.json().await;
+        let history = history.value().array();
+        history.assert_len(2);
+        for i in 0..2 {
+            let event = history.get(i).object();
+            event.get("type").assert_string("nec_repeat");
+            event.get("repeat").assert_bool(true);
+        }
+        let resp = device
+            .client
+            .post("/repeat")
+            .query("count", &0)
+            .send()
+            .await;
+        assert_error(resp, StatusCode::BAD_REQUEST, "invalid_count").await;
+    }
+
+    #[tokio::test]
+    async fn input_preview_sends_nothing() {
+        let device = TestDevice::spawn();
+
+        device.send("/input/optical").await;
+        let resp = device.client.get("/input/next/preview").send().await;
+
+        resp.assert_status_is_ok();
+        resp.json()
+            .await
+            .value()
+            .object()
+            .get("input")
+            .assert_string(AudioInput::Optical.next().as_str());
+        assert_eq!(device.sent().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn ping_goes_through_firmware() {
+        let device = TestDevice::spawn();
+
+        let resp = device.client.get("/ping").send().await;
+
+        resp.assert_status_is_ok();
+        resp.json()
+            .await
+            .value()
+            .object()
+            .get("round_trip_us")
+            .i64();
+        assert_eq!(device.sent(), [wire::PING]);
+
+        device.unreachable.store(true, Ordering::Relaxed);
+        let resp = device.client.get("/ping").send().await;
+        assert_error(resp, StatusCode::SERVICE_UNAVAILABLE, "ping_failed").await;
+    }
+
+    #[tokio::test]
+    async fn unknown_routes_answer_in_json() {
+        let device = TestDevice::spawn();
+        let app = device
+            .handles
+            .attach(device_routes())
+            .catch_all_error(json_errors);
+        let client = TestClient::new(app);
+
+        let resp = client.get("/nonexistent").send().await;
+        assert_error(resp, StatusCode::NOT_FOUND, "not_found").await;
+        let resp = client.get("/toggle-power").send().await;
+        assert_error(resp, StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed").await;
+    }
+
+    #[tokio::test]
+    async fn selftest_selects_every_input() {
+        let device = TestDevice::spawn();
+        device.configure(&Config {
+            input_coalesce_ms: 60_000,
+            ..Config::default()
+        });
+
+        let resp = device.send_query("/selftest", &[("dwell_ms", 0)]).await;
+        let body = resp.json().await;
+        let body = body.value().object();
+        body.get("frames").array().assert_len(AudioInput::ALL.len());
+        body.get("restored").assert_bool(false);
+
+        // Also the first one the self-test selects
+        let first = AudioInput::ALL[0];
+        device.send(&format!("/input/{}", first.as_str())).await;
+        device.frames.lock().unwrap().clear();
+        let resp = device.send_query("/selftest", &[("dwell_ms", 0)]).await;
+        resp.json()
+            .await
+            .value()
+            .object()
+            .get("restored")
+            .assert_bool(true);
+        // Not coalesced with the selection just before
+        let mut expected: Vec<_> = AudioInput::ALL.into_iter().map(input_frame).collect();
+        expected.push(input_frame(first));
+        assert_eq!(device.sent(), expected);
+    }
+
+    #[tokio::test]
+    async fn overlong_delays_are_rejected() {
+        let device = TestDevice::spawn();
+
+        let resp = device
+            .client
+            .post("/command")
+            .body_json(&serde_json::json!([
+                { "type": "mute" },
+                { "type": "delay", "ms": u64::MAX },
+            ]))
+            .send()
+            .await;
+
+        assert_error(resp, StatusCode::BAD_REQUEST, "invalid_step").await;
+        assert!(device.sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn overlong_gaps_are_rejected() {
+        let device = TestDevice::spawn();
+
+        for path in ["/volume-up", "/power-on-hack"] {
+            let resp = device
+                .client
+                .post(path)
+                .query("repeat", &2)
+                .query("gap_ms", &u64::MAX)
+                .send()
+                .await;
+
+            assert_error(resp, StatusCode::BAD_REQUEST, "invalid_gap").await;
+        }
+        assert!(device.sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn debug_frame_takes_hex_commands() {
+        let device = TestDevice::spawn();
+
+        let resp = device
+            .client
+            .get("/debug/frame")
+            .query("cmd", &"0x66")
+            .send()
+            .await;
+
+        resp.assert_status_is_ok();
+        resp.json()
+            .await
+            .value()
+            .object()
+            .get("frame")
+            .assert_string("66992385");
+        assert!(device.sent().is_empty());
+    }
 }
@@ -0,0 +1,130 @@
+//! Prometheus metrics exposed on `/metrics`.
+
+use std::{sync::Arc, time::Duration};
+
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text::encode},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{Histogram, exponential_buckets},
+    },
+    registry::{Registry, Unit},
+};
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CommandLabels {
+    r#type: &'static str,
+}
+
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Arc<Registry>,
+    commands: Family<CommandLabels, Counter>,
+    serial_reopens: Counter,
+    command_errors: Counter,
+    frame_retries: Counter,
+    serial_connected: Gauge,
+    serial_writes: Histogram,
+    queue_delays: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let commands = Family::<CommandLabels, Counter>::default();
+        let serial_reopens = Counter::default();
+        let command_errors = Counter::default();
+        let frame_retries = Counter::default();
+        let serial_connected = Gauge::default();
+        // 100µs up to about 1.6s
+        let serial_writes = Histogram::new(exponential_buckets(0.0001, 2.0, 15));
+        // 1ms up to about 33s, the queue may hold a few slow macros
+        let queue_delays = Histogram::new(exponential_buckets(0.001, 2.0, 16));
+
+        let mut registry = Registry::with_prefix("pico_ir");
+        registry.register(
+            "commands",
+            "Commands accepted into the queue",
+            commands.clone(),
+        );
+        registry.register(
+            "serial_reopens",
+            "Times the serial port was reopened after a write failure",
+            serial_reopens.clone(),
+        );
+        registry.register(
+            "command_errors",
+            "Commands that could not be queued or were not acknowledged",
+            command_errors.clone(),
+        );
+        registry.register(
+            "frame_retries",
+            "Frames sent again after the firmware rejected them",
+            frame_retries.clone(),
+        );
+        registry.register(
+            "serial_connected",
+            "Whether the serial port is currently open",
+            serial_connected.clone(),
+        );
+        registry.register_with_unit(
+            "serial_write",
+            "Time taken writing a frame to the serial port",
+            Unit::Seconds,
+            serial_writes.clone(),
+        );
+        registry.register_with_unit(
+            "command_queue_delay",
+            "Time commands spent in the queue before the IR task took them up",
+            Unit::Seconds,
+            queue_delays.clone(),
+        );
+
+        Metrics {
+            registry: Arc::new(registry),
+            commands,
+            serial_reopens,
+            command_errors,
+            frame_retries,
+            serial_connected,
+            serial_writes,
+            queue_delays,
+        }
+    }
+
+    pub fn command_queued(&self, kind: &'static str) {
+        self.commands
+            .get_or_create(&CommandLabels { r#type: kind })
+            .inc();
+    }
+
+    pub fn serial_reopened(&self) {
+        self.serial_reopens.inc();
+    }
+
+    pub fn command_failed(&self) {
+        self.command_errors.inc();
+    }
+
+    pub fn frame_retried(&self) {
+        self.frame_retries.inc();
+    }
+
+    pub fn serial_written(&self, took: Duration) {
+        self.serial_writes.observe(took.as_secs_f64());
+    }
+
+    pub fn command_dequeued(&self, waited: Duration) {
+        self.queue_delays.observe(waited.as_secs_f64());
+    }
+
+    /// Renders all metrics in the Prometheus text format. The connection
+    /// gauge is only sampled here, since nothing else needs it.
+    pub fn encode(&self, serial_connected: bool) -> String {
+        self.serial_connected.set(serial_connected.into());
+        let mut out = String::new();
+        encode(&mut out, &self.registry).expect("writing to a String cannot fail");
+        out
+    }
+}
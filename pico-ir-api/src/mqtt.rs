@@ -0,0 +1,489 @@
+//! Sending the commands of a device through `pico-ir-mqtt` running on the
+//! host the firmware is attached to, rather than over a local serial port.
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use pico_ir_proto::{AudioInput, InfraredCommand, codes, wire};
+use rumqttc::{
+    AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS, TlsConfiguration, Transport,
+};
+use serde::Deserialize;
+use tokio::{
+    sync::{mpsc, watch},
+    time::{self, Duration},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    SerialState,
+    link::{IrLink, TransmitError},
+};
+
+const DEFAULT_PORT: u16 = 1883;
+const DEFAULT_TLS_PORT: u16 = 8883;
+/// The default topic prefix of the bridge.
+const DEFAULT_TOPIC_PREFIX: &str = "jabu/pico-ir/";
+/// How long the bridge may take to report the outcome of a command. Longer
+/// than the firmware takes, as the bridge may be reopening its serial port.
+const RESULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often to warn that the bridge is still offline while waiting for it
+/// to come back.
+const OFFLINE_WARNING_INTERVAL: Duration = Duration::from_secs(600);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Where the bridge of a device is, given as
+/// `mqtt[s]://[<user>[:<password>]@]<host>[:<port>][/<topic prefix>][?ca=<path>]`
+/// in place of its serial port. `mqtts` connects over TLS, trusting the CA
+/// certificate in the PEM file at `ca`, or the platform's ones without it.
+/// The user and password are taken as they are, so they can't contain a `/`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MqttTarget {
+    host: String,
+    port: u16,
+    /// Ends with a `/`, like the bridge's own.
+    prefix: String,
+    /// The user and password to log in to the broker with.
+    credentials: Option<(String, String)>,
+    tls: Option<MqttTls>,
+}
+
+/// How the certificate of the broker is checked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum MqttTls {
+    PlatformRoots,
+    CaCert(PathBuf),
+}
+
+impl MqttTarget {
+    /// `None` when `serial` is a serial port rather than an MQTT URL.
+    pub fn parse(serial: &str) -> Option<anyhow::Result<Self>> {
+        let (url, tls) = match serial.strip_prefix("mqtts://") {
+            Some(url) => (url, true),
+            None => (serial.strip_prefix("mqtt://")?, false),
+        };
+        Some(Self::parse_url(url, tls).with_context(|| format!("Invalid MQTT URL '{serial}'")))
+    }
+
+    fn parse_url(url: &str, tls: bool) -> anyhow::Result<Self> {
+        let (url, ca) = match url.split_once('?') {
+            Some((url, query)) => {
+                let ca = query
+                    .strip_prefix("ca=")
+                    .context("Unknown query parameter")?;
+                anyhow::ensure!(tls, "A CA certificate is only used with mqtts");
+                anyhow::ensure!(!ca.is_empty(), "Missing CA certificate path");
+                (url, Some(PathBuf::from(ca)))
+            }
+            None => (url, None),
+        };
+        let (authority, prefix) = url.split_once('/').unwrap_or((url, ""));
+        let (credentials, authority) = match authority.rsplit_once('@') {
+            Some((user_info, authority)) => {
+                let (user, password) = user_info.split_once(':').unwrap_or((user_info, ""));
+                anyhow::ensure!(!user.is_empty(), "Missing user");
+                (Some((user.to_owned(), password.to_owned())), authority)
+            }
+            None => (None, authority),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().context("Invalid port")?),
+            None if tls => (authority, DEFAULT_TLS_PORT),
+            None => (authority, DEFAULT_PORT),
+        };
+        anyhow::ensure!(!host.is_empty(), "Missing host");
+        anyhow::ensure!(
+            !prefix.contains(['+', '#']),
+            "The topic prefix can't contain wildcards"
+        );
+        let prefix = match prefix.trim_end_matches('/') {
+            "" => DEFAULT_TOPIC_PREFIX.to_owned(),
+            prefix => format!("{prefix}/"),
+        };
+        let tls = tls.then(|| ca.map_or(MqttTls::PlatformRoots, MqttTls::CaCert));
+        Ok(MqttTarget {
+            host: host.to_owned(),
+            port,
+            prefix,
+            credentials,
+            tls,
+        })
+    }
+}
+
+/// What the bridge publishes to its result topic after each command.
+#[derive(Debug, Deserialize)]
+struct BridgeResult {
+    command: String,
+    success: bool,
+    /// How the command failed, missing from bridges that don't tell.
+    kind: Option<BridgeFailure>,
+    error: Option<String>,
+    /// What the firmware responded with, when it rejected the command.
+    response: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BridgeFailure {
+    Invalid,
+    Unwritten,
+    Unacknowledged,
+    Rejected,
+}
+
+/// Publishes commands to the topics the bridge subscribes to, and takes the
+/// results it publishes as the acknowledgements. The bridge only sends NEC
+/// commands to the speakers, so nothing else can be sent this way, and the
+/// firmware's IR receiver isn't reachable through it.
+pub struct MqttLink {
+    client: AsyncClient,
+    prefix: String,
+    /// Whether the bridge reports itself online.
+    online: watch::Receiver<bool>,
+    results: mpsc::Receiver<BridgeResult>,
+}
+
+impl MqttLink {
+    /// Connects to the broker in the background, marking `state` connected
+    /// while the bridge reports itself online.
+    pub fn connect(
+        target: MqttTarget,
+        client_id: String,
+        state: SerialState,
+    ) -> anyhow::Result<Self> {
+        let mut options = MqttOptions::new(client_id, target.host, target.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        match target.tls {
+            Some(MqttTls::CaCert(path)) => {
+                let ca = std::fs::read(&path)
+                    .with_context(|| format!("Could not read {}", path.display()))?;
+                options.set_transport(Transport::tls_with_config(TlsConfiguration::Simple {
+                    ca,
+                    alpn: None,
+                    client_auth: None,
+                }));
+            }
+            Some(MqttTls::PlatformRoots) => {
+                options.set_transport(Transport::tls_with_config(TlsConfiguration::default()));
+            }
+            None => {}
+        }
+        if let Some((user, password)) = target.credentials {
+            options.set_credentials(user, password);
+        }
+        let (client, events) = AsyncClient::new(options, 10);
+        let (online_tx, online) = watch::channel(false);
+        let (results_tx, results) = mpsc::channel(8);
+        tokio::spawn(run_event_loop(
+            events,
+            client.clone(),
+            target.prefix.clone(),
+            online_tx,
+            results_tx,
+            state,
+        ));
+        Ok(MqttLink {
+            client,
+            prefix: target.prefix,
+            online,
+            results,
+        })
+    }
+}
+
+/// Drives the connection to the broker until the link is dropped, following
+/// the bridge's status and passing on its results.
+async fn run_event_loop(
+    mut events: EventLoop,
+    client: AsyncClient,
+    prefix: String,
+    online: watch::Sender<bool>,
+    results: mpsc::Sender<BridgeResult>,
+    state: SerialState,
+) {
+    let status_topic = format!("{prefix}status");
+    let result_topic = format!("{prefix}result");
+    loop {
+        let event = tokio::select! {
+            event = events.poll() => event,
+            () = results.closed() => return,
+        };
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to the MQTT broker");
+                for topic in [&status_topic, &result_topic] {
+                    // Called from the event loop, which is what makes room in
+                    // the request queue, so this must not block
+                    if let Err(e) = client.try_subscribe(topic, QoS::AtLeastOnce) {
+                        warn!(topic, error = %e, "Failed to subscribe");
+                    }
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(msg))) if msg.topic == status_topic => {
+                let is_online = &msg.payload[..] == b"online";
+                info!(online = is_online, "MQTT bridge status changed");
+                state.set_connected(is_online);
+                online.send_replace(is_online);
+            }
+            Ok(Event::Incoming(Packet::Publish(msg))) if msg.topic == result_topic => {
+                match serde_json::from_slice(&msg.payload) {
+                    // Nobody waits for results of commands published by others
+                    Ok(result) => _ = results.try_send(result),
+                    Err(e) => warn!(error = %e, "Invalid result from the MQTT bridge"),
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!(error = %e, "MQTT connection failed, reconnecting");
+                state.set_connected(false);
+                online.send_replace(false);
+                time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+/// The topic, relative to the prefix, and payload the bridge transmits
+/// `frame` on, if it can.
+fn bridge_command(frame: &[u8]) -> Option<(&'static str, String)> {
+    let [wire::OP_NEC, value @ ..] = frame else {
+        return None;
+    };
+    let value = u32::from_le_bytes(value.try_into().ok()?);
+    let cmd = (value >> 24) as u8;
+    // Anything other than a command to the speakers, like another address
+    // or a raw frame, can't be sent by the bridge
+    if InfraredCommand::Raw(cmd).as_u32_le() != value {
+        return None;
+    }
+    let input = AudioInput::ALL
+        .into_iter()
+        .find(|&input| InfraredCommand::SetInput(input).as_u8() == cmd);
+    Some(match (cmd, input) {
+        (codes::TOGGLE_POWER, _) => ("power", String::new()),
+        (_, Some(input)) => ("input", input.as_str().to_owned()),
+        (_, None) => ("raw", format!("{cmd:02x}")),
+    })
+}
+
+/// How the command of a failed `result` went wrong. Without a `kind`, the
+/// command may have been transmitted or not.
+fn bridge_error(result: BridgeResult) -> TransmitError {
+    let error = result
+        .error
+        .unwrap_or_else(|| "the MQTT bridge failed".into());
+    match result.kind {
+        Some(BridgeFailure::Rejected) => TransmitError::Rejected(result.response.unwrap_or(error)),
+        Some(BridgeFailure::Invalid | BridgeFailure::Unwritten) => TransmitError::Unwritten(error),
+        Some(BridgeFailure::Unacknowledged) | None => TransmitError::Unacknowledged(error),
+    }
+}
+
+impl IrLink for MqttLink {
+    async fn transmit(
+        &mut self,
+        kind: &'static str,
+        frame: &[u8],
+    ) -> anyhow::Result<Result<(), TransmitError>> {
+        let Some((name, payload)) = bridge_command(frame) else {
+            return Ok(Err(TransmitError::Unsupported(format!(
+                "{kind} can't be sent through the MQTT bridge"
+            ))));
+        };
+        if !*self.online.borrow() {
+            return Ok(Err(TransmitError::Unwritten(
+                "the MQTT bridge is offline".into(),
+            )));
+        }
+        // Results of commands published by others in the meantime
+        while self.results.try_recv().is_ok() {}
+        debug!(command = kind, topic = name, "Publishing command");
+        let topic = format!("{}{name}", self.prefix);
+        if let Err(e) = self
+            .client
+            .publish(topic, QoS::AtLeastOnce, false, payload)
+            .await
+        {
+            return Ok(Err(TransmitError::Unwritten(e.to_string())));
+        }
+        let result = time::timeout(RESULT_TIMEOUT, async {
+            loop {
+                match self.results.recv().await {
+                    Some(result) if result.command == name => return Some(result),
+                    Some(_) => {}
+                    None => return None,
+                }
+            }
+        })
+        .await;
+        Ok(match result {
+            Ok(Some(BridgeResult { success: true, .. })) => Ok(()),
+            Ok(Some(result)) => Err(bridge_error(result)),
+            Ok(None) => anyhow::bail!("The MQTT event loop stopped"),
+            Err(_) => Err(TransmitError::Unacknowledged(
+                "timed out waiting for the MQTT bridge's result".into(),
+            )),
+        })
+    }
+
+    async fn ping(&mut self) -> anyhow::Result<()> {
+        anyhow::ensure!(*self.online.borrow(), "The MQTT bridge is offline");
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> std::io::Result<String> {
+        std::future::pending().await
+    }
+
+    /// Waits for the bridge to come back online, however long that takes,
+    /// like a serial port retried forever. The event loop reconnects to the
+    /// broker by itself in the meantime.
+    async fn reopen(&mut self) -> anyhow::Result<()> {
+        loop {
+            let online = self.online.wait_for(|online| *online);
+            match time::timeout(OFFLINE_WARNING_INTERVAL, online).await {
+                Ok(result) => {
+                    result.context("The MQTT event loop stopped")?;
+                    return Ok(());
+                }
+                Err(_) => warn!("The MQTT bridge is still offline"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pico_ir_proto::NecAddress;
+
+    fn target(serial: &str) -> MqttTarget {
+        MqttTarget::parse(serial).unwrap().unwrap()
+    }
+
+    #[test]
+    fn target_defaults() {
+        assert_eq!(
+            target("mqtt://broker"),
+            MqttTarget {
+                host: "broker".into(),
+                port: DEFAULT_PORT,
+                prefix: DEFAULT_TOPIC_PREFIX.into(),
+                credentials: None,
+                tls: None,
+            }
+        );
+        assert!(MqttTarget::parse("/dev/ttyACM0").is_none());
+    }
+
+    #[test]
+    fn target_ports_and_prefixes() {
+        let parsed = target("mqtt://broker:1884/living-room");
+        assert_eq!(parsed.port, 1884);
+        assert_eq!(parsed.prefix, "living-room/");
+        assert_eq!(target("mqtt://broker/a/b//").prefix, "a/b/");
+        assert_eq!(target("mqtt://broker/").prefix, DEFAULT_TOPIC_PREFIX);
+        for invalid in [
+            "mqtt://",
+            "mqtt://:1884",
+            "mqtt://broker:x",
+            "mqtt://broker:99999",
+        ] {
+            assert!(MqttTarget::parse(invalid).unwrap().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn target_rejects_wildcards() {
+        for invalid in ["mqtt://broker/a/+/b", "mqtt://broker/a/#"] {
+            assert!(MqttTarget::parse(invalid).unwrap().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn target_tls_and_credentials() {
+        let parsed = target("mqtts://user:p@ss:word@broker/prefix?ca=/etc/ca.pem");
+        assert_eq!(parsed.port, DEFAULT_TLS_PORT);
+        assert_eq!(parsed.prefix, "prefix/");
+        assert_eq!(
+            parsed.credentials,
+            Some(("user".into(), "p@ss:word".into()))
+        );
+        assert_eq!(parsed.tls, Some(MqttTls::CaCert("/etc/ca.pem".into())));
+        assert_eq!(target("mqtts://broker").tls, Some(MqttTls::PlatformRoots));
+        let parsed = target("mqtt://user@broker:1884");
+        assert_eq!(parsed.credentials, Some(("user".into(), String::new())));
+        assert_eq!(parsed.tls, None);
+        for invalid in [
+            "mqtt://broker?ca=/etc/ca.pem",
+            "mqtts://broker?ca=",
+            "mqtt://@broker",
+        ] {
+            assert!(MqttTarget::parse(invalid).unwrap().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn bridge_commands() {
+        let frame = |cmd: InfraredCommand, address| wire::nec(cmd.encode(address));
+        let speakers = NecAddress::DEFAULT;
+        assert_eq!(
+            bridge_command(&frame(InfraredCommand::TogglePower, speakers)),
+            Some(("power", String::new()))
+        );
+        for input in AudioInput::ALL {
+            assert_eq!(
+                bridge_command(&frame(InfraredCommand::SetInput(input), speakers)),
+                Some(("input", input.as_str().to_owned()))
+            );
+            // Raw codes of inputs are sent as the input they select
+            let raw = InfraredCommand::Raw(InfraredCommand::SetInput(input).as_u8());
+            assert_eq!(
+                bridge_command(&frame(raw, speakers)),
+                Some(("input", input.as_str().to_owned()))
+            );
+        }
+        assert_eq!(
+            bridge_command(&frame(InfraredCommand::Mute, speakers)),
+            Some(("raw", format!("{:02x}", InfraredCommand::Mute.as_u8())))
+        );
+        assert_eq!(
+            bridge_command(&frame(InfraredCommand::Raw(0xa8), speakers)),
+            Some(("raw", "a8".into()))
+        );
+    }
+
+    #[test]
+    fn bridge_commands_only_to_the_speakers() {
+        let other = NecAddress::from_hex("0x1234").unwrap();
+        let frame = wire::nec(InfraredCommand::TogglePower.encode(other));
+        assert_eq!(bridge_command(&frame), None);
+        assert_eq!(bridge_command(&wire::pulses(&[560, 560]).unwrap()), None);
+        assert_eq!(bridge_command(&wire::NEC_REPEAT), None);
+    }
+
+    #[test]
+    fn bridge_errors_by_kind() {
+        let error = |json| bridge_error(serde_json::from_value(json).unwrap());
+        let failed = |kind| {
+            error(serde_json::json!({
+                "command": "power",
+                "success": false,
+                "kind": kind,
+                "error": "failed",
+                "response": "ERR busy",
+            }))
+        };
+        assert!(matches!(failed("rejected"), TransmitError::Rejected(r) if r == "ERR busy"));
+        assert!(matches!(
+            failed("unacknowledged"),
+            TransmitError::Unacknowledged(_)
+        ));
+        assert!(matches!(failed("unwritten"), TransmitError::Unwritten(_)));
+        assert!(matches!(failed("invalid"), TransmitError::Unwritten(_)));
+        let unknown =
+            error(serde_json::json!({"command": "power", "success": false, "error": "failed"}));
+        assert!(matches!(unknown, TransmitError::Unacknowledged(e) if e == "failed"));
+    }
+}
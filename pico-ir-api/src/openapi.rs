@@ -0,0 +1,756 @@
+//! Documented variant of the HTTP API, built with the `openapi` feature.
+//!
+//! The same endpoints as the plain routes are served under `/api`, with the
+//! spec at `/openapi.json` and Swagger UI at `/docs`. The `/command` batch,
+//! the macros, `/capabilities`, `/events`, `/history`, `/metrics` and
+//! `/debug/frame` are only available on the plain routes.
+
+use pico_ir_proto::{AudioInput, InfraredCommand, NecAddress};
+use poem::{Route, http::StatusCode, web::Data};
+use poem_openapi::{
+    ApiResponse, Enum, Object, OpenApi, OpenApiService, Tags,
+    param::{Path, Query},
+    payload::Json,
+};
+use tokio::sync::watch;
+
+use crate::{
+    CommandSender, DeviceStatus, HealthResponse, HoldStopResponse, InputPreview, LearnedFrame,
+    NecRepeatResponse, PingResponse, PowerOnHackResponse, PowerResponse, PulsesResponse,
+    QueueClearResponse, QueueResponse, RepeatParams, ResetResponse, SelfTestResponse, SentFrame,
+    SerialHealth, SerialState, VersionResponse, clear_queue, cycled_input, events::Events,
+    json_error, learn, ping, raw_not_permitted, run_selftest, send_cycled_input, send_direct,
+    send_nec_repeat, send_power, send_power_on_hack, send_pulses, send_raw_frame, send_repeated,
+    send_reset, send_toggled_input, start_hold, stop_hold, toggled_input,
+};
+
+/// Adds the documented API, its spec and Swagger UI to `routes`.
+pub fn mount(routes: Route) -> Route {
+    let api = OpenApiService::new(Api, "Pico IR", env!("CARGO_PKG_VERSION")).server("/api");
+    let spec = api.spec_endpoint();
+    let ui = api.swagger_ui();
+    routes
+        .nest("/api", api)
+        .at("/openapi.json", spec)
+        .nest("/docs", ui)
+}
+
+#[derive(Tags)]
+enum ApiTags {
+    /// Transmitting commands to the device
+    Commands,
+    /// State of the server and the device
+    Status,
+}
+
+/// Mirrors [`AudioInput`], which can't implement the OpenAPI traits itself.
+#[derive(Clone, Copy, Enum)]
+enum Input {
+    #[oai(rename = "bluetooth")]
+    Bluetooth,
+    #[oai(rename = "3.5mm")]
+    _3_5mm,
+    #[oai(rename = "optical")]
+    Optical,
+    #[oai(rename = "rca")]
+    Rca,
+}
+
+impl From<Input> for AudioInput {
+    fn from(input: Input) -> Self {
+        match input {
+            Input::Bluetooth => AudioInput::Bluetooth,
+            Input::_3_5mm => AudioInput::_3_5mm,
+            Input::Optical => AudioInput::Optical,
+            Input::Rca => AudioInput::Rca,
+        }
+    }
+}
+
+impl From<AudioInput> for Input {
+    fn from(input: AudioInput) -> Self {
+        match input {
+            AudioInput::Bluetooth => Input::Bluetooth,
+            AudioInput::_3_5mm => Input::_3_5mm,
+            AudioInput::Optical => Input::Optical,
+            AudioInput::Rca => Input::Rca,
+        }
+    }
+}
+
+/// Same as [`DeviceStatus`], with the input as an OpenAPI enum.
+#[derive(Object)]
+struct Status {
+    /// Whether the device is on, known after a power-on hack and tracked
+    /// through the toggles since.
+    power: Option<bool>,
+    /// The input selected by the last successful input command, or the one
+    /// the device is expected to be on after a power toggle since.
+    last_input: Option<Input>,
+    /// When the last successful command of any kind was sent, in milliseconds
+    /// since the Unix epoch.
+    last_command_at: Option<u64>,
+}
+
+/// Same as [`InputPreview`], with the input as an OpenAPI enum.
+#[derive(Object)]
+struct InputPreviewResponse {
+    input: Input,
+    scancode: String,
+    frame: String,
+}
+
+impl From<InputPreview> for InputPreviewResponse {
+    fn from(preview: InputPreview) -> Self {
+        InputPreviewResponse {
+            input: preview.input.into(),
+            scancode: preview.frame.scancode,
+            frame: preview.frame.frame,
+        }
+    }
+}
+
+#[derive(ApiResponse)]
+enum HealthResult {
+    /// The serial port to the device is open
+    #[oai(status = 200)]
+    Connected(Json<HealthResponse>),
+    /// The serial port to the device is not open
+    #[oai(status = 503)]
+    Disconnected(Json<HealthResponse>),
+}
+
+struct InvalidAddress;
+
+impl From<InvalidAddress> for poem::Error {
+    fn from(_: InvalidAddress) -> Self {
+        json_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_address",
+            "invalid address",
+        )
+    }
+}
+
+fn parse_address(address: Option<String>) -> Result<Option<NecAddress>, InvalidAddress> {
+    address
+        .map(|a| NecAddress::from_hex(&a).ok_or(InvalidAddress))
+        .transpose()
+}
+
+struct Api;
+
+#[OpenApi]
+impl Api {
+    /// Toggle the power of the device
+    #[oai(path = "/toggle-power", method = "post", tag = "ApiTags::Commands")]
+    async fn toggle_power(
+        &self,
+        tx: Data<&CommandSender>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+        /// Respond only once the command was transmitted, failing when that
+        /// didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<SentFrame>> {
+        let address = parse_address(address.0)?;
+        send_direct(
+            &tx,
+            InfraredCommand::TogglePower,
+            address,
+            wait.0.unwrap_or(false),
+        )
+        .await
+        .map(Json)
+    }
+
+    /// Turn the device on
+    ///
+    /// Sends the power toggle twice, so that the device ends up on whatever
+    /// state it was in. Takes a few seconds longer when it already was on.
+    /// Rejected with 409 while another one is queued or runs, and skipped
+    /// right after one finished.
+    #[oai(path = "/power-on-hack", method = "post", tag = "ApiTags::Commands")]
+    async fn power_on_hack(
+        &self,
+        tx: Data<&CommandSender>,
+        /// How long to wait between the two toggles, in milliseconds
+        #[oai(validator(maximum(value = "10000")))]
+        gap_ms: Query<Option<u64>>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+        /// Respond only once the command was transmitted, failing when that
+        /// didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<PowerOnHackResponse>> {
+        let address = parse_address(address.0)?;
+        send_power_on_hack(&tx, gap_ms.0, address, wait.0.unwrap_or(false))
+            .await
+            .map(Json)
+    }
+
+    /// Turn the device off and on again
+    ///
+    /// Disruptive: whatever state the device was in, it's turned off and on
+    /// again, or on, off and on when it was off, which takes the power-on
+    /// debounce plus the power-on gap. Rejected with 409 while a power-on hack
+    /// runs.
+    #[oai(path = "/reset", method = "post", tag = "ApiTags::Commands")]
+    async fn reset(
+        &self,
+        tx: Data<&CommandSender>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+        /// Respond only once the command was transmitted, failing when that
+        /// didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<ResetResponse>> {
+        let address = parse_address(address.0)?;
+        send_reset(&tx, address, wait.0.unwrap_or(false))
+            .await
+            .map(Json)
+    }
+
+    /// Turn the device on if it's off
+    ///
+    /// Sends a single toggle when the device is known to be off, and nothing
+    /// when it's on. Responds with 409 while the power state is unknown, until
+    /// a power-on hack establishes it.
+    #[oai(path = "/power/on", method = "post", tag = "ApiTags::Commands")]
+    async fn power_on(
+        &self,
+        tx: Data<&CommandSender>,
+        status: Data<&watch::Receiver<DeviceStatus>>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+        /// Respond only once the command was transmitted, failing when that
+        /// didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<PowerResponse>> {
+        let address = parse_address(address.0)?;
+        send_power(&tx, &status, true, address, wait.0.unwrap_or(false))
+            .await
+            .map(Json)
+    }
+
+    /// Turn the device off if it's on
+    ///
+    /// Like `/power/on`, in the opposite direction.
+    #[oai(path = "/power/off", method = "post", tag = "ApiTags::Commands")]
+    async fn power_off(
+        &self,
+        tx: Data<&CommandSender>,
+        status: Data<&watch::Receiver<DeviceStatus>>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+        /// Respond only once the command was transmitted, failing when that
+        /// didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<PowerResponse>> {
+        let address = parse_address(address.0)?;
+        send_power(&tx, &status, false, address, wait.0.unwrap_or(false))
+            .await
+            .map(Json)
+    }
+
+    /// Turn the volume up
+    #[oai(path = "/volume-up", method = "post", tag = "ApiTags::Commands")]
+    async fn volume_up(
+        &self,
+        tx: Data<&CommandSender>,
+        /// How many times to send the command
+        #[oai(validator(minimum(value = "1"), maximum(value = "20")))]
+        repeat: Query<Option<u8>>,
+        /// Time between the repeated commands, in milliseconds
+        #[oai(validator(maximum(value = "10000")))]
+        gap_ms: Query<Option<u64>>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+        /// Respond only once the command was transmitted, failing when that
+        /// didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<SentFrame>> {
+        let params = RepeatParams {
+            address: parse_address(address.0)?,
+            repeat: repeat.0,
+            gap_ms: gap_ms.0,
+        };
+        send_repeated(
+            &tx,
+            InfraredCommand::VolumeUp,
+            &params,
+            wait.0.unwrap_or(false),
+        )
+        .await
+        .map(Json)
+    }
+
+    /// Turn the volume down
+    #[oai(path = "/volume-down", method = "post", tag = "ApiTags::Commands")]
+    async fn volume_down(
+        &self,
+        tx: Data<&CommandSender>,
+        /// How many times to send the command
+        #[oai(validator(minimum(value = "1"), maximum(value = "20")))]
+        repeat: Query<Option<u8>>,
+        /// Time between the repeated commands, in milliseconds
+        #[oai(validator(maximum(value = "10000")))]
+        gap_ms: Query<Option<u64>>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+        /// Respond only once the command was transmitted, failing when that
+        /// didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<SentFrame>> {
+        let params = RepeatParams {
+            address: parse_address(address.0)?,
+            repeat: repeat.0,
+            gap_ms: gap_ms.0,
+        };
+        send_repeated(
+            &tx,
+            InfraredCommand::VolumeDown,
+            &params,
+            wait.0.unwrap_or(false),
+        )
+        .await
+        .map(Json)
+    }
+
+    /// Toggle mute
+    #[oai(path = "/mute", method = "post", tag = "ApiTags::Commands")]
+    async fn mute(
+        &self,
+        tx: Data<&CommandSender>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+        /// Respond only once the command was transmitted, failing when that
+        /// didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<SentFrame>> {
+        let address = parse_address(address.0)?;
+        send_direct(&tx, InfraredCommand::Mute, address, wait.0.unwrap_or(false))
+            .await
+            .map(Json)
+    }
+
+    /// Select an audio input
+    #[oai(path = "/set-input", method = "post", tag = "ApiTags::Commands")]
+    async fn set_input(
+        &self,
+        tx: Data<&CommandSender>,
+        input: Query<Input>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+        /// Respond only once the command was transmitted, failing when that
+        /// didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<SentFrame>> {
+        let address = parse_address(address.0)?;
+        send_direct(
+            &tx,
+            InfraredCommand::SetInput(input.0.into()),
+            address,
+            wait.0.unwrap_or(false),
+        )
+        .await
+        .map(Json)
+    }
+
+    /// Select an audio input given in the path
+    #[oai(path = "/input/:name", method = "post", tag = "ApiTags::Commands")]
+    async fn input(
+        &self,
+        tx: Data<&CommandSender>,
+        name: Path<Input>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+        /// Respond only once the command was transmitted, failing when that
+        /// didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<SentFrame>> {
+        let address = parse_address(address.0)?;
+        send_direct(
+            &tx,
+            InfraredCommand::SetInput(name.0.into()),
+            address,
+            wait.0.unwrap_or(false),
+        )
+        .await
+        .map(Json)
+    }
+
+    /// Select the next audio input
+    ///
+    /// Cycles through the inputs starting from the last one selected through
+    /// this server, like the source button of the remote. Selects the first
+    /// input when none is known.
+    #[oai(path = "/input/next", method = "post", tag = "ApiTags::Commands")]
+    async fn input_next(
+        &self,
+        tx: Data<&CommandSender>,
+        status: Data<&watch::Receiver<DeviceStatus>>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+        /// Respond only once the command was transmitted, failing when that
+        /// didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<SentFrame>> {
+        let address = parse_address(address.0)?;
+        send_cycled_input(&tx, &status, true, address, wait.0.unwrap_or(false))
+            .await
+            .map(Json)
+    }
+
+    /// Select the previous audio input
+    ///
+    /// Like `/input/next`, in the opposite direction.
+    #[oai(path = "/input/prev", method = "post", tag = "ApiTags::Commands")]
+    async fn input_prev(
+        &self,
+        tx: Data<&CommandSender>,
+        status: Data<&watch::Receiver<DeviceStatus>>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+        /// Respond only once the command was transmitted, failing when that
+        /// didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<SentFrame>> {
+        let address = parse_address(address.0)?;
+        send_cycled_input(&tx, &status, false, address, wait.0.unwrap_or(false))
+            .await
+            .map(Json)
+    }
+
+    /// Switch between the two configured audio inputs
+    ///
+    /// Selects the other one of the pair when one of them was the last input
+    /// selected through this server, and the first one otherwise.
+    #[oai(path = "/input/toggle", method = "post", tag = "ApiTags::Commands")]
+    async fn input_toggle(
+        &self,
+        tx: Data<&CommandSender>,
+        status: Data<&watch::Receiver<DeviceStatus>>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+        /// Respond only once the command was transmitted, failing when that
+        /// didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<SentFrame>> {
+        let address = parse_address(address.0)?;
+        send_toggled_input(&tx, &status, address, wait.0.unwrap_or(false))
+            .await
+            .map(Json)
+    }
+
+    /// Preview the next audio input
+    ///
+    /// The input `/input/next` would select right now, and the frame it would
+    /// send, without sending anything.
+    #[oai(path = "/input/next/preview", method = "get", tag = "ApiTags::Status")]
+    async fn input_next_preview(
+        &self,
+        tx: Data<&CommandSender>,
+        status: Data<&watch::Receiver<DeviceStatus>>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+    ) -> poem::Result<Json<InputPreviewResponse>> {
+        let address = parse_address(address.0)?;
+        let input = cycled_input(&status, true);
+        Ok(Json(InputPreview::new(&tx, input, address).into()))
+    }
+
+    /// Preview the previous audio input
+    ///
+    /// Like `/input/next/preview`, for `/input/prev`.
+    #[oai(path = "/input/prev/preview", method = "get", tag = "ApiTags::Status")]
+    async fn input_prev_preview(
+        &self,
+        tx: Data<&CommandSender>,
+        status: Data<&watch::Receiver<DeviceStatus>>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+    ) -> poem::Result<Json<InputPreviewResponse>> {
+        let address = parse_address(address.0)?;
+        let input = cycled_input(&status, false);
+        Ok(Json(InputPreview::new(&tx, input, address).into()))
+    }
+
+    /// Preview the toggled audio input
+    ///
+    /// Like `/input/next/preview`, for `/input/toggle`.
+    #[oai(
+        path = "/input/toggle/preview",
+        method = "get",
+        tag = "ApiTags::Status"
+    )]
+    async fn input_toggle_preview(
+        &self,
+        tx: Data<&CommandSender>,
+        status: Data<&watch::Receiver<DeviceStatus>>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+    ) -> poem::Result<Json<InputPreviewResponse>> {
+        let address = parse_address(address.0)?;
+        let input = toggled_input(&tx, &status);
+        Ok(Json(InputPreview::new(&tx, input, address).into()))
+    }
+
+    /// List the audio inputs
+    #[oai(path = "/inputs", method = "get", tag = "ApiTags::Status")]
+    async fn inputs(&self) -> Json<Vec<Input>> {
+        Json(AudioInput::ALL.into_iter().map(Input::from).collect())
+    }
+
+    /// Send an arbitrary command byte
+    ///
+    /// Which bytes are permitted may be restricted by the server configuration.
+    #[oai(path = "/raw-command", method = "post", tag = "ApiTags::Commands")]
+    async fn raw_command(
+        &self,
+        tx: Data<&CommandSender>,
+        /// The command byte
+        cmd: Query<u8>,
+        /// How many times to send the command
+        #[oai(validator(minimum(value = "1"), maximum(value = "20")))]
+        repeat: Query<Option<u8>>,
+        /// Time between the repeated commands, in milliseconds
+        #[oai(validator(maximum(value = "10000")))]
+        gap_ms: Query<Option<u64>>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+        /// Respond only once the command was transmitted, failing when that
+        /// didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<SentFrame>> {
+        if !tx.permits_raw(cmd.0) {
+            return Err(raw_not_permitted(cmd.0));
+        }
+        let params = RepeatParams {
+            address: parse_address(address.0)?,
+            repeat: repeat.0,
+            gap_ms: gap_ms.0,
+        };
+        send_repeated(
+            &tx,
+            InfraredCommand::Raw(cmd.0),
+            &params,
+            wait.0.unwrap_or(false),
+        )
+        .await
+        .map(Json)
+    }
+
+    /// Hold a button down
+    ///
+    /// Sends the command, then NEC repeat codes as if its button were held,
+    /// until `/hold/stop`, or for 30 seconds at most. Only one button is held
+    /// at a time, responds with 409 while another one is. The command byte
+    /// is restricted like raw commands.
+    #[oai(path = "/hold/start", method = "post", tag = "ApiTags::Commands")]
+    async fn hold_start(
+        &self,
+        tx: Data<&CommandSender>,
+        state: Data<&SerialState>,
+        /// The command byte, in hex with a 0x prefix or in decimal
+        #[oai(validator(pattern = r"^(0x[0-9a-fA-F]{1,2}|[0-9]{1,3})$"))]
+        cmd: Query<String>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+    ) -> poem::Result<Json<SentFrame>> {
+        let address = parse_address(address.0)?;
+        start_hold(&tx, &state, &cmd.0, address).await.map(Json)
+    }
+
+    /// Release the held button
+    #[oai(path = "/hold/stop", method = "post", tag = "ApiTags::Commands")]
+    async fn hold_stop(&self, tx: Data<&CommandSender>) -> Json<HoldStopResponse> {
+        Json(stop_hold(&tx))
+    }
+
+    /// Send a complete 32-bit frame
+    ///
+    /// The frame is sent exactly as given, for remotes that don't follow the
+    /// NEC structure. Only permitted when raw commands aren't restricted.
+    #[oai(path = "/raw-frame", method = "post", tag = "ApiTags::Commands")]
+    async fn raw_frame(
+        &self,
+        tx: Data<&CommandSender>,
+        /// The frame in hex, in the bit order it is transmitted
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,8}$"))]
+        frame: Query<String>,
+        /// How many times to send the frame
+        #[oai(validator(minimum(value = "1"), maximum(value = "20")))]
+        repeat: Query<Option<u8>>,
+        /// Time between the repeated frames, in milliseconds
+        #[oai(validator(maximum(value = "10000")))]
+        gap_ms: Query<Option<u64>>,
+        /// Respond only once the frame was transmitted, failing when that
+        /// didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<SentFrame>> {
+        send_raw_frame(&tx, &frame.0, repeat.0, gap_ms.0, wait.0.unwrap_or(false))
+            .await
+            .map(Json)
+    }
+
+    /// Send NEC repeat codes
+    ///
+    /// Lone repeat codes, without a frame before them, for devices that
+    /// expect them on their own. They continue whatever frame the device got
+    /// last. Responds with 501 when the firmware doesn't support it.
+    #[oai(path = "/repeat", method = "post", tag = "ApiTags::Commands")]
+    async fn repeat(
+        &self,
+        tx: Data<&CommandSender>,
+        state: Data<&SerialState>,
+        /// How many repeat codes to send, 108ms apart, between 1 and 20
+        count: Query<Option<u8>>,
+        /// Respond only once the repeat codes were transmitted, failing when
+        /// that didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<NecRepeatResponse>> {
+        send_nec_repeat(&tx, &state, count.0, wait.0.unwrap_or(false))
+            .await
+            .map(Json)
+    }
+
+    /// Send a sequence of pulses
+    ///
+    /// Mark and space durations in microseconds, starting with a mark, are
+    /// transmitted on the NEC carrier as they are, for remotes whose protocol
+    /// isn't supported otherwise. Only permitted when raw commands aren't
+    /// restricted, and responds with 501 when the firmware doesn't support it.
+    #[oai(path = "/pulses", method = "post", tag = "ApiTags::Commands")]
+    async fn pulses(
+        &self,
+        tx: Data<&CommandSender>,
+        state: Data<&SerialState>,
+        /// The durations, between 1 and 128 of them, each up to 65535
+        durations: Json<Vec<u32>>,
+        /// Respond only once the sequence was transmitted, failing when that
+        /// didn't succeed
+        wait: Query<Option<bool>>,
+    ) -> poem::Result<Json<PulsesResponse>> {
+        send_pulses(&tx, &state, &durations.0, wait.0.unwrap_or(false))
+            .await
+            .map(Json)
+    }
+
+    /// Wait for the next frame picked up by the IR receiver
+    ///
+    /// Responds with 504 when no frame is received within the timeout.
+    #[oai(path = "/learn", method = "post", tag = "ApiTags::Commands")]
+    async fn learn(
+        &self,
+        events: Data<&Events>,
+        /// How long to wait for a frame, in milliseconds
+        #[oai(validator(maximum(value = "60000")))]
+        timeout_ms: Query<Option<u64>>,
+    ) -> poem::Result<Json<LearnedFrame>> {
+        learn(&events, timeout_ms.0).await.map(Json)
+    }
+
+    /// Select every input in turn
+    ///
+    /// For checking that the device follows each input code while setting
+    /// it up. The last known input is selected again at the end. Responds
+    /// once every frame was transmitted, and with 409 while another
+    /// self-test is running.
+    #[oai(path = "/selftest", method = "post", tag = "ApiTags::Commands")]
+    async fn selftest(
+        &self,
+        tx: Data<&CommandSender>,
+        status: Data<&watch::Receiver<DeviceStatus>>,
+        /// How long to stay on each input, in milliseconds
+        #[oai(validator(maximum(value = "60000")))]
+        dwell_ms: Query<Option<u64>>,
+        /// NEC address in hex, the configured one when not given
+        #[oai(validator(pattern = r"^(0x)?[0-9a-fA-F]{1,4}$"))]
+        address: Query<Option<String>>,
+    ) -> poem::Result<Json<SelfTestResponse>> {
+        let address = parse_address(address.0)?;
+        run_selftest(&tx, &status, dwell_ms.0, address)
+            .await
+            .map(Json)
+    }
+
+    /// Whether the server is connected to the device
+    #[oai(path = "/health", method = "get", tag = "ApiTags::Status")]
+    async fn health(&self, state: Data<&SerialState>) -> HealthResult {
+        if state.is_connected() {
+            HealthResult::Connected(Json(HealthResponse {
+                serial: SerialHealth::Connected,
+            }))
+        } else {
+            HealthResult::Disconnected(Json(HealthResponse {
+                serial: SerialHealth::Disconnected,
+            }))
+        }
+    }
+
+    /// Ping the firmware through the command queue
+    ///
+    /// Unlike `/health`, checks that the firmware answers right now. Fails
+    /// with 503 when it doesn't, after which the serial port is reopened.
+    #[oai(path = "/ping", method = "get", tag = "ApiTags::Status")]
+    async fn ping(&self, tx: Data<&CommandSender>) -> poem::Result<Json<PingResponse>> {
+        ping(&tx).await.map(Json)
+    }
+
+    /// Versions of the server and of the firmware of the device
+    #[oai(path = "/version", method = "get", tag = "ApiTags::Status")]
+    async fn version(&self, state: Data<&SerialState>) -> Json<VersionResponse> {
+        Json(VersionResponse::new(&state))
+    }
+
+    /// Best-effort state of the device
+    ///
+    /// Inferred from the commands sent through this server, so changes made
+    /// with the physical remote are not reflected.
+    #[oai(path = "/status", method = "get", tag = "ApiTags::Status")]
+    async fn status(&self, status: Data<&watch::Receiver<DeviceStatus>>) -> Json<Status> {
+        let status = status.borrow();
+        Json(Status {
+            power: status.power,
+            last_input: status.last_input.map(Input::from),
+            last_command_at: status.last_command_at,
+        })
+    }
+
+    /// Occupancy of the command queue
+    #[oai(path = "/queue", method = "get", tag = "ApiTags::Status")]
+    async fn queue(&self, tx: Data<&CommandSender>) -> Json<QueueResponse> {
+        Json(QueueResponse::new(&tx))
+    }
+
+    /// Discard the queued commands
+    ///
+    /// The command being transmitted, if any, is finished first. Requests
+    /// waiting for the discarded commands fail.
+    #[oai(path = "/queue/clear", method = "post", tag = "ApiTags::Commands")]
+    async fn queue_clear(
+        &self,
+        tx: Data<&CommandSender>,
+    ) -> poem::Result<Json<QueueClearResponse>> {
+        clear_queue(&tx).await.map(Json)
+    }
+}
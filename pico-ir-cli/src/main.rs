@@ -0,0 +1,243 @@
+use ::std::{
+    io::{BufRead, Write},
+    time::Duration,
+};
+
+use ::anyhow::{Context, bail};
+use ::backon::{BlockingRetryable, ExponentialBuilder};
+use ::bpaf::{Bpaf, ParseFailure, Parser};
+use ::pico_ir_proto::{AudioInput, FirmwareInfo, InfraredCommand, NecAddress, Rc6Command, wire};
+
+#[derive(Clone, Debug, Bpaf)]
+struct CmdArgs {
+    #[bpaf(short('s'), env("PICO_IR_SERIAL"), fallback(DEFAULT_SERIAL_PORT.into()))]
+    serial_port: String,
+    #[bpaf(long, env("PICO_IR_BAUD"), fallback(DEFAULT_BAUD_RATE))]
+    baud: u32,
+    /// NEC address of the device in hex, the speakers by default
+    #[bpaf(long, env("PICO_IR_NEC_ADDRESS"), argument("ADDRESS"))]
+    address: Option<String>,
+    #[bpaf(external(command))]
+    command: Command,
+}
+
+#[derive(Clone, Debug, Bpaf)]
+enum Command {
+    /// Toggle the power
+    #[bpaf(command)]
+    Power,
+    /// Turn the volume up
+    #[bpaf(command("volume-up"))]
+    VolumeUp,
+    /// Turn the volume down
+    #[bpaf(command("volume-down"))]
+    VolumeDown,
+    /// Toggle mute
+    #[bpaf(command)]
+    Mute,
+    /// Select an input: bluetooth, 3.5mm, optical or rca
+    #[bpaf(command)]
+    Input {
+        #[bpaf(positional("INPUT"))]
+        input: AudioInput,
+    },
+    /// Send a raw command byte, given in hex
+    #[bpaf(command)]
+    Raw {
+        #[bpaf(positional("BYTE"))]
+        byte: String,
+    },
+    /// Send an RC6 mode 0 command, the control and information bytes given
+    /// in hex
+    #[bpaf(command)]
+    Rc6 {
+        /// Set the toggle bit. A press only counts as a new one when it
+        /// differs from the previous press
+        #[bpaf(long)]
+        toggle: bool,
+        #[bpaf(positional("CONTROL"))]
+        control: String,
+        #[bpaf(positional("INFO"))]
+        info: String,
+    },
+    /// Show the firmware version and what it supports
+    #[bpaf(command)]
+    Info,
+    /// Read commands from stdin, one per line as given on the command line,
+    /// e.g. `input optical`, and send them until the end of input. Waits for
+    /// the serial port to appear
+    #[bpaf(command)]
+    Stdin,
+}
+
+const DEFAULT_SERIAL_PORT: &str = "/dev/serial/by-id/usb-Jabu_Infrared_1-if00";
+const DEFAULT_BAUD_RATE: u32 = 115200;
+const ACK_TIMEOUT: Duration = Duration::from_secs(1);
+/// The longest wait between attempts to open the serial port in the stdin
+/// mode.
+const OPEN_RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+
+impl Command {
+    /// The command to transmit, framed for the firmware, `None` for commands
+    /// that only talk to the firmware.
+    fn to_wire(&self, address: NecAddress) -> ::anyhow::Result<Option<Vec<u8>>> {
+        let command = match self {
+            Command::Power => InfraredCommand::TogglePower,
+            Command::VolumeUp => InfraredCommand::VolumeUp,
+            Command::VolumeDown => InfraredCommand::VolumeDown,
+            Command::Mute => InfraredCommand::Mute,
+            Command::Input { input } => InfraredCommand::SetInput(*input),
+            Command::Raw { byte } => InfraredCommand::Raw(parse_byte(byte, "raw command")?),
+            Command::Rc6 {
+                toggle,
+                control,
+                info,
+            } => {
+                let command = Rc6Command {
+                    control: parse_byte(control, "RC6 control field")?,
+                    info: parse_byte(info, "RC6 information field")?,
+                };
+                return Ok(Some(wire::rc6(command.encode(*toggle))));
+            }
+            Command::Info => return Ok(None),
+            Command::Stdin => bail!("stdin can only be given on the command line"),
+        };
+        Ok(Some(wire::nec(command.encode(address)).to_vec()))
+    }
+}
+
+fn parse_byte(byte: &str, what: &str) -> ::anyhow::Result<u8> {
+    let digits = byte.strip_prefix("0x").unwrap_or(byte);
+    if digits.is_empty() || digits.len() > 2 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("{what} must be one or two hex digits, got '{byte}'");
+    }
+    Ok(u8::from_str_radix(digits, 16)?)
+}
+
+fn print_info(serial: &mut dyn ::serialport::SerialPort) -> ::anyhow::Result<()> {
+    serial
+        .write_all(wire::INFO)
+        .context("writing to serial port")?;
+    let response = read_ack(serial)?;
+    let info = FirmwareInfo::parse(&response)
+        .with_context(|| format!("firmware did not describe itself: {response}"))?;
+    println!("version: {}", info.version.as_deref().unwrap_or("unknown"));
+    println!("protocols: {}", info.protocols.join(", "));
+    if let Some(emitters) = info.emitters {
+        println!("emitters: {emitters}");
+    }
+    println!("receiver: {}", if info.receiver { "yes" } else { "no" });
+    Ok(())
+}
+
+/// Waits for the firmware to acknowledge the command just written.
+fn expect_ok(serial: &mut dyn ::serialport::SerialPort) -> ::anyhow::Result<()> {
+    match read_ack(serial)?.as_str() {
+        "OK" => Ok(()),
+        ack => bail!("firmware rejected the command: {ack}"),
+    }
+}
+
+/// Reads the firmware's response to the command, skipping frames reported
+/// by its IR receiver.
+fn read_ack(serial: &mut dyn ::serialport::SerialPort) -> ::anyhow::Result<String> {
+    loop {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        while serial
+            .read(&mut byte)
+            .context("no response from the firmware")?
+            == 1
+        {
+            if byte[0] == b'\n' {
+                break;
+            }
+            line.push(byte[0]);
+        }
+        let line = String::from_utf8_lossy(&line).into_owned();
+        if !line.starts_with("RX ") {
+            return Ok(line);
+        }
+    }
+}
+
+/// Opens the serial port, waiting for it to appear.
+fn open_serial(args: &CmdArgs) -> ::anyhow::Result<Box<dyn ::serialport::SerialPort>> {
+    (|| {
+        ::serialport::new(&args.serial_port, args.baud)
+            .timeout(ACK_TIMEOUT)
+            .open()
+    })
+    .retry(
+        ExponentialBuilder::default()
+            .with_max_delay(OPEN_RETRY_MAX_DELAY)
+            .without_max_times(),
+    )
+    .notify(|e, d| eprintln!("failed to open serial, retrying in {} s: {e}", d.as_secs()))
+    .call()
+    .with_context(|| format!("opening {}", args.serial_port))
+}
+
+/// Sends the commands read from stdin, one per line, until the end of input.
+/// Empty lines and lines starting with `#` are skipped. A command that fails
+/// is reported and the following ones are still sent, a serial port that
+/// fails to be written to is reopened.
+fn run_stdin(args: &CmdArgs, address: NecAddress) -> ::anyhow::Result<()> {
+    let parser = command().to_options();
+    let mut serial = open_serial(args)?;
+    let mut failed = 0;
+    for (n, line) in ::std::io::stdin().lock().lines().enumerate() {
+        let line = line.context("reading stdin")?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match parser.run_inner(&words[..]) {
+            Ok(Command::Info) => print_info(&mut *serial),
+            Ok(command) => command.to_wire(address).and_then(|frame| {
+                let frame = frame.expect("only info doesn't transmit");
+                if let Err(e) = serial.write_all(&frame) {
+                    eprintln!("failed to write to serial, reopening: {e}");
+                    serial = open_serial(args)?;
+                    serial.write_all(&frame).context("writing to serial port")?;
+                }
+                expect_ok(&mut *serial)
+            }),
+            Err(ParseFailure::Stderr(doc) | ParseFailure::Stdout(doc, _)) => {
+                Err(::anyhow::anyhow!("{}", doc.monochrome(false)))
+            }
+            Err(ParseFailure::Completion(_)) => continue,
+        };
+        if let Err(e) = result {
+            eprintln!("line {}: {line}: {e:#}", n + 1);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} command(s) failed");
+    }
+    Ok(())
+}
+
+fn main() -> ::anyhow::Result<()> {
+    let args = cmd_args().run();
+    let address = match &args.address {
+        Some(address) => NecAddress::from_hex(address).context("invalid NEC address")?,
+        None => NecAddress::DEFAULT,
+    };
+    if let Command::Stdin = args.command {
+        return run_stdin(&args, address);
+    }
+    let frame = args.command.to_wire(address)?;
+
+    let mut serial = ::serialport::new(&args.serial_port, args.baud)
+        .timeout(ACK_TIMEOUT)
+        .open()
+        .with_context(|| format!("opening {}", args.serial_port))?;
+    let Some(frame) = frame else {
+        return print_info(&mut *serial);
+    };
+    serial.write_all(&frame).context("writing to serial port")?;
+    expect_ok(&mut *serial)
+}
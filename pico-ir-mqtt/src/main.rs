@@ -1,111 +1,774 @@
-use ::std::str::{self, FromStr};
+use ::std::{
+    collections::VecDeque,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    str,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
+};
 
-use ::anyhow::{Context, anyhow, bail};
+use ::anyhow::{Context, bail};
+use ::backon::{BackoffBuilder, BlockingRetryable, ExponentialBuilder};
 use ::bpaf::{Bpaf, Parser};
+use ::pico_ir_proto::{AudioInput, FirmwareInfo, InfraredCommand, NecAddress, wire};
 use ::rumqttc as mq;
+use ::serde_json::json;
 
 #[derive(Clone, Debug, Bpaf)]
 struct CmdArgs {
-    #[bpaf(short('h'), long)]
+    #[bpaf(short('h'), long, env("MQTT_HOST"))]
     mqtt_host: String,
-    #[bpaf(short('u'), long)]
-    mqtt_user: String,
+    /// Broker port, 8883 with TLS and 1883 otherwise by default
+    #[bpaf(long, env("MQTT_PORT"))]
+    mqtt_port: Option<u16>,
+    #[bpaf(short('u'), long, env("MQTT_USER"))]
+    mqtt_user: Option<String>,
     #[bpaf(env("MQTT_PASSWORD"))]
-    mqtt_password: String,
-    #[bpaf(short('s'))]
+    mqtt_password: Option<String>,
+    /// Connect over TLS, trusting the CA certificate in this PEM file
+    #[bpaf(long, env("MQTT_CA_CERT"), argument("PATH"))]
+    mqtt_ca_cert: Option<PathBuf>,
+    /// QoS of the command subscription, 0, 1 or 2
+    #[bpaf(
+        long,
+        env("MQTT_QOS"),
+        argument::<u8>("QOS"),
+        parse(parse_qos),
+        fallback(mq::QoS::AtMostOnce)
+    )]
+    mqtt_qos: mq::QoS,
+    /// Keep the session on the broker across reconnects, so that commands
+    /// published at QoS 1 or 2 while disconnected are delivered afterwards
+    #[bpaf(long, env("MQTT_PERSISTENT_SESSION"))]
+    mqtt_persistent_session: bool,
+    #[bpaf(short('s'), env("PICO_IR_SERIAL"), fallback(DEFAULT_SERIAL_PORT.into()))]
     serial_port: String,
+    #[bpaf(long, env("PICO_IR_BAUD"), fallback(DEFAULT_BAUD_RATE))]
+    baud: u32,
+    /// Prefix of all topics, so that several bridges can share a broker
+    #[bpaf(
+        long,
+        env("MQTT_TOPIC_PREFIX"),
+        argument::<String>("PREFIX"),
+        parse(parse_topic_prefix),
+        fallback(DEFAULT_TOPIC_PREFIX.into())
+    )]
+    topic_prefix: String,
+    /// Devices other than the speakers, as comma separated `<name>=<address>`
+    /// with the NEC address in hex. Their commands are received under
+    /// `<prefix><name>/`
+    #[bpaf(
+        long,
+        env("PICO_IR_DEVICES"),
+        argument::<String>("DEVICES"),
+        parse(parse_devices),
+        fallback(Vec::new())
+    )]
+    devices: Vec<Device>,
+    /// Publish Home Assistant MQTT discovery configs
+    #[bpaf(long, env("PICO_IR_HA_DISCOVERY"))]
+    ha_discovery: bool,
+    /// Serve GET /health on this address, reporting whether the broker and
+    /// the serial port are connected
+    #[bpaf(long, env("PICO_IR_HEALTH_BIND"), argument("ADDR"))]
+    health_bind: Option<SocketAddr>,
 }
 
-#[derive(Clone, Copy, Debug)]
-enum InfraredCommand {
-    TogglePower,
-    SetInput(AudioInput),
-    Raw(u8),
+const DEFAULT_SERIAL_PORT: &str = "/dev/serial/by-id/usb-Jabu_Infrared_1-if00";
+const DEFAULT_BAUD_RATE: u32 = 115200;
+/// How long the firmware may take to respond to a command.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Longest wait between attempts to open the serial port at startup, which
+/// keeps being retried until the device shows up.
+const STARTUP_RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+/// How many times reopening the serial port after a failed write is retried.
+/// Kept short, as it holds up the MQTT event loop, the next command tries
+/// again.
+const REOPEN_RETRIES: usize = 3;
+const REOPEN_RETRY_MIN_DELAY: Duration = Duration::from_millis(100);
+const DEFAULT_TOPIC_PREFIX: &str = "jabu/pico-ir/";
+/// How many packet ids of received commands are remembered to recognize
+/// redeliveries.
+const RECENT_PACKETS: usize = 32;
+/// How long a health check client may take to send its request.
+const HEALTH_READ_TIMEOUT: Duration = Duration::from_secs(2);
+/// Requests queued for the event loop when a session is set up, besides the
+/// discovery configs: the subscription and the online status.
+const SESSION_REQUESTS: usize = 2;
+/// Discovery configs published for the speakers and for each device.
+const DISCOVERY_CONFIGS: usize = 3;
+/// Room in the request queue for results and errors published while the
+/// event loop is busy transmitting.
+const SPARE_REQUESTS: usize = 10;
+
+fn parse_qos(qos: u8) -> Result<mq::QoS, String> {
+    mq::qos(qos).map_err(|_| format!("QoS must be 0, 1 or 2, got {qos}"))
+}
+
+/// Checks a topic prefix and makes it end with a `/`.
+fn parse_topic_prefix(prefix: String) -> Result<String, String> {
+    if prefix.is_empty() || prefix.contains(['#', '+']) {
+        return Err(format!(
+            "topic prefix must be non-empty and without wildcards, got '{prefix}'"
+        ));
+    }
+    if prefix.ends_with('/') {
+        Ok(prefix)
+    } else {
+        Ok(prefix + "/")
+    }
+}
+
+/// A device whose commands are received on its own subtree of topics.
+#[derive(Clone, Debug)]
+struct Device {
+    name: String,
+    address: NecAddress,
+}
+
+/// Parses the comma separated `<name>=<address>` entries of the devices.
+fn parse_devices(list: String) -> Result<Vec<Device>, String> {
+    let mut devices: Vec<Device> = Vec::new();
+    for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((name, address)) = entry.split_once('=') else {
+            return Err(format!("device must be <name>=<address>, got '{entry}'"));
+        };
+        // Part of the topics, and of the Home Assistant ids
+        let valid_name = !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid_name {
+            return Err(format!("invalid device name '{name}'"));
+        }
+        if devices.iter().any(|d| d.name == name) {
+            return Err(format!("duplicate device name '{name}'"));
+        }
+        let Some(address) = NecAddress::from_hex(address) else {
+            return Err(format!(
+                "invalid NEC address '{address}' of device '{name}'"
+            ));
+        };
+        devices.push(Device {
+            name: name.to_owned(),
+            address,
+        });
+    }
+    Ok(devices)
+}
+
+/// The topics of the bridge, all under the configured prefix.
+struct Topics {
+    prefix: String,
+    /// Availability of the bridge, `online` or `offline`.
+    status: String,
+    /// Where the outcome of every command is published.
+    result: String,
+    /// Where messages that aren't valid commands are reported, along with
+    /// their topic and payload.
+    error: String,
+}
+
+impl Topics {
+    fn new(prefix: &str) -> Self {
+        Topics {
+            prefix: prefix.to_owned(),
+            status: format!("{prefix}status"),
+            result: format!("{prefix}result"),
+            error: format!("{prefix}error"),
+        }
+    }
+
+    /// The topic commands named `name` are received on.
+    fn command(&self, name: &str) -> String {
+        format!("{}{name}", self.prefix)
+    }
+
+    /// The command part of a command topic.
+    fn command_name<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        topic.strip_prefix(&self.prefix)
+    }
+
+    /// Whether `topic` is one the bridge publishes to itself.
+    fn is_own(&self, topic: &str) -> bool {
+        [&self.status, &self.result, &self.error]
+            .iter()
+            .any(|own| *own == topic)
+    }
+}
+
+/// Packet ids of the most recently received commands.
+///
+/// At QoS 1 and 2 the broker redelivers a message, flagged as a duplicate and
+/// with the same packet id, when it didn't see our acknowledgement before the
+/// connection dropped. Those must not be transmitted a second time.
+struct RecentPackets(VecDeque<u16>);
+
+impl RecentPackets {
+    fn new() -> Self {
+        RecentPackets(VecDeque::with_capacity(RECENT_PACKETS))
+    }
+
+    /// Records the packet id of `msg` and returns whether it is a redelivery
+    /// of a message already received.
+    fn is_redelivery(&mut self, msg: &mq::Publish) -> bool {
+        if msg.qos == mq::QoS::AtMostOnce {
+            return false;
+        }
+        if msg.dup && self.0.contains(&msg.pkid) {
+            return true;
+        }
+        if self.0.len() == RECENT_PACKETS {
+            self.0.pop_front();
+        }
+        self.0.push_back(msg.pkid);
+        false
+    }
+}
+
+/// Parses a raw command byte given as one or two hex digits. Anything else,
+/// like signs or whitespace that `from_str_radix` would let through, is refused.
+fn parse_raw(payload: &str) -> ::anyhow::Result<u8> {
+    if payload.is_empty() || payload.len() > 2 || !payload.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("raw command must be one or two hex digits, got '{payload}'");
+    }
+    Ok(u8::from_str_radix(payload, 16)?)
+}
+
+/// The command received in `msg`, and the address of the device it's for:
+/// the speakers for `<prefix><command>`, or one of `devices` for
+/// `<prefix><device>/<command>`.
+fn parse_command(
+    topics: &Topics,
+    devices: &[Device],
+    msg: &mq::Publish,
+) -> ::anyhow::Result<(InfraredCommand, NecAddress)> {
+    let Some(topic) = topics.command_name(&msg.topic) else {
+        bail!("topic prefix wrong");
+    };
+    let (address, topic) = match topic.split_once('/') {
+        Some((name, topic)) => {
+            let Some(device) = devices.iter().find(|d| d.name == name) else {
+                bail!("unknown device '{name}'");
+            };
+            (device.address, topic)
+        }
+        None => (NecAddress::DEFAULT, topic),
+    };
+    let command = match topic {
+        "power" => InfraredCommand::TogglePower,
+        "input" => {
+            let input = str::from_utf8(&msg.payload)?;
+            let Some(input) = AudioInput::from_name(input) else {
+                let inputs: Vec<_> = AudioInput::ALL.iter().map(AudioInput::as_str).collect();
+                bail!(
+                    "unknown input '{input}', expected one of {}",
+                    inputs.join(", ")
+                );
+            };
+            InfraredCommand::SetInput(input)
+        }
+        "raw" => InfraredCommand::Raw(parse_raw(str::from_utf8(&msg.payload)?)?),
+        cmd => bail!("invalid command '{cmd}'"),
+    };
+    Ok((command, address))
 }
 
-#[derive(Clone, Copy, Debug)]
-enum AudioInput {
-    Bluetooth,
-    _3_5mm,
-    Optical,
-    Rca,
+/// How many requests the queue to the event loop must hold so that setting up
+/// a session for `devices` never blocks it.
+fn request_capacity(devices: usize) -> usize {
+    SESSION_REQUESTS + DISCOVERY_CONFIGS * (1 + devices) + SPARE_REQUESTS
 }
 
-impl FromStr for AudioInput {
-    type Err = ::anyhow::Error;
+/// The retained Home Assistant discovery configs for the command topics of
+/// the speakers and of each of `devices`, by the topic they are published to.
+fn discovery_configs(topics: &Topics, devices: &[Device]) -> Vec<(String, ::serde_json::Value)> {
+    let inputs: Vec<_> = AudioInput::ALL.iter().map(AudioInput::as_str).collect();
+    let speakers = ("pico_ir".to_owned(), "Pico IR".to_owned(), String::new());
+    let others = devices.iter().map(|d| {
+        (
+            format!("pico_ir_{}", d.name),
+            format!("Pico IR {}", d.name),
+            format!("{}/", d.name),
+        )
+    });
+    let mut all = Vec::with_capacity(DISCOVERY_CONFIGS * (1 + devices.len()));
+    for (id, name, subtree) in std::iter::once(speakers).chain(others) {
+        let device = json!({
+            "identifiers": [id],
+            "name": name,
+            "manufacturer": "Jabu",
+        });
+        let configs: [_; DISCOVERY_CONFIGS] = [
+            (
+                format!("homeassistant/button/{id}_power/config"),
+                json!({
+                    "name": "Power",
+                    "unique_id": format!("{id}_power"),
+                    "command_topic": topics.command(&format!("{subtree}power")),
+                    "availability_topic": topics.status,
+                    "device": device,
+                }),
+            ),
+            (
+                format!("homeassistant/select/{id}_input/config"),
+                json!({
+                    "name": "Input",
+                    "unique_id": format!("{id}_input"),
+                    "command_topic": topics.command(&format!("{subtree}input")),
+                    "options": inputs,
+                    "optimistic": true,
+                    "availability_topic": topics.status,
+                    "device": device,
+                }),
+            ),
+            (
+                format!("homeassistant/text/{id}_raw/config"),
+                json!({
+                    "name": "Raw command",
+                    "unique_id": format!("{id}_raw"),
+                    "command_topic": topics.command(&format!("{subtree}raw")),
+                    "pattern": "[0-9a-fA-F]{1,2}",
+                    "availability_topic": topics.status,
+                    "device": device,
+                }),
+            ),
+        ];
+        all.extend(configs);
+    }
+    all
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "bluetooth" => Ok(Self::Bluetooth),
-            "3.5mm" => Ok(Self::_3_5mm),
-            "optical" => Ok(Self::Optical),
-            "rca" => Ok(Self::Rca),
-            _ => Err(anyhow!("invalid audio input string")),
+/// Subscribes to the commands and publishes the online status, along with
+/// the discovery configs when enabled.
+fn set_up_session(
+    client: &mq::Client,
+    topics: &Topics,
+    args: &CmdArgs,
+) -> Result<(), mq::ClientError> {
+    // Called from the event loop, the queue is sized by request_capacity for
+    // all of this to fit without blocking
+    client.try_subscribe(format!("{}#", topics.prefix), args.mqtt_qos)?;
+    client.try_publish(&topics.status, mq::QoS::AtLeastOnce, true, "online")?;
+    if args.ha_discovery {
+        for (topic, config) in discovery_configs(topics, &args.devices) {
+            client.try_publish(topic, mq::QoS::AtLeastOnce, true, config.to_string())?;
         }
     }
+    Ok(())
+}
+
+/// Why a command wasn't transmitted. Published along with its `kind`, so
+/// that clients can tell the failures apart without parsing the error.
+#[derive(Debug)]
+enum CommandError {
+    /// The message isn't a valid command.
+    Invalid(::anyhow::Error),
+    /// The frame couldn't be written to the serial port, even after reopening
+    /// it.
+    Unwritten(::anyhow::Error),
+    /// The firmware didn't acknowledge the frame, which may have been
+    /// transmitted or not.
+    Unacknowledged(::anyhow::Error),
+    /// The firmware responded with this error instead.
+    Rejected(String),
 }
 
-impl InfraredCommand {
-    pub fn as_u8(&self) -> u8 {
+impl CommandError {
+    fn kind(&self) -> &'static str {
         match self {
-            InfraredCommand::TogglePower => 0x66,
-            InfraredCommand::SetInput(AudioInput::Bluetooth) => 0x86,
-            InfraredCommand::SetInput(AudioInput::_3_5mm) => 0x97,
-            InfraredCommand::SetInput(AudioInput::Optical) => 0x88,
-            InfraredCommand::SetInput(AudioInput::Rca) => 0x96,
-            InfraredCommand::Raw(b) => *b,
+            CommandError::Invalid(_) => "invalid",
+            CommandError::Unwritten(_) => "unwritten",
+            CommandError::Unacknowledged(_) => "unacknowledged",
+            CommandError::Rejected(_) => "rejected",
         }
     }
+}
 
-    pub fn as_u32_le(&self) -> u32 {
-        const ADDRESS: u32 = 0x2385;
-        (self.as_u8() as u32) << 24 | (!self.as_u8() as u32) << 16 | ADDRESS
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Invalid(e)
+            | CommandError::Unwritten(e)
+            | CommandError::Unacknowledged(e) => write!(f, "{e:#}"),
+            CommandError::Rejected(response) => {
+                write!(f, "firmware rejected command: {response}")
+            }
+        }
     }
 }
 
-impl TryFrom<mq::Publish> for InfraredCommand {
-    type Error = ::anyhow::Error;
+/// Publishes the outcome of the command received on `topic` to the result
+/// topic.
+fn publish_result(
+    client: &mq::Client,
+    topics: &Topics,
+    topic: &str,
+    result: &Result<(), CommandError>,
+) {
+    let command = topics.command_name(topic).unwrap_or(topic);
+    let payload = match result {
+        Ok(()) => json!({ "command": command, "success": true }),
+        Err(e) => {
+            let mut payload = json!({
+                "command": command,
+                "success": false,
+                "kind": e.kind(),
+                "error": e.to_string(),
+            });
+            if let CommandError::Rejected(response) = e {
+                payload["response"] = response.as_str().into();
+            }
+            payload
+        }
+    };
+    // Called from the event loop, which is what makes room in the request
+    // queue, so this must not block
+    if let Err(e) = client.try_publish(
+        &topics.result,
+        mq::QoS::AtMostOnce,
+        false,
+        payload.to_string(),
+    ) {
+        eprintln!("failed to publish result: {e}");
+    }
+}
 
-    fn try_from(msg: mq::Publish) -> Result<Self, Self::Error> {
-        let Some(topic) = msg.topic.strip_prefix("jabu/pico-ir/") else {
-            bail!("topic prefix wrong");
-        };
-        let command = match topic {
-            "power" => InfraredCommand::TogglePower,
-            "input" => InfraredCommand::SetInput(str::from_utf8(&msg.payload)?.parse()?),
-            "raw" => InfraredCommand::Raw(u8::from_str_radix(str::from_utf8(&msg.payload)?, 16)?),
-            cmd => bail!("invalid command '{cmd}'"),
-        };
-        Ok(command)
+/// Publishes why `msg` is not a valid command to the error topic.
+fn publish_parse_error(
+    client: &mq::Client,
+    topics: &Topics,
+    msg: &mq::Publish,
+    error: &::anyhow::Error,
+) {
+    let payload = json!({
+        "topic": msg.topic,
+        "payload": String::from_utf8_lossy(&msg.payload),
+        "error": format!("{error:#}"),
+    });
+    // Called from the event loop like publish_result, so this must not block
+    if let Err(e) = client.try_publish(
+        &topics.error,
+        mq::QoS::AtMostOnce,
+        false,
+        payload.to_string(),
+    ) {
+        eprintln!("failed to publish error: {e}");
+    }
+}
+
+/// Whether the bridge is connected to the broker and to the firmware, as
+/// reported by [`serve_health`]. The serial port is only known to be gone once
+/// writing to it failed.
+#[derive(Default)]
+struct Health {
+    mqtt: AtomicBool,
+    serial: AtomicBool,
+}
+
+/// Answers health checks on `listener` until the process exits.
+fn serve_health(listener: TcpListener, health: &Health) {
+    for stream in listener.incoming() {
+        let result = stream.and_then(|mut stream| answer_health(&mut stream, health));
+        if let Err(e) = result {
+            eprintln!("failed to answer health check: {e}");
+        }
+    }
+}
+
+/// Responds to a `GET /health` with 200 when both the broker and the serial
+/// port are connected and 503 otherwise, and to anything else with 404.
+fn answer_health(stream: &mut TcpStream, health: &Health) -> io::Result<()> {
+    stream.set_read_timeout(Some(HEALTH_READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&*stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers are of no interest, but closing the connection with them
+    // unread would reset it
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+    let state = |connected: &AtomicBool| {
+        if connected.load(Ordering::Relaxed) {
+            "connected"
+        } else {
+            "disconnected"
+        }
+    };
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/health"] => {
+            let body = json!({ "mqtt": state(&health.mqtt), "serial": state(&health.serial) });
+            let healthy =
+                health.mqtt.load(Ordering::Relaxed) && health.serial.load(Ordering::Relaxed);
+            let status = if healthy {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            (status, body)
+        }
+        _ => ("404 Not Found", json!({ "error": "not found" })),
+    };
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// The open serial port.
+struct Serial {
+    port: Box<dyn ::serialport::SerialPort>,
+    /// Whether the firmware takes length-prefixed commands, see
+    /// [`wire::framed`].
+    framed: bool,
+}
+
+impl Serial {
+    /// `frame`, length-prefixed when the firmware takes that.
+    fn frame(&self, frame: &[u8]) -> Vec<u8> {
+        self.framed
+            .then(|| wire::framed(frame))
+            .flatten()
+            .unwrap_or_else(|| frame.to_vec())
+    }
+}
+
+/// Writes the frame of `command` to the device at `address`, reopening the
+/// serial port once if that fails, and reads the firmware's acknowledgement,
+/// which would otherwise pile up until the firmware can't write any more.
+/// Fails unless the firmware acknowledged the command.
+fn transmit(
+    serial: &mut Serial,
+    args: &CmdArgs,
+    health: &Health,
+    (command, address): (InfraredCommand, NecAddress),
+) -> Result<(), CommandError> {
+    let frame = wire::nec(command.encode(address));
+    if let Err(e) = serial.port.write_all(&serial.frame(&frame)) {
+        eprintln!("failed to write to serial port, reopening: {e}");
+        health.serial.store(false, Ordering::Relaxed);
+        let backoff = ExponentialBuilder::default()
+            .with_min_delay(REOPEN_RETRY_MIN_DELAY)
+            .with_max_times(REOPEN_RETRIES);
+        *serial = open_serial(args, backoff).map_err(CommandError::Unwritten)?;
+        serial
+            .port
+            .write_all(&serial.frame(&frame))
+            .context("writing to the reopened serial port")
+            .map_err(CommandError::Unwritten)?;
+        health.serial.store(true, Ordering::Relaxed);
+    }
+    let ack = read_response(&mut *serial.port)
+        .context("reading the acknowledgement")
+        .map_err(CommandError::Unacknowledged)?;
+    if ack != "OK" {
+        return Err(CommandError::Rejected(ack));
+    }
+    Ok(())
+}
+
+/// Opens the serial port, retrying with `backoff`, and logs which firmware is
+/// on the other end.
+fn open_serial(args: &CmdArgs, backoff: ExponentialBuilder) -> ::anyhow::Result<Serial> {
+    let mut port = (|| {
+        ::serialport::new(&args.serial_port, args.baud)
+            .timeout(RESPONSE_TIMEOUT)
+            .open()
+    })
+    .retry(backoff)
+    .notify(|e, d| eprintln!("failed to open serial, retrying in {} s: {e}", d.as_secs()))
+    .call()
+    .context("serialport failed")?;
+    let framed = match query_info(&mut *port) {
+        Ok(info) => {
+            println!(
+                "connected to firmware {}, protocols {}",
+                info.version.as_deref().unwrap_or("of unknown version"),
+                info.protocols.join(",")
+            );
+            info.supports("framed")
+        }
+        Err(e) => {
+            eprintln!("firmware did not describe itself: {e:#}");
+            false
+        }
+    };
+    Ok(Serial { port, framed })
+}
+
+/// Asks the firmware to describe itself. Older firmware doesn't know how.
+fn query_info(serial: &mut dyn ::serialport::SerialPort) -> ::anyhow::Result<FirmwareInfo> {
+    serial.write_all(wire::INFO)?;
+    let line = read_response(serial)?;
+    FirmwareInfo::parse(&line).with_context(|| format!("unexpected response {line:?}"))
+}
+
+/// Reads the line the firmware responds to a command with.
+fn read_response(serial: &mut dyn ::serialport::SerialPort) -> ::anyhow::Result<String> {
+    loop {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        while serial.read(&mut byte).context("no response")? == 1 && byte[0] != b'\n' {
+            line.push(byte[0]);
+        }
+        let line = String::from_utf8_lossy(&line);
+        // Frames picked up by the IR receiver may come first
+        if !line.starts_with("RX ") {
+            return Ok(line.trim_end_matches('\r').to_owned());
+        }
     }
 }
 
 fn main() -> ::anyhow::Result<()> {
     let args = cmd_args().run();
-    let mut serial = ::serialport::new(args.serial_port, 115200)
-        .open()
-        .context("serialport failed")?;
+    let topics = Topics::new(&args.topic_prefix);
     let opts = {
-        let mut opts = mq::MqttOptions::new("pico-ir-mqtt", args.mqtt_host, 1883);
-        opts.set_credentials(args.mqtt_user, args.mqtt_password);
+        let tls = match &args.mqtt_ca_cert {
+            Some(path) => Some(
+                fs::read(path)
+                    .with_context(|| format!("reading CA certificate {}", path.display()))?,
+            ),
+            None => None,
+        };
+        let port = args
+            .mqtt_port
+            .unwrap_or(if tls.is_some() { 8883 } else { 1883 });
+        let mut opts = mq::MqttOptions::new("pico-ir-mqtt", &args.mqtt_host, port);
+        if let Some(ca) = tls {
+            opts.set_transport(mq::Transport::tls_with_config(
+                mq::TlsConfiguration::Simple {
+                    ca,
+                    alpn: None,
+                    client_auth: None,
+                },
+            ));
+        }
+        match (&args.mqtt_user, &args.mqtt_password) {
+            (Some(user), Some(password)) => {
+                opts.set_credentials(user, password);
+            }
+            (Some(_), None) => bail!("MQTT_PASSWORD must be set along with the MQTT user"),
+            (None, _) => {}
+        }
+        opts.set_clean_session(!args.mqtt_persistent_session);
+        opts.set_last_will(mq::LastWill::new(
+            &topics.status,
+            "offline",
+            mq::QoS::AtLeastOnce,
+            true,
+        ));
         opts
     };
-    let (client, mut conn) = mq::Client::new(opts, 10);
-    client.subscribe("jabu/pico-ir/#", mq::QoS::AtMostOnce)?;
-    println!("We're on");
+    // The bridge may well start before the device is enumerated, so wait for
+    // it to show up however long it takes
+    let startup_backoff = ExponentialBuilder::default()
+        .with_max_delay(STARTUP_RETRY_MAX_DELAY)
+        .without_max_times();
+    let health = Arc::new(Health::default());
+    if let Some(addr) = args.health_bind {
+        let listener = TcpListener::bind(addr)
+            .with_context(|| format!("binding the health check listener to {addr}"))?;
+        let health = health.clone();
+        thread::spawn(move || serve_health(listener, &health));
+    }
+    let mut serial = open_serial(&args, startup_backoff)?;
+    health.serial.store(true, Ordering::Relaxed);
+    let (client, mut conn) = mq::Client::new(opts, request_capacity(args.devices.len()));
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    ::ctrlc::set_handler({
+        let client = client.clone();
+        let shutdown = shutdown.clone();
+        let status = topics.status.clone();
+        move || {
+            shutdown.store(true, Ordering::Relaxed);
+            let _ = client.publish(&status, mq::QoS::AtLeastOnce, true, "offline");
+            let _ = client.disconnect();
+        }
+    })?;
+
+    let reconnect_backoff = ExponentialBuilder::default()
+        .with_max_delay(Duration::from_secs(60))
+        .without_max_times();
+    let mut backoff = reconnect_backoff.build();
+    let mut recent = RecentPackets::new();
+    // The event loop reconnects by itself when polled after an error, we only
+    // need to delay it and set up the session again once connected.
     for ev in conn.iter() {
-        let ev = ev.context("got connection error")?;
-        let rumqttc::Event::Incoming(mq::Packet::Publish(msg)) = ev else {
-            continue;
-        };
-        let command = match InfraredCommand::try_from(msg) {
-            Ok(command) => command,
+        let ev = match ev {
+            Ok(ev) => ev,
             Err(e) => {
-                eprintln!("failed to parse message: {e}");
+                health.mqtt.store(false, Ordering::Relaxed);
+                let delay = backoff.next().expect("backoff has no max times");
+                eprintln!(
+                    "got connection error, reconnecting in {} s: {e}",
+                    delay.as_secs()
+                );
+                thread::sleep(delay);
                 continue;
             }
         };
-        write!(serial, "{:x}", command.as_u32_le()).context("failed to write to serial port")?;
+        let msg = match ev {
+            mq::Event::Incoming(mq::Packet::ConnAck(_)) => {
+                println!("We're on");
+                health.mqtt.store(true, Ordering::Relaxed);
+                backoff = reconnect_backoff.build();
+                if let Err(e) = set_up_session(&client, &topics, &args) {
+                    eprintln!("failed to set up the session: {e}");
+                }
+                continue;
+            }
+            mq::Event::Outgoing(mq::Outgoing::Disconnect) if shutdown.load(Ordering::Relaxed) => {
+                println!("Shutting down");
+                return Ok(());
+            }
+            mq::Event::Incoming(mq::Packet::Publish(msg)) if !topics.is_own(&msg.topic) => msg,
+            _ => continue,
+        };
+        if recent.is_redelivery(&msg) {
+            eprintln!("ignoring redelivered message {} on {}", msg.pkid, msg.topic);
+            continue;
+        }
+        let result = match parse_command(&topics, &args.devices, &msg) {
+            Ok(command) => transmit(&mut serial, &args, &health, command),
+            Err(e) => {
+                publish_parse_error(&client, &topics, &msg, &e);
+                Err(CommandError::Invalid(e))
+            }
+        };
+        if let Err(e) = &result {
+            eprintln!("command on {} failed: {e}", msg.topic);
+        }
+        publish_result(&client, &topics, &msg.topic, &result);
     }
     bail!("wtf loop died");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_setup_fits_the_request_queue() {
+        let topics = Topics::new(DEFAULT_TOPIC_PREFIX);
+        let mut devices = Vec::new();
+        for n in 0..8 {
+            let configs = discovery_configs(&topics, &devices);
+            assert_eq!(configs.len(), DISCOVERY_CONFIGS * (1 + n));
+            assert!(SESSION_REQUESTS + configs.len() <= request_capacity(n));
+            devices.push(Device {
+                name: format!("device{n}"),
+                address: NecAddress::DEFAULT,
+            });
+        }
+    }
+}